@@ -0,0 +1,27 @@
+create table "security_alert"
+(
+    "id" SERIAL PRIMARY KEY,
+    "guardian_event_id" INTEGER NOT NULL REFERENCES "guardian_event" ("id") ON DELETE CASCADE,
+    "chain_id" BIGINT NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "email" VARCHAR(100) NOT NULL,
+    "kind" VARCHAR(20) NOT NULL,
+    "freeze_token" CHAR(64) NOT NULL,
+    "status" SMALLINT NOT NULL DEFAULT 0,
+    "claimed_at" TIMESTAMPTZ,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "updated_at" TIMESTAMPTZ
+);
+
+create unique index "security_alert_event_email_idx" on "security_alert" ("guardian_event_id", "email");
+create unique index "security_alert_freeze_token_idx" on "security_alert" ("freeze_token");
+create index "security_alert_status_idx" on "security_alert" ("status");
+
+create table "account_freeze"
+(
+    "chain_id" BIGINT NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "alert_id" INTEGER REFERENCES "security_alert" ("id") ON DELETE SET NULL,
+    "frozen_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY ("chain_id", "account")
+);
@@ -0,0 +1,24 @@
+alter table "bind_code" add column "purpose" SMALLINT NOT NULL DEFAULT 0;
+
+create table "recovery_request"
+(
+    "id" SERIAL PRIMARY KEY,
+    "chain_id" BIGINT NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "new_owner" CHAR(42) NOT NULL,
+    "bind_code_id" INTEGER NOT NULL REFERENCES "bind_code" ("id"),
+    "status" SMALLINT NOT NULL,
+    "freeze_token" CHAR(64) NOT NULL,
+    "failed_attempts" SMALLINT NOT NULL DEFAULT 0,
+    "signature" VARCHAR(132),
+    "approved_at" TIMESTAMPTZ,
+    "approval_notified" BOOLEAN NOT NULL DEFAULT FALSE,
+    "claimed_at" TIMESTAMPTZ,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "updated_at" TIMESTAMPTZ
+);
+
+create index "recovery_request_account_idx" on "recovery_request" ("chain_id", "account");
+create index "recovery_request_status_idx" on "recovery_request" ("status");
+create unique index "recovery_request_bind_code_idx" on "recovery_request" ("bind_code_id");
+create unique index "recovery_request_freeze_token_idx" on "recovery_request" ("freeze_token");
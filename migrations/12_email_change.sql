@@ -0,0 +1,19 @@
+create table "email_change"
+(
+    "id" SERIAL PRIMARY KEY,
+    "chain_id" BIGINT NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "old_code_id" INTEGER NOT NULL REFERENCES "bind_code" ("id"),
+    "new_code_id" INTEGER NOT NULL REFERENCES "bind_code" ("id"),
+    "status" SMALLINT NOT NULL,
+    "unlock_at" TIMESTAMPTZ,
+    "cancel_token" CHAR(64) NOT NULL,
+    "notified" BOOLEAN NOT NULL DEFAULT FALSE,
+    "claimed_at" TIMESTAMPTZ,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "updated_at" TIMESTAMPTZ
+);
+
+create index "email_change_account_idx" on "email_change" ("chain_id", "account");
+create index "email_change_status_idx" on "email_change" ("status");
+create unique index "email_change_cancel_token_idx" on "email_change" ("cancel_token");
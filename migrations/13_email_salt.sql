@@ -0,0 +1,6 @@
+create table "email_salt"
+(
+    "account" CHAR(42) PRIMARY KEY,
+    "salt" CHAR(66),
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
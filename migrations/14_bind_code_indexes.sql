@@ -0,0 +1,3 @@
+create index if not exists "bind_code_account_email_idx" on "bind_code" ("account", "email");
+create index if not exists "bind_code_status_id_idx" on "bind_code" ("status", "id");
+create index if not exists "bind_code_created_at_idx" on "bind_code" ("created_at");
@@ -0,0 +1 @@
+alter table "bind_code" add column "claimed_at" TIMESTAMPTZ;
@@ -0,0 +1,35 @@
+create table "email_binding"
+(
+    "id" SERIAL PRIMARY KEY,
+    "chain_id" BIGINT NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "email" VARCHAR(100) NOT NULL,
+    "email_hash" CHAR(66) NOT NULL,
+    "signer" CHAR(42) NOT NULL,
+    "signature_id" INTEGER NOT NULL REFERENCES "binding_signature" ("id"),
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "revoked_at" TIMESTAMPTZ
+);
+
+create unique index "email_binding_active_idx" on "email_binding" ("chain_id", "account", "email_hash") where "revoked_at" is null;
+create index "email_binding_account_idx" on "email_binding" ("account");
+create index "email_binding_email_idx" on "email_binding" ("email");
+
+-- the latest signature of each account and email hash
+insert into "email_binding"("chain_id", "account", "email", "email_hash", "signer", "signature_id", "created_at", "updated_at")
+select distinct on (s.chain_id, lower(s.account), s.email_hash)
+    s.chain_id, lower(s.account), lower(trim(c.email)), s.email_hash, s.signer, s.id, s.created_at, s.created_at
+from "binding_signature" s join "bind_code" c on c.id = s.bind_code_id
+where s.chain_id is not null
+order by s.chain_id, lower(s.account), s.email_hash, s.id desc;
+
+-- superseded bindings are revoked, unless the guardian still holds them
+update "email_binding" b set "revoked_at" = b."updated_at"
+where exists (
+    select 1 from "email_binding" n
+    where n.chain_id = b.chain_id and n.account = b.account and n.signature_id > b.signature_id
+) and not exists (
+    select 1 from "onchain_binding" o
+    where o.chain_id = b.chain_id and o.account = b.account and o.email_hash = b.email_hash and o.bound
+);
@@ -0,0 +1,20 @@
+alter table "bind_code" add column "email_ciphertext" TEXT;
+alter table "bind_code" add column "email_key_version" SMALLINT;
+alter table "bind_code" add column "email_index" CHAR(64);
+
+create index "bind_code_account_email_index_idx" on "bind_code" ("account", "email_index");
+create index "bind_code_email_key_version_idx" on "bind_code" ("email_key_version");
+
+alter table "email_binding" add column "email_ciphertext" TEXT;
+alter table "email_binding" add column "email_key_version" SMALLINT;
+alter table "email_binding" add column "email_index" CHAR(64);
+
+create index "email_binding_email_index_idx" on "email_binding" ("email_index");
+
+alter table "security_alert" add column "email_ciphertext" TEXT;
+alter table "security_alert" add column "email_key_version" SMALLINT;
+alter table "security_alert" add column "email_index" CHAR(64);
+
+-- encrypted alerts share an empty email, so they are told apart by their index
+drop index "security_alert_event_email_idx";
+create unique index "security_alert_event_email_idx" on "security_alert" ("guardian_event_id", (coalesce("email_index", "email")));
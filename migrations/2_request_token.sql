@@ -0,0 +1,3 @@
+alter table "bind_code" add column "request_token" VARCHAR(64);
+
+create unique index "bind_code_request_token_idx" on "bind_code" ("request_token");
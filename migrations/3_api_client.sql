@@ -0,0 +1,21 @@
+create table "api_client"
+(
+    "id" SERIAL PRIMARY KEY,
+    "name" VARCHAR(100) NOT NULL,
+    "key_hash" CHAR(64) NOT NULL UNIQUE,
+    "allowed_origins" TEXT[] NOT NULL DEFAULT '{}',
+    "allowed_methods" TEXT[] NOT NULL DEFAULT '{}',
+    "quota_per_minute" INTEGER NOT NULL,
+    "quota_per_day" INTEGER NOT NULL,
+    "enabled" BOOLEAN NOT NULL DEFAULT TRUE,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+create table "api_client_usage"
+(
+    "client_id" INTEGER NOT NULL REFERENCES "api_client" ("id"),
+    "period" VARCHAR(10) NOT NULL,
+    "period_start" TIMESTAMPTZ NOT NULL,
+    "count" INTEGER NOT NULL,
+    PRIMARY KEY ("client_id", "period", "period_start")
+);
@@ -0,0 +1,5 @@
+create table "used_challenge"
+(
+    "challenge" VARCHAR(100) PRIMARY KEY,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,12 @@
+create table "idempotency_key"
+(
+    "scope" INTEGER NOT NULL,
+    "key" VARCHAR(100) NOT NULL,
+    "fingerprint" CHAR(64) NOT NULL,
+    "response" JSONB,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "locked_until" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY ("scope", "key")
+);
+
+create index "idempotency_key_created_at_idx" on "idempotency_key" ("created_at");
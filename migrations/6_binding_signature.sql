@@ -0,0 +1,23 @@
+create table "binding_signature"
+(
+    "id" SERIAL PRIMARY KEY,
+    "bind_code_id" INTEGER NOT NULL REFERENCES "bind_code" ("id"),
+    "account" CHAR(42) NOT NULL,
+    "email_hash" CHAR(66) NOT NULL,
+    "signer" CHAR(42) NOT NULL,
+    "signature" VARCHAR(132) NOT NULL,
+    "nonce" BIGINT,
+    "deadline" TIMESTAMPTZ,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+create index "binding_signature_account_idx" on "binding_signature" ("account");
+
+create table "ownership_nonce"
+(
+    "nonce" VARCHAR(32) PRIMARY KEY,
+    "account" CHAR(42) NOT NULL,
+    "issued_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "used_at" TIMESTAMPTZ
+);
@@ -0,0 +1,7 @@
+alter table "bind_code" add column "chain_id" BIGINT;
+
+alter table "binding_signature" add column "chain_id" BIGINT;
+alter table "binding_signature" add column "guardian_address" CHAR(42);
+
+delete from "ownership_nonce";
+alter table "ownership_nonce" add column "chain_id" BIGINT NOT NULL;
@@ -0,0 +1,23 @@
+create table "relay_transaction"
+(
+    "id" SERIAL PRIMARY KEY,
+    "bind_code_id" INTEGER NOT NULL REFERENCES "bind_code" ("id"),
+    "chain_id" BIGINT NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "email_hash" CHAR(66) NOT NULL,
+    "signature" VARCHAR(132) NOT NULL,
+    "status" SMALLINT NOT NULL,
+    "nonce" BIGINT,
+    "gas_price" BIGINT,
+    "tx_hash" CHAR(66),
+    "replaced_tx_hashes" TEXT[] NOT NULL DEFAULT '{}',
+    "user_op_hash" CHAR(66),
+    "error" TEXT,
+    "submitted_at" TIMESTAMPTZ,
+    "claimed_at" TIMESTAMPTZ,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    "updated_at" TIMESTAMPTZ
+);
+
+create unique index "relay_transaction_bind_code_idx" on "relay_transaction" ("bind_code_id");
+create index "relay_transaction_status_idx" on "relay_transaction" ("status");
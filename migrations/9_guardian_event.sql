@@ -0,0 +1,40 @@
+create table "indexer_checkpoint"
+(
+    "chain_id" BIGINT NOT NULL,
+    "guardian_address" CHAR(42) NOT NULL,
+    "block_number" BIGINT NOT NULL,
+    "block_hash" CHAR(66) NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY ("chain_id", "guardian_address")
+);
+
+create table "guardian_event"
+(
+    "id" SERIAL PRIMARY KEY,
+    "chain_id" BIGINT NOT NULL,
+    "guardian_address" CHAR(42) NOT NULL,
+    "block_number" BIGINT NOT NULL,
+    "tx_hash" CHAR(66) NOT NULL,
+    "log_index" INTEGER NOT NULL,
+    "kind" VARCHAR(20) NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "email_hash" CHAR(66),
+    "new_owner" CHAR(42),
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+create unique index "guardian_event_log_idx" on "guardian_event" ("chain_id", "guardian_address", "tx_hash", "log_index");
+create index "guardian_event_block_idx" on "guardian_event" ("chain_id", "guardian_address", "block_number");
+
+create table "onchain_binding"
+(
+    "chain_id" BIGINT NOT NULL,
+    "guardian_address" CHAR(42) NOT NULL,
+    "account" CHAR(42) NOT NULL,
+    "email_hash" CHAR(66) NOT NULL,
+    "bound" BOOLEAN NOT NULL,
+    "block_number" BIGINT NOT NULL,
+    "tx_hash" CHAR(66) NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY ("chain_id", "guardian_address", "account")
+);
@@ -0,0 +1,75 @@
+use core::fmt;
+
+use crate::rpc::error::{ErrorCode, RpcError};
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request could not be delivered.
+    Http(reqwest::Error),
+    /// The server answered with a non-success status and no JSON-RPC error.
+    Status(reqwest::StatusCode),
+    /// No response arrived within the configured timeout. The server may still have handled
+    /// the request, so timeouts are only retried for requests that are safe to repeat.
+    Timeout,
+    /// The server answered with a JSON-RPC error.
+    Rpc(RpcError),
+    /// The response could not be decoded into the expected type.
+    Decode(serde_json::Error),
+    /// The response did not contain an answer for the request id.
+    MissingResponse,
+}
+
+impl ClientError {
+    /// The JSON-RPC error code, when the server returned one.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Rpc(err) => Some(err.code),
+            _ => None,
+        }
+    }
+
+    /// Whether another attempt may help. Requests that never reached the server are always
+    /// retried, the others only when `idempotent`.
+    pub(crate) fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            ClientError::Http(err) if err.is_connect() => true,
+            ClientError::Http(err) => idempotent && err.is_timeout(),
+            ClientError::Status(status) => idempotent && status.is_server_error(),
+            ClientError::Timeout => idempotent,
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(value: reqwest::Error) -> Self {
+        ClientError::Http(value)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(value: serde_json::Error) -> Self {
+        ClientError::Decode(value)
+    }
+}
+
+impl From<RpcError> for ClientError {
+    fn from(value: RpcError) -> Self {
+        ClientError::Rpc(value)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(err) => write!(f, "http error: {}", err),
+            ClientError::Status(status) => write!(f, "http status: {}", status),
+            ClientError::Timeout => f.write_str("request timed out"),
+            ClientError::Rpc(err) => write!(f, "rpc error: {}", err),
+            ClientError::Decode(err) => write!(f, "decode error: {}", err),
+            ClientError::MissingResponse => f.write_str("missing response"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
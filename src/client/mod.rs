@@ -0,0 +1,657 @@
+pub mod error;
+
+use std::{
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+
+use self::error::ClientError;
+use crate::{
+    rpc::{
+        request::{Id, Request, RequestParams, RpcCall, RpcMethodCall, Version},
+        response::{Response, ResponseResult, RpcResponse},
+    },
+    server::auth::API_KEY_HEADER,
+    service::{
+        account::{AccountAddress, CounterfactualAccount},
+        alert::AccountFreeze,
+        challenge::Challenge,
+        code::{SendCodeOptions, SendCodeResult},
+        email_change::{
+            ChangeEmailOptions, ChangeEmailResult, ConfirmEmailChangeOptions, EmailChangeResult,
+        },
+        ownership::OwnershipChallenge,
+        recovery::{ApproveRecoveryOptions, RecoveryApproval, StartRecoveryOptions},
+        signature::BindingSignature,
+        status::{BindingStatus, StatusOptions},
+        verify::{VerifyCodeOptions, VerifyCodeResponse},
+    },
+};
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// A method call to send as part of a batch.
+#[derive(Debug, Clone)]
+pub struct BatchCall {
+    pub method: String,
+    pub params: Vec<serde_json::Value>,
+}
+
+impl BatchCall {
+    pub fn new<P: Serialize>(method: impl Into<String>, params: P) -> Result<Self> {
+        let params = match serde_json::to_value(params)? {
+            serde_json::Value::Array(params) => params,
+            serde_json::Value::Null => Vec::new(),
+            param => vec![param],
+        };
+        Ok(BatchCall {
+            method: method.into(),
+            params,
+        })
+    }
+
+    pub fn send_code(account: &str, email: &str) -> Self {
+        BatchCall {
+            method: "send_code".to_string(),
+            params: vec![account.into(), email.into()],
+        }
+    }
+
+    pub fn send_code_with(account: &str, email: &str, options: &SendCodeOptions) -> Result<Self> {
+        Self::new("send_code", (account, email, options))
+    }
+
+    pub fn verify_code(account: &str, email: &str, code: &str) -> Self {
+        BatchCall {
+            method: "verify_code".to_string(),
+            params: vec![account.into(), email.into(), code.into()],
+        }
+    }
+
+    pub fn verify_code_with(
+        account: &str,
+        email: &str,
+        code: &str,
+        options: &VerifyCodeOptions,
+    ) -> Result<Self> {
+        Self::new("verify_code", (account, email, code, options))
+    }
+
+    pub fn get_status(account: &str, email: &str, options: &StatusOptions) -> Result<Self> {
+        Self::new("get_status", (account, email, options))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    url: String,
+    timeout: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+    api_key: Option<String>,
+}
+
+impl ClientBuilder {
+    pub fn new(url: impl Into<String>) -> Self {
+        ClientBuilder {
+            url: url.into(),
+            timeout: Duration::from_secs(30),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(500),
+            api_key: None,
+        }
+    }
+
+    /// API key sent in the `x-api-key` header.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Deadline for a single attempt.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of additional attempts after a transport failure or timeout.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Base delay between attempts, doubled after every retry.
+    pub fn retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    pub fn build(self) -> Result<BinderClient> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(api_key) = &self.api_key {
+            if let Ok(value) = reqwest::header::HeaderValue::from_str(api_key) {
+                headers.insert(API_KEY_HEADER, value);
+            }
+        }
+        let http = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .default_headers(headers)
+            .build()?;
+        Ok(BinderClient {
+            http,
+            url: self.url,
+            max_retries: self.max_retries,
+            retry_backoff: self.retry_backoff,
+            next_id: Arc::new(AtomicI64::new(1)),
+        })
+    }
+}
+
+/// Typed async client for the binder JSON-RPC API.
+#[derive(Debug, Clone)]
+pub struct BinderClient {
+    http: reqwest::Client,
+    url: String,
+    max_retries: u32,
+    retry_backoff: Duration,
+    next_id: Arc<AtomicI64>,
+}
+
+impl BinderClient {
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        ClientBuilder::new(url).build()
+    }
+
+    pub fn builder(url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder::new(url)
+    }
+
+    pub async fn send_code(&self, account: &str, email: &str) -> Result<SendCodeResult> {
+        self.call_one(BatchCall::send_code(account, email)).await
+    }
+
+    /// Sends a code with a challenge solution or other options.
+    pub async fn send_code_with(
+        &self,
+        account: &str,
+        email: &str,
+        options: &SendCodeOptions,
+    ) -> Result<SendCodeResult> {
+        self.call_one(BatchCall::send_code_with(account, email, options)?)
+            .await
+    }
+
+    pub async fn get_challenge(&self) -> Result<Challenge> {
+        self.call_one(BatchCall::new("get_challenge", ())?).await
+    }
+
+    pub async fn verify_code(&self, account: &str, email: &str, code: &str) -> Result<String> {
+        self.call_one(BatchCall::verify_code(account, email, code))
+            .await
+    }
+
+    /// Verifies a code with an idempotency key or other options. The signature comes as a
+    /// [`VerifyCodeResponse::Detailed`] result when `options.detailed` is set.
+    pub async fn verify_code_with(
+        &self,
+        account: &str,
+        email: &str,
+        code: &str,
+        options: &VerifyCodeOptions,
+    ) -> Result<VerifyCodeResponse> {
+        self.call_one(BatchCall::verify_code_with(account, email, code, options)?)
+            .await
+    }
+
+    /// The status of the code `send_code` returned `options.request_id` for.
+    pub async fn get_status(
+        &self,
+        account: &str,
+        email: &str,
+        options: &StatusOptions,
+    ) -> Result<BindingStatus> {
+        self.call_one(BatchCall::get_status(account, email, options)?)
+            .await
+    }
+
+    /// Requests an ownership challenge on `chain_id`, or the default chain.
+    pub async fn get_ownership_challenge(
+        &self,
+        account: &str,
+        chain_id: Option<u64>,
+    ) -> Result<OwnershipChallenge> {
+        self.call("get_ownership_challenge", (account, chain_id))
+            .await
+    }
+
+    /// Fetches the latest binding signature of `account` with a `personal_sign`
+    /// signature over the message of an ownership challenge.
+    pub async fn get_signature(
+        &self,
+        account: &str,
+        nonce: &str,
+        signature: &str,
+        chain_id: Option<u64>,
+    ) -> Result<BindingSignature> {
+        self.call("get_signature", (account, nonce, signature, chain_id))
+            .await
+    }
+
+    /// Derives the address of a counterfactual account and whether it is deployed.
+    pub async fn get_account_address(
+        &self,
+        account: &CounterfactualAccount,
+        chain_id: Option<u64>,
+    ) -> Result<AccountAddress> {
+        self.call("get_account_address", (account, chain_id)).await
+    }
+
+    /// Freezes the account of a security alert with the token of its "this wasn't me" link.
+    pub async fn freeze_account(&self, token: &str) -> Result<AccountFreeze> {
+        self.call("freeze_account", (token,)).await
+    }
+
+    /// Mails a recovery code to the email bound to `account`.
+    pub async fn start_recovery(
+        &self,
+        account: &str,
+        new_owner: &str,
+        options: &StartRecoveryOptions,
+    ) -> Result<SendCodeResult> {
+        self.call("start_recovery", (account, new_owner, options))
+            .await
+    }
+
+    /// Exchanges a recovery code for the guardian signer's recovery approval.
+    pub async fn approve_recovery(
+        &self,
+        account: &str,
+        code: &str,
+        options: &ApproveRecoveryOptions,
+    ) -> Result<RecoveryApproval> {
+        self.call("approve_recovery", (account, code, options))
+            .await
+    }
+
+    /// Mails codes to both the bound and the new address of `account`.
+    pub async fn change_email(
+        &self,
+        account: &str,
+        new_email: &str,
+        options: &ChangeEmailOptions,
+    ) -> Result<ChangeEmailResult> {
+        self.call("change_email", (account, new_email, options))
+            .await
+    }
+
+    /// Confirms the pending email change of `account` with the mailed codes.
+    pub async fn confirm_email_change(
+        &self,
+        account: &str,
+        options: &ConfirmEmailChangeOptions,
+    ) -> Result<EmailChangeResult> {
+        self.call("confirm_email_change", (account, options)).await
+    }
+
+    /// Cancels an email change with the token mailed to the bound address.
+    pub async fn cancel_email_change(&self, token: &str) -> Result<EmailChangeResult> {
+        self.call("cancel_email_change", (token,)).await
+    }
+
+    /// Calls an arbitrary method and decodes its result.
+    pub async fn call<P, R>(&self, method: &str, params: P) -> Result<R>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        self.call_one(BatchCall::new(method, params)?).await
+    }
+
+    async fn call_one<R: DeserializeOwned>(&self, call: BatchCall) -> Result<R> {
+        let id = self.next_id();
+        let request = Request::Single(self.method_call(id.clone(), call));
+        let response = match self.send(&request).await? {
+            Response::Single(response) => response,
+            Response::Batch(_) => return Err(ClientError::MissingResponse),
+        };
+        if response.id() != Some(&id) && !is_unaddressed(&response) {
+            return Err(ClientError::MissingResponse);
+        }
+        decode_result(response)
+    }
+
+    /// Sends all calls in a single `Request::Batch` and returns their results in call order.
+    pub async fn batch(&self, calls: Vec<BatchCall>) -> Result<Vec<Result<serde_json::Value>>> {
+        if calls.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let ids: Vec<Id> = calls.iter().map(|_| self.next_id()).collect();
+        let request = Request::Batch(
+            calls
+                .into_iter()
+                .zip(ids.iter().cloned())
+                .map(|(call, id)| self.method_call(id, call))
+                .collect(),
+        );
+        let mut responses = match self.send(&request).await? {
+            Response::Batch(responses) => responses,
+            // requests rejected as a whole, such as unauthorized ones, get a single error
+            Response::Single(response) if is_unaddressed(&response) => {
+                return decode_result::<serde_json::Value>(response).map(|_| Vec::new())
+            }
+            Response::Single(response) => vec![response],
+        };
+
+        Ok(ids
+            .iter()
+            .map(|id| {
+                let index = responses
+                    .iter()
+                    .position(|response| response.id() == Some(id))
+                    .ok_or(ClientError::MissingResponse)?;
+                decode_result(responses.swap_remove(index))
+            })
+            .collect())
+    }
+
+    fn next_id(&self) -> Id {
+        Id::Number(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn method_call(&self, id: Id, call: BatchCall) -> RpcCall {
+        RpcCall::MethodCall(RpcMethodCall {
+            jsonrpc: Version::V2,
+            method: call.method,
+            params: RequestParams::Array(call.params),
+            id,
+        })
+    }
+
+    async fn send(&self, request: &Request) -> Result<Response> {
+        let idempotent = match request {
+            Request::Single(call) => is_idempotent(call),
+            Request::Batch(calls) => calls.iter().all(is_idempotent),
+        };
+        let mut attempt = 0;
+        loop {
+            match self.send_once(request).await {
+                Err(err) if err.is_retryable(idempotent) && attempt < self.max_retries => {
+                    warn!(target: "client", attempt, %err, "retrying binder request");
+                    tokio::time::sleep(self.retry_backoff * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_once(&self, request: &Request) -> Result<Response> {
+        let response = self
+            .http
+            .post(&self.url)
+            .json(request)
+            .send()
+            .await
+            .map_err(|err| {
+                if err.is_timeout() {
+                    ClientError::Timeout
+                } else {
+                    ClientError::Http(err)
+                }
+            })?;
+        // rejected requests still carry a JSON-RPC error, which tells more than the status
+        let status = response.status();
+        let body = response.bytes().await?;
+        match serde_json::from_slice(&body) {
+            Ok(response) => Ok(response),
+            Err(err) if status.is_success() => Err(err.into()),
+            Err(_) => Err(ClientError::Status(status)),
+        }
+    }
+}
+
+/// Methods without side effects a repeated call could duplicate.
+const READ_ONLY_METHODS: &[&str] = &["get_account_address", "get_challenge", "get_status"];
+
+/// Whether sending `call` twice has the effect of sending it once: read-only methods without
+/// an ownership proof, whose nonce a retry could not reuse, and calls carrying an
+/// `idempotency_key`.
+fn is_idempotent(call: &RpcCall) -> bool {
+    let call = match call {
+        RpcCall::MethodCall(call) => call,
+        _ => return false,
+    };
+    let options = match &call.params {
+        RequestParams::Array(params) => params.last().and_then(|options| options.as_object()),
+        _ => None,
+    };
+    let has = |field: &str| {
+        options.is_some_and(|options| options.get(field).is_some_and(|value| !value.is_null()))
+    };
+    (READ_ONLY_METHODS.contains(&call.method.as_str()) && !has("ownership"))
+        || has("idempotency_key")
+}
+
+/// Whether the response answers no call in particular, as errors of rejected requests do.
+fn is_unaddressed(response: &RpcResponse) -> bool {
+    matches!(response.id(), None | Some(Id::Null))
+}
+
+fn decode_result<R: DeserializeOwned>(response: RpcResponse) -> Result<R> {
+    match response.into_result() {
+        ResponseResult::Success(value) => Ok(serde_json::from_value(value)?),
+        ResponseResult::Error(err) => Err(ClientError::Rpc(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rpc::error::{ErrorCode, RpcError},
+        server::{
+            auth::{Authenticator, ClientIdentity},
+            handler::{serve_http, RpcHandler},
+        },
+        service::{
+            events::EventHub, ownership::OwnershipProof, verify::VerifyCodeResult, ApiRequest,
+        },
+    };
+
+    #[derive(Clone)]
+    struct StubHandler;
+
+    #[async_trait::async_trait]
+    impl RpcHandler for StubHandler {
+        type Request = ApiRequest;
+
+        async fn on_request(&self, request: Self::Request, _: &ClientIdentity) -> ResponseResult {
+            match request {
+                ApiRequest::SendCode(_, email, _) => ResponseResult::success(SendCodeResult {
+                    request_id: "token".to_string(),
+                    issued: true,
+                    expires_at: chrono::Utc::now(),
+                    resend_available_at: chrono::Utc::now(),
+                    email,
+                    chain_id: Some(4690),
+                }),
+                ApiRequest::VerifyCode(_, _, code, options) if code == "123456" => {
+                    let signature = "0xsignature".to_string();
+                    ResponseResult::success(if options.detailed {
+                        VerifyCodeResponse::Detailed(VerifyCodeResult {
+                            signature,
+                            chain_id: 4690,
+                            guardian: "0xBf081D23317966eEBD59Bc8EDB593A830F373178".to_string(),
+                            user_operation: None,
+                            email_hash: None,
+                        })
+                    } else {
+                        VerifyCodeResponse::Signature(signature)
+                    })
+                }
+                _ => RpcError::internal_error_with("error code").into(),
+            }
+        }
+    }
+
+    async fn spawn_server() -> String {
+        let server = serve_http(
+            "127.0.0.1:0".parse().unwrap(),
+            StubHandler,
+            EventHub::new(),
+            Authenticator::anonymous(),
+        );
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn calls_methods_against_in_process_server() {
+        let client = BinderClient::new(spawn_server().await).unwrap();
+
+        let result = client.send_code("0x01", "test@test.com").await.unwrap();
+        assert_eq!(result.request_id, "token");
+        assert_eq!(
+            client
+                .verify_code("0x01", "test@test.com", "123456")
+                .await
+                .unwrap(),
+            "0xsignature"
+        );
+        let detailed = VerifyCodeOptions {
+            detailed: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            client
+                .verify_code_with("0x01", "test@test.com", "123456", &detailed)
+                .await
+                .unwrap(),
+            VerifyCodeResponse::Detailed(result) if result.chain_id == 4690
+        ));
+
+        let err = client
+            .verify_code("0x01", "test@test.com", "000000")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::InternalError));
+        assert!(matches!(
+            client.call::<_, String>("unknown", ()).await.unwrap_err(),
+            ClientError::Rpc(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn batches_calls_in_order() {
+        let client = BinderClient::new(spawn_server().await).unwrap();
+
+        let results = client
+            .batch(vec![
+                BatchCall::verify_code("0x01", "test@test.com", "000000"),
+                BatchCall::verify_code("0x01", "test@test.com", "123456"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap(), "0xsignature");
+    }
+
+    #[test]
+    fn only_repeats_idempotent_calls() {
+        let client = BinderClient::new("http://127.0.0.1:1/").unwrap();
+        let call = |call: BatchCall| client.method_call(Id::Number(1), call);
+
+        let keyed = call(
+            BatchCall::verify_code_with(
+                "0x01",
+                "test@test.com",
+                "123456",
+                &VerifyCodeOptions {
+                    idempotency_key: Some("key".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+        );
+
+        assert!(!is_idempotent(&call(BatchCall::verify_code(
+            "0x01",
+            "test@test.com",
+            "123456"
+        ))));
+        assert!(is_idempotent(&keyed));
+        assert!(is_idempotent(&call(
+            BatchCall::get_status("0x01", "test@test.com", &Default::default()).unwrap()
+        )));
+        let proven = StatusOptions {
+            ownership: Some(OwnershipProof {
+                nonce: "nonce".to_string(),
+                signature: "0x01".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(!is_idempotent(&call(
+            BatchCall::get_status("0x01", "test@test.com", &proven).unwrap()
+        )));
+    }
+
+    #[tokio::test]
+    async fn decodes_errors_of_rejected_requests() {
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post(|| async {
+                (
+                    hyper::StatusCode::TOO_MANY_REQUESTS,
+                    axum::Json(Response::error(RpcError::internal_error_with("slow down"))),
+                )
+            }),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let client = BinderClient::new(format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let err = client
+            .get_status("0x01", "test@test.com", &Default::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::InternalError));
+        assert!(matches!(
+            client
+                .batch(vec![BatchCall::verify_code(
+                    "0x01",
+                    "test@test.com",
+                    "123456"
+                )])
+                .await
+                .unwrap_err(),
+            ClientError::Rpc(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_unreachable_server() {
+        let client = BinderClient::builder("http://127.0.0.1:1/")
+            .max_retries(1)
+            .retry_backoff(Duration::from_millis(1))
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            client
+                .get_status("0x01", "test@test.com", &Default::default())
+                .await
+                .unwrap_err(),
+            ClientError::Http(_)
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use ethers::{
+    abi::{encode, AbiEncode, Token},
+    prelude::abigen,
+    providers::Middleware,
+    types::{Address, Bytes, U256},
+    utils::{get_create2_address, id},
+};
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use super::provider::ChainProvider;
+
+abigen!(
+    IERC1271,
+    r#"[
+        function isValidSignature(bytes32, bytes) external view returns (bytes4)
+    ]"#,
+);
+
+abigen!(
+    ISimpleAccount,
+    r#"[
+        function execute(address dest, uint256 value, bytes func) external
+    ]"#,
+);
+
+abigen!(
+    ISimpleAccountFactory,
+    r#"[
+        function createAccount(address owner, uint256 salt) external returns (address)
+    ]"#,
+);
+
+/// `bytes4(keccak256("isValidSignature(bytes32,bytes)"))`
+pub const ERC1271_MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+pub async fn is_contract(provider: ChainProvider, account: Address) -> Result<bool> {
+    let code = provider.get_code(account, None).await?;
+    Ok(!code.is_empty())
+}
+
+/// Asks a smart account whether `signature` is valid for `hash` through ERC-1271.
+pub async fn is_valid_signature(
+    provider: ChainProvider,
+    account: Address,
+    hash: [u8; 32],
+    signature: Bytes,
+) -> Result<bool> {
+    let wallet = IERC1271::new(account, Arc::new(provider));
+    let result = wallet.is_valid_signature(hash, signature).call().await?;
+    Ok(result == ERC1271_MAGIC_VALUE)
+}
+
+/// Calldata of an account call executing `func` on `dest`.
+pub fn execute_call_data(dest: Address, value: U256, func: Bytes) -> Bytes {
+    ExecuteCall { dest, value, func }.encode().into()
+}
+
+/// A factory deploying accounts as `ERC1967Proxy` instances of an implementation, like
+/// the ERC-4337 `SimpleAccountFactory`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountFactory {
+    pub address: Address,
+    pub implementation: Address,
+    /// Creation code of the proxy, without constructor arguments.
+    pub proxy_creation_code: Bytes,
+}
+
+impl AccountFactory {
+    /// The init code of the proxy the factory deploys for `owner`:
+    /// `proxyCreationCode ++ abi.encode(implementation, abi.encodeCall(initialize, (owner)))`.
+    pub fn init_code(&self, owner: Address) -> Vec<u8> {
+        let initialize = [
+            id("initialize(address)").to_vec(),
+            encode(&[Token::Address(owner)]),
+        ]
+        .concat();
+        [
+            self.proxy_creation_code.to_vec(),
+            encode(&[
+                Token::Address(self.implementation),
+                Token::Bytes(initialize),
+            ]),
+        ]
+        .concat()
+    }
+
+    /// The ERC-4337 `initCode` deploying the account of `owner` and `salt`.
+    pub fn user_operation_init_code(&self, owner: Address, salt: U256) -> Bytes {
+        [
+            self.address.as_bytes().to_vec(),
+            CreateAccountCall { owner, salt }.encode(),
+        ]
+        .concat()
+        .into()
+    }
+
+    /// The CREATE2 address of the account of `owner` and `salt`, deployed or not.
+    pub fn account_address(&self, owner: Address, salt: U256) -> Address {
+        let mut salt_bytes = [0u8; 32];
+        salt.to_big_endian(&mut salt_bytes);
+        get_create2_address(self.address, salt_bytes, self.init_code(owner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::utils::keccak256;
+
+    #[test]
+    fn computes_counterfactual_address() {
+        let factory = AccountFactory {
+            address: "0x9406Cc6185a346906296840746125a0E44976454"
+                .parse()
+                .unwrap(),
+            implementation: "0x8ABB13360b87Be5EEb1B98647A016adD927a136c"
+                .parse()
+                .unwrap(),
+            proxy_creation_code: Bytes::from(vec![0x60, 0x80, 0x60, 0x40]),
+        };
+        let owner: Address = "0x8803DAF0AB9Bad65a56F4D9AEcA56085491C299A"
+            .parse()
+            .unwrap();
+
+        let init_code = factory.init_code(owner);
+        assert_eq!(&init_code[..4], &[0x60, 0x80, 0x60, 0x40]);
+        // implementation word, bytes offset, bytes length, then the 36 bytes call padded to 64
+        assert_eq!(init_code.len(), 4 + 32 * 3 + 64);
+        assert_eq!(&init_code[4 + 96..4 + 100], &id("initialize(address)"));
+
+        let address = factory.account_address(owner, U256::from(7));
+        let mut preimage = vec![0xff];
+        preimage.extend_from_slice(factory.address.as_bytes());
+        let mut salt = [0u8; 32];
+        salt[31] = 7;
+        preimage.extend_from_slice(&salt);
+        preimage.extend_from_slice(&keccak256(&init_code));
+        assert_eq!(address, Address::from_slice(&keccak256(preimage)[12..]));
+        assert_ne!(address, factory.account_address(owner, U256::from(8)));
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use ethers::{
+    abi::{encode, Token},
+    prelude::abigen,
+    types::{Address, Bytes, TransactionReceipt, U256},
+    utils::keccak256,
+};
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use super::provider::ChainProvider;
+
+abigen!(
+    IEntryPoint,
+    r#"[
+        function getNonce(address sender, uint192 key) external view returns (uint256)
+    ]"#,
+);
+
+/// The ERC-4337 v0.6 EntryPoint deployed at the same address on every chain.
+pub const DEFAULT_ENTRY_POINT: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+
+/// The next nonce of `sender` on the default nonce key.
+pub async fn get_nonce(
+    provider: ChainProvider,
+    entry_point: Address,
+    sender: Address,
+) -> Result<U256> {
+    let entry_point = IEntryPoint::new(entry_point, Arc::new(provider));
+    let nonce = entry_point.get_nonce(sender, U256::zero()).call().await?;
+    Ok(nonce)
+}
+
+/// An ERC-4337 v0.6 UserOperation, as sent to bundlers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperation {
+    pub sender: Address,
+    pub nonce: U256,
+    pub init_code: Bytes,
+    pub call_data: Bytes,
+    pub call_gas_limit: U256,
+    pub verification_gas_limit: U256,
+    pub pre_verification_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub paymaster_and_data: Bytes,
+    pub signature: Bytes,
+}
+
+impl UserOperation {
+    /// The hash the sender signs, as computed by `EntryPoint.getUserOpHash`.
+    pub fn hash(&self, entry_point: Address, chain_id: u64) -> [u8; 32] {
+        let packed = encode(&[
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::FixedBytes(keccak256(&self.init_code).to_vec()),
+            Token::FixedBytes(keccak256(&self.call_data).to_vec()),
+            Token::Uint(self.call_gas_limit),
+            Token::Uint(self.verification_gas_limit),
+            Token::Uint(self.pre_verification_gas),
+            Token::Uint(self.max_fee_per_gas),
+            Token::Uint(self.max_priority_fee_per_gas),
+            Token::FixedBytes(keccak256(&self.paymaster_and_data).to_vec()),
+        ]);
+        keccak256(encode(&[
+            Token::FixedBytes(keccak256(packed).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(chain_id.into()),
+        ]))
+    }
+}
+
+/// Gas limits returned by a bundler's `eth_estimateUserOperationGas`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationGas {
+    pub pre_verification_gas: U256,
+    pub verification_gas_limit: U256,
+    pub call_gas_limit: U256,
+}
+
+/// A bundler's `eth_getUserOperationReceipt` result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationReceipt {
+    pub success: bool,
+    pub receipt: TransactionReceipt,
+}
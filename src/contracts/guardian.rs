@@ -1,36 +1,136 @@
 use std::sync::Arc;
 
 use ethers::{
+    abi::{encode, AbiEncode, Token},
     prelude::abigen,
-    providers::{Http, Provider},
-    types::Address,
+    types::{Address, Bytes},
     utils::keccak256,
 };
 use eyre::Result;
 
+use super::provider::ChainProvider;
+
 abigen!(
     IEmailGuardian,
     r#"[
         function getHash(address, bytes32) external view returns (bytes32)
+        function nonces(address) external view returns (uint256)
+        function getRecoveryHash(address account, address newOwner) external view returns (bytes32)
+        function bindEmail(bytes32 email, bytes signature) external
+        function bindEmailFor(address account, bytes32 email, bytes signature) external
+        event EmailBound(address indexed account, bytes32 indexed email)
+        event EmailRemoved(address indexed account, bytes32 indexed email)
+        event RecoveryStarted(address indexed account, address newOwner)
     ]"#,
 );
 
 pub async fn get_hash(
-    provider: Provider<Http>,
-    guardian_address: &str,
-    account: &str,
-    email: &str,
+    provider: ChainProvider,
+    guardian: Address,
+    account: Address,
+    email_hash: [u8; 32],
+) -> Result<[u8; 32]> {
+    let client = Arc::new(provider);
+    let guardian = IEmailGuardian::new(guardian, client);
+
+    let hash = guardian.get_hash(account, email_hash).call().await?;
+    Ok(hash)
+}
+
+/// The number of bindings of `account` on the guardian, the `nonce` of its next binding hash.
+pub async fn get_nonce(
+    provider: ChainProvider,
+    guardian: Address,
+    account: Address,
+) -> Result<u64> {
+    let client = Arc::new(provider);
+    let guardian = IEmailGuardian::new(guardian, client);
+
+    let nonce = guardian.nonces(account).call().await?;
+    Ok(nonce.as_u64())
+}
+
+/// The hash the guardian expects its signer to approve for moving `account` to `new_owner`.
+pub async fn get_recovery_hash(
+    provider: ChainProvider,
+    guardian: Address,
+    account: Address,
+    new_owner: Address,
 ) -> Result<[u8; 32]> {
     let client = Arc::new(provider);
-    let address: Address = guardian_address
-        .parse()
-        .expect("parse guardian address error");
-    let account: Address = account.parse().expect("parse account address error");
-    let guardian = IEmailGuardian::new(address, client);
+    let guardian = IEmailGuardian::new(guardian, client);
 
     let hash = guardian
-        .get_hash(account, keccak256::<&str>(email))
+        .get_recovery_hash(account, new_owner)
         .call()
         .await?;
     Ok(hash)
 }
+
+/// Calldata of the guardian call an account makes to bind `email_hash` with our signature.
+pub fn bind_email_call_data(email_hash: [u8; 32], signature: Bytes) -> Bytes {
+    BindEmailCall {
+        email: email_hash,
+        signature,
+    }
+    .encode()
+    .into()
+}
+
+/// Calldata of the guardian call a relayer makes to bind `email_hash` to `account`.
+pub fn bind_email_for_call_data(account: Address, email_hash: [u8; 32], signature: Bytes) -> Bytes {
+    BindEmailForCall {
+        account,
+        email: email_hash,
+        signature,
+    }
+    .encode()
+    .into()
+}
+
+/// Derives the hash `getHash` returns without calling the guardian:
+/// `keccak256(abi.encode(block.chainid, address(this), account, emailHash, nonce))`,
+/// where `nonce` counts the bindings of `account` on the guardian.
+pub fn local_hash(
+    chain_id: u64,
+    guardian: Address,
+    account: Address,
+    email_hash: [u8; 32],
+    nonce: u64,
+) -> [u8; 32] {
+    keccak256(encode(&[
+        Token::Uint(chain_id.into()),
+        Token::Address(guardian),
+        Token::Address(account),
+        Token::FixedBytes(email_hash.to_vec()),
+        Token::Uint(nonce.into()),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_hash_inputs_as_words() {
+        let guardian: Address = "0xBf081D23317966eEBD59Bc8EDB593A830F373178"
+            .parse()
+            .unwrap();
+        let account: Address = "0x8803DAF0AB9Bad65a56F4D9AEcA56085491C299A"
+            .parse()
+            .unwrap();
+        let email_hash = keccak256("test@test.com");
+
+        let mut words = [0u8; 160];
+        words[24..32].copy_from_slice(&4690u64.to_be_bytes());
+        words[44..64].copy_from_slice(guardian.as_bytes());
+        words[76..96].copy_from_slice(account.as_bytes());
+        words[96..128].copy_from_slice(&email_hash);
+        words[159] = 1;
+
+        assert_eq!(
+            local_hash(4690, guardian, account, email_hash, 1),
+            keccak256(words)
+        );
+    }
+}
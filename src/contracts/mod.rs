@@ -1 +1,4 @@
+pub mod account;
+pub mod entry_point;
 pub mod guardian;
+pub mod provider;
@@ -0,0 +1,576 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use ethers::providers::{Http, JsonRpcClient, JsonRpcError, Provider, ProviderError, RpcError, Ws};
+use futures::future::{join_all, BoxFuture};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+/// Provider used for every chain access of the service.
+pub type ChainProvider = Provider<FailoverClient>;
+
+/// Methods answered by a quorum of endpoints when `quorum` is above one.
+const QUORUM_METHODS: [&str; 3] = ["eth_call", "eth_getCode", "eth_getStorageAt"];
+
+const MAX_SCORE: u32 = 100;
+
+/// Timeouts, retries and quorum of a chain's endpoints.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProviderConfig {
+    /// Timeout of a single request to one endpoint.
+    pub request_timeout_ms: u64,
+    /// Deadline of a call across all endpoints and retries.
+    pub deadline_ms: u64,
+    /// Passes over the endpoints after the first one failed.
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled on each following one.
+    pub retry_backoff_ms: u64,
+    /// Endpoints that must return the same result for a quorum read.
+    pub quorum: usize,
+}
+
+impl Default for ProviderConfig {
+    fn default() -> Self {
+        ProviderConfig {
+            request_timeout_ms: 3_000,
+            deadline_ms: 10_000,
+            max_retries: 2,
+            retry_backoff_ms: 200,
+            quorum: 1,
+        }
+    }
+}
+
+/// An object safe JSON-RPC transport.
+#[async_trait]
+pub trait Transport: fmt::Debug + Send + Sync {
+    async fn call(&self, method: &str, params: Option<Value>) -> Result<Value, ProviderError>;
+}
+
+#[async_trait]
+impl<C: JsonRpcClient> Transport for C {
+    async fn call(&self, method: &str, params: Option<Value>) -> Result<Value, ProviderError> {
+        match params {
+            Some(params) => JsonRpcClient::request(self, method, params).await,
+            None => JsonRpcClient::request(self, method, ()).await,
+        }
+        .map_err(Into::into)
+    }
+}
+
+type Connect<C> = Box<dyn Fn(String) -> BoxFuture<'static, Result<C, ProviderError>> + Send + Sync>;
+
+/// A connection, numbered so a failed one is only dropped once.
+#[derive(Debug)]
+struct Connection<C> {
+    generation: u64,
+    client: Option<C>,
+}
+
+/// An endpoint connected on first use, and again on the next call after a transport error,
+/// like a WebSocket endpoint whose connection dropped.
+struct Reconnecting<C> {
+    url: String,
+    connect: Connect<C>,
+    connection: tokio::sync::Mutex<Connection<C>>,
+}
+
+impl<C> Reconnecting<C> {
+    fn new(url: String, connect: Connect<C>) -> Self {
+        Reconnecting {
+            url,
+            connect,
+            connection: tokio::sync::Mutex::new(Connection {
+                generation: 0,
+                client: None,
+            }),
+        }
+    }
+}
+
+impl Reconnecting<Ws> {
+    fn ws(url: String) -> Self {
+        Self::new(
+            url,
+            Box::new(|url| Box::pin(async move { Ok(Ws::connect(url).await?) })),
+        )
+    }
+}
+
+impl<C> fmt::Debug for Reconnecting<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reconnecting")
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+impl<C: Clone> Reconnecting<C> {
+    async fn client(&self) -> Result<(u64, C), ProviderError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(client) = &connection.client {
+            return Ok((connection.generation, client.clone()));
+        }
+        let client = (self.connect)(self.url.clone()).await?;
+        connection.generation += 1;
+        connection.client = Some(client.clone());
+        Ok((connection.generation, client))
+    }
+}
+
+#[async_trait]
+impl<C: Transport + Clone> Transport for Reconnecting<C> {
+    async fn call(&self, method: &str, params: Option<Value>) -> Result<Value, ProviderError> {
+        let (generation, client) = self.client().await?;
+        let result = client.call(method, params).await;
+        if let Err(err) = &result {
+            // node errors come over a working connection, anything else drops it
+            if err.as_error_response().is_none() {
+                let mut connection = self.connection.lock().await;
+                if connection.generation == generation {
+                    connection.client = None;
+                }
+            }
+        }
+        result
+    }
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: String,
+    transport: Box<dyn Transport>,
+    /// Lowered on failures and raised on successes, endpoints are tried by descending score.
+    score: Mutex<u32>,
+}
+
+impl Endpoint {
+    fn score(&self) -> u32 {
+        *self.score.lock().unwrap()
+    }
+
+    fn record_success(&self) {
+        let mut score = self.score.lock().unwrap();
+        *score = (*score + 10).min(MAX_SCORE);
+    }
+
+    fn record_failure(&self) {
+        let mut score = self.score.lock().unwrap();
+        *score /= 2;
+    }
+
+    /// Sends a request, telling node errors apart from transport errors and timeouts.
+    async fn send(
+        &self,
+        method: &str,
+        params: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Value, Attempt> {
+        match tokio::time::timeout(timeout, self.transport.call(method, params)).await {
+            Ok(Ok(value)) => {
+                self.record_success();
+                Ok(value)
+            }
+            Ok(Err(err)) => match err.as_error_response() {
+                Some(response) => {
+                    self.record_success();
+                    Err(Attempt::Response(response.clone()))
+                }
+                None => {
+                    self.record_failure();
+                    warn!(target: "provider", url = %self.url, %err, method, "endpoint error");
+                    Err(Attempt::Failed(err.to_string()))
+                }
+            },
+            Err(_) => {
+                self.record_failure();
+                warn!(target: "provider", url = %self.url, method, "endpoint timeout");
+                Err(Attempt::Failed(format!("{} timed out", self.url)))
+            }
+        }
+    }
+}
+
+enum Attempt {
+    /// The endpoint answered with a JSON-RPC error, e.g. a reverted call.
+    Response(JsonRpcError),
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub enum FailoverError {
+    /// An endpoint answered with a JSON-RPC error.
+    JsonRpc(JsonRpcError),
+    SerdeJson(serde_json::Error),
+    /// The call deadline passed.
+    Timeout,
+    /// Endpoints answered but not enough of them agreed.
+    NoQuorum,
+    /// No endpoint answered; holds the last failure.
+    Unavailable(String),
+}
+
+impl fmt::Display for FailoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailoverError::JsonRpc(err) => write!(f, "{}", err),
+            FailoverError::SerdeJson(err) => write!(f, "{}", err),
+            FailoverError::Timeout => write!(f, "chain request deadline exceeded"),
+            FailoverError::NoQuorum => write!(f, "chain endpoints did not reach quorum"),
+            FailoverError::Unavailable(err) => write!(f, "no chain endpoint available: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FailoverError {}
+
+impl RpcError for FailoverError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            FailoverError::JsonRpc(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            FailoverError::SerdeJson(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<FailoverError> for ProviderError {
+    fn from(value: FailoverError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(value))
+    }
+}
+
+/// A JSON-RPC client over several HTTP and WebSocket endpoints of one chain.
+///
+/// Calls go to the healthiest endpoint first and fail over to the others on
+/// transport errors or timeouts, until the call deadline. Quorum reads ask all
+/// endpoints and return the first result enough of them agree on.
+#[derive(Debug, Clone)]
+pub struct FailoverClient {
+    endpoints: Arc<Vec<Endpoint>>,
+    config: ProviderConfig,
+}
+
+impl FailoverClient {
+    /// Creates a client from `http(s)://` and `ws(s)://` urls.
+    pub fn connect(urls: &[String], config: ProviderConfig) -> eyre::Result<Self> {
+        let mut transports: Vec<(String, Box<dyn Transport>)> = Vec::new();
+        for url in urls {
+            let transport: Box<dyn Transport> =
+                if url.starts_with("ws://") || url.starts_with("wss://") {
+                    Box::new(Reconnecting::ws(url.clone()))
+                } else {
+                    Box::new(url.parse::<Http>()?)
+                };
+            transports.push((url.clone(), transport));
+        }
+        Self::new(transports, config)
+    }
+
+    pub fn new(
+        transports: Vec<(String, Box<dyn Transport>)>,
+        config: ProviderConfig,
+    ) -> eyre::Result<Self> {
+        if transports.is_empty() {
+            eyre::bail!("no rpc endpoint");
+        }
+        if config.quorum == 0 || config.quorum > transports.len() {
+            eyre::bail!(
+                "quorum {} must be between 1 and the {} endpoints",
+                config.quorum,
+                transports.len()
+            );
+        }
+        Ok(FailoverClient {
+            endpoints: Arc::new(
+                transports
+                    .into_iter()
+                    .map(|(url, transport)| Endpoint {
+                        url,
+                        transport,
+                        score: Mutex::new(MAX_SCORE),
+                    })
+                    .collect(),
+            ),
+            config,
+        })
+    }
+
+    /// Endpoint urls with their current health score.
+    pub fn health(&self) -> Vec<(String, u32)> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| (endpoint.url.clone(), endpoint.score()))
+            .collect()
+    }
+
+    fn ranked(&self) -> Vec<&Endpoint> {
+        let mut endpoints: Vec<&Endpoint> = self.endpoints.iter().collect();
+        endpoints.sort_by_key(|endpoint| std::cmp::Reverse(endpoint.score()));
+        endpoints
+    }
+
+    async fn failover(
+        &self,
+        method: &str,
+        params: &Option<Value>,
+        deadline: Instant,
+    ) -> Result<Value, FailoverError> {
+        let mut last_error = None;
+        for endpoint in self.ranked() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(FailoverError::Timeout);
+            }
+            let timeout = remaining.min(Duration::from_millis(self.config.request_timeout_ms));
+            match endpoint.send(method, params.clone(), timeout).await {
+                Ok(value) => return Ok(value),
+                Err(Attempt::Response(err)) => return Err(FailoverError::JsonRpc(err)),
+                Err(Attempt::Failed(err)) => last_error = Some(err),
+            }
+        }
+        Err(FailoverError::Unavailable(last_error.unwrap_or_default()))
+    }
+
+    async fn quorum(
+        &self,
+        method: &str,
+        params: &Option<Value>,
+        deadline: Instant,
+    ) -> Result<Value, FailoverError> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(FailoverError::Timeout);
+        }
+        let timeout = remaining.min(Duration::from_millis(self.config.request_timeout_ms));
+        let results = join_all(
+            self.endpoints
+                .iter()
+                .map(|endpoint| endpoint.send(method, params.clone(), timeout)),
+        )
+        .await;
+
+        let mut tally: Vec<(Value, usize)> = Vec::new();
+        let mut last_error = None;
+        for result in results {
+            match result {
+                Ok(value) => match tally.iter_mut().find(|(v, _)| *v == value) {
+                    Some((_, count)) => *count += 1,
+                    None => tally.push((value, 1)),
+                },
+                Err(Attempt::Response(err)) => last_error = Some(FailoverError::JsonRpc(err)),
+                Err(Attempt::Failed(err)) => {
+                    if last_error.is_none() {
+                        last_error = Some(FailoverError::Unavailable(err));
+                    }
+                }
+            }
+        }
+        match tally
+            .into_iter()
+            .find(|(_, count)| *count >= self.config.quorum)
+        {
+            Some((value, _)) => Ok(value),
+            None => Err(match last_error {
+                Some(FailoverError::JsonRpc(err)) => FailoverError::JsonRpc(err),
+                _ => FailoverError::NoQuorum,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for FailoverClient {
+    type Error = FailoverError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        // Zero sized params such as `()` are left out of the request, like ethers does.
+        let params = if std::mem::size_of::<T>() == 0 {
+            None
+        } else {
+            Some(serde_json::to_value(params).map_err(FailoverError::SerdeJson)?)
+        };
+        let quorum = self.config.quorum > 1 && QUORUM_METHODS.contains(&method);
+        let deadline = Instant::now() + Duration::from_millis(self.config.deadline_ms);
+
+        let mut backoff = Duration::from_millis(self.config.retry_backoff_ms);
+        let mut attempt = 0;
+        loop {
+            let result = if quorum {
+                self.quorum(method, &params, deadline).await
+            } else {
+                self.failover(method, &params, deadline).await
+            };
+            match result {
+                Ok(value) => {
+                    return serde_json::from_value(value).map_err(FailoverError::SerdeJson)
+                }
+                Err(err @ (FailoverError::JsonRpc(_) | FailoverError::Timeout)) => return Err(err),
+                Err(err) if attempt >= self.config.max_retries => return Err(err),
+                Err(_) => {}
+            }
+            if Instant::now() + backoff >= deadline {
+                return Err(FailoverError::Timeout);
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        providers::{Middleware, MockError, MockProvider},
+        types::U64,
+    };
+
+    /// A transport whose requests never complete.
+    #[derive(Debug)]
+    struct Stalled;
+
+    #[async_trait]
+    impl Transport for Stalled {
+        async fn call(&self, _: &str, _: Option<Value>) -> Result<Value, ProviderError> {
+            futures::future::pending().await
+        }
+    }
+
+    /// A connection that fails all requests once `broken` is set.
+    #[derive(Debug, Clone)]
+    struct Flaky {
+        broken: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Transport for Flaky {
+        async fn call(&self, _: &str, _: Option<Value>) -> Result<Value, ProviderError> {
+            if self.broken.load(std::sync::atomic::Ordering::SeqCst) {
+                Err(ProviderError::CustomError("connection closed".to_string()))
+            } else {
+                Ok(Value::from("0x1"))
+            }
+        }
+    }
+
+    fn config() -> ProviderConfig {
+        ProviderConfig {
+            request_timeout_ms: 50,
+            deadline_ms: 1_000,
+            max_retries: 1,
+            retry_backoff_ms: 10,
+            quorum: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_over_stalled_endpoint() {
+        let mock = MockProvider::new();
+        mock.push(U64::from(4690)).unwrap();
+        let client = FailoverClient::new(
+            vec![
+                ("stalled".to_string(), Box::new(Stalled)),
+                ("mock".to_string(), Box::new(mock)),
+            ],
+            config(),
+        )
+        .unwrap();
+        let provider = Provider::new(client);
+
+        assert_eq!(provider.get_chainid().await.unwrap().as_u64(), 4690);
+        let health = provider.as_ref().health();
+        assert!(health[0].1 < health[1].1);
+    }
+
+    #[tokio::test]
+    async fn requires_quorum_for_reads() {
+        let agreeing = MockProvider::new();
+        agreeing.push(U64::from(1)).unwrap();
+        let other = MockProvider::new();
+        other.push(U64::from(1)).unwrap();
+        let disagreeing = MockProvider::new();
+        disagreeing.push(U64::from(2)).unwrap();
+        let client = FailoverClient::new(
+            vec![
+                ("a".to_string(), Box::new(agreeing)),
+                ("b".to_string(), Box::new(disagreeing)),
+                ("c".to_string(), Box::new(other)),
+            ],
+            ProviderConfig {
+                quorum: 2,
+                ..config()
+            },
+        )
+        .unwrap();
+
+        let value: U64 = client.request("eth_call", ["0x"]).await.unwrap();
+        assert_eq!(value, U64::from(1));
+        assert!(matches!(
+            client.request::<_, U64>("eth_call", ["0x"]).await,
+            Err(FailoverError::NoQuorum)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_unavailable_chain() {
+        let mock = MockProvider::new();
+        let client =
+            FailoverClient::new(vec![("mock".to_string(), Box::new(mock))], config()).unwrap();
+
+        let err = client
+            .request::<_, U64>("eth_chainId", ())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FailoverError::Unavailable(_)));
+        assert!(err
+            .to_string()
+            .contains(&MockError::EmptyResponses.to_string()));
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_transport_errors() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let broken = Arc::new(AtomicBool::new(false));
+        let (counter, first) = (connects.clone(), broken.clone());
+        let endpoint = Reconnecting::new(
+            "ws://node".to_string(),
+            Box::new(move |_| {
+                // the first connection is the one that breaks
+                let broken = match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 => first.clone(),
+                    _ => Arc::new(AtomicBool::new(false)),
+                };
+                Box::pin(async move { Ok(Flaky { broken }) })
+            }),
+        );
+
+        assert!(endpoint.call("eth_chainId", None).await.is_ok());
+        assert!(endpoint.call("eth_chainId", None).await.is_ok());
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        broken.store(true, Ordering::SeqCst);
+        assert!(endpoint.call("eth_chainId", None).await.is_err());
+        assert!(endpoint.call("eth_chainId", None).await.is_ok());
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+}
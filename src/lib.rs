@@ -1,3 +1,4 @@
+pub mod client;
 pub mod contracts;
 pub mod rpc;
 pub mod server;
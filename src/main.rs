@@ -1,10 +1,35 @@
-use std::{env, time::Duration};
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
 
-use ethers::providers::{Http, Provider};
+use ethers::{
+    providers::{Middleware, Provider},
+    types::H256,
+};
 use sqlx::postgres::PgPoolOptions;
+use tracing::error;
 use verifying_email_binder::{
-    server::handler::serve_http,
-    service::{email::send_mails, Context, HttpRpcHandler},
+    contracts::provider::{FailoverClient, ProviderConfig},
+    server::{auth::Authenticator, handler::serve_http},
+    service::{
+        chain::{backfill_chain_id, ChainConfig, ChainRegistry},
+        challenge::{ChallengeGate, HttpCaptchaVerifier},
+        code::CODE_EXPIRY_SECS,
+        commitment::migrate_commitments,
+        email::{
+            send_alerts, send_email_change_notices, send_mails, send_recovery_mails, SmtpConfig,
+        },
+        email_change::EmailChangeConfig,
+        encryption::{rotate_email_keys, EmailCipher},
+        events::EventHub,
+        guardian::{check_parity, enable_local_hashes, HashConfig},
+        indexer::run_indexers,
+        ownership::OwnershipConfig,
+        recovery::RecoveryConfig,
+        relayer::{process_relays, Relayer, RelayerConfig},
+        retention::{run_retention, RetentionConfig},
+        schema::{baseline, check_schema, latest_version, migrate},
+        store::{CodeStore, PgCodeStore, SqliteCodeStore},
+        Context, HttpRpcHandler,
+    },
 };
 
 #[tokio::main]
@@ -17,33 +42,334 @@ async fn main() {
         .await
         .expect("could not connect to database");
 
-    let provider = Provider::<Http>::try_from(env::var("RPC_URL").expect("RPC_URL must be set"))
-        .expect("instance provider error");
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        match env::args().nth(2).as_deref() {
+            Some("baseline") => {
+                let version = env::args()
+                    .nth(3)
+                    .expect("usage: migrate baseline <version>")
+                    .parse()
+                    .expect("baseline version must be a number");
+                let recorded = baseline(&db, version)
+                    .await
+                    .expect("could not baseline database");
+                println!("recorded {} migrations as applied", recorded);
+            }
+            _ => {
+                let version = migrate(&db).await.expect("could not migrate database");
+                println!("schema version {}", version);
+            }
+        }
+        return;
+    }
+    if env::var("AUTO_MIGRATE").is_ok_and(|v| v == "true") {
+        migrate(&db).await.expect("could not migrate database");
+    }
+    // AUTO_MIGRATE brought the schema up to date above, otherwise refuse to run on an old one
+    match check_schema(&db)
+        .await
+        .expect("unsupported database schema")
+    {
+        Some(version) if version < latest_version() => {
+            error!(target: "schema", version, latest = latest_version(), "database schema is behind, run `migrate`");
+            std::process::exit(1);
+        }
+        Some(_) => {}
+        None => {
+            error!(target: "schema", "database has no recorded migrations, run `migrate` or `migrate baseline`");
+            std::process::exit(1);
+        }
+    }
+
+    let email_cipher = env::var("EMAIL_KEYS").ok().map(|keys| {
+        let keys: HashMap<i16, H256> = serde_json::from_str(&keys)
+            .expect("EMAIL_KEYS must map key versions to 32 byte hex keys");
+        let version = env::var("EMAIL_KEY_VERSION")
+            .map(|v| v.parse().expect("EMAIL_KEY_VERSION must be a number"))
+            .unwrap_or_else(|_| *keys.keys().max().expect("EMAIL_KEYS must not be empty"));
+        let index_key = env::var("EMAIL_INDEX_KEY").expect("EMAIL_INDEX_KEY must be set");
+        let index_key =
+            hex::decode(index_key.trim_start_matches("0x")).expect("EMAIL_INDEX_KEY must be hex");
+        EmailCipher::new(
+            keys.into_iter()
+                .map(|(version, key)| (version, key.0))
+                .collect(),
+            version,
+            index_key,
+        )
+        .expect("EMAIL_KEY_VERSION must be one of EMAIL_KEYS")
+    });
+    if env::args().nth(1).as_deref() == Some("rotate-email-keys") {
+        let cipher = email_cipher.expect("EMAIL_KEYS must be set");
+        let rotated = rotate_email_keys(&db, &cipher)
+            .await
+            .expect("could not rotate email keys");
+        println!(
+            "encrypted {} emails with key version {}",
+            rotated,
+            cipher.version()
+        );
+        return;
+    }
+
+    let chain_configs: Vec<ChainConfig> = match env::var("CHAINS") {
+        Ok(chains) => serde_json::from_str(&chains).expect("CHAINS must be a JSON array of chains"),
+        Err(_) => {
+            let rpc_urls: Vec<String> = env::var("RPC_URL")
+                .expect("RPC_URL or CHAINS must be set")
+                .split(',')
+                .map(|url| url.trim().to_string())
+                .collect();
+            let provider = Provider::new(
+                FailoverClient::connect(&rpc_urls, ProviderConfig::default())
+                    .expect("instance provider error"),
+            );
+            vec![ChainConfig {
+                chain_id: provider
+                    .get_chainid()
+                    .await
+                    .expect("could not get chain id")
+                    .as_u64(),
+                rpc_urls,
+                guardian_address: env::var("GUARDIAN_ADDRESS")
+                    .expect("GUARDIAN_ADDRESS must be set"),
+                ..Default::default()
+            }]
+        }
+    };
+    let chains = ChainRegistry::new(
+        chain_configs,
+        env::var("DEFAULT_CHAIN_ID")
+            .ok()
+            .map(|v| v.parse().expect("DEFAULT_CHAIN_ID must be a number")),
+        env::var("SIGNER").ok(),
+    )
+    .expect("invalid chain configuration");
+    backfill_chain_id(&db, chains.get(None).expect("default chain is configured"))
+        .await
+        .expect("could not assign stored codes to the default chain");
+
+    let guardian_hash = HashConfig {
+        local: env::var("LOCAL_GUARDIAN_HASH").is_ok_and(|v| v == "true"),
+        sample_rate: env::var("HASH_SAMPLE_RATE")
+            .map(|v| v.parse().expect("HASH_SAMPLE_RATE must be a number"))
+            .unwrap_or(0.05),
+    };
+    assert!(
+        (0.0..=1.0).contains(&guardian_hash.sample_rate),
+        "HASH_SAMPLE_RATE must be between 0 and 1"
+    );
+
+    if env::args().nth(1).as_deref() == Some("migrate-commitments") {
+        for chain in chains.chains() {
+            let migrated = migrate_commitments(&db, email_cipher.as_ref(), chain, &guardian_hash)
+                .await
+                .expect("could not migrate email commitments");
+            println!(
+                "chain {}: signed {} salted bindings",
+                chain.chain_id, migrated
+            );
+        }
+        return;
+    }
+    if guardian_hash.local {
+        enable_local_hashes(&chains).await;
+        let chains = chains.clone();
+        let interval = env::var("HASH_CHECK_INTERVAL")
+            .map(|v| v.parse().expect("HASH_CHECK_INTERVAL must be a number"))
+            .unwrap_or(600);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                check_parity(&chains).await;
+            }
+        });
+    }
+
+    let ownership = OwnershipConfig {
+        domain: env::var("SIWE_DOMAIN").unwrap_or_else(|_| "localhost:3000".to_string()),
+        uri: env::var("SIWE_URI").unwrap_or_else(|_| "http://localhost:3000".to_string()),
+        required: !env::var("REQUIRE_OWNERSHIP").is_ok_and(|v| v == "false"),
+    };
+
+    let events = EventHub::new();
+    let authenticator = Authenticator::new(
+        db.clone(),
+        env::var("ALLOW_ANONYMOUS").is_ok_and(|v| v == "true"),
+    );
+
+    let challenge = env::var("CHALLENGE_SECRET").ok().map(|secret| {
+        let difficulty = env::var("CHALLENGE_DIFFICULTY")
+            .map(|v| v.parse().expect("CHALLENGE_DIFFICULTY must be a number"))
+            .unwrap_or(16);
+        let max_difficulty = env::var("CHALLENGE_MAX_DIFFICULTY")
+            .map(|v| {
+                v.parse()
+                    .expect("CHALLENGE_MAX_DIFFICULTY must be a number")
+            })
+            .unwrap_or(24);
+        let gate = ChallengeGate::new(secret.into_bytes(), difficulty, max_difficulty);
+        match (env::var("CAPTCHA_VERIFY_URL"), env::var("CAPTCHA_SECRET")) {
+            (Ok(url), Ok(secret)) => {
+                let verifier =
+                    HttpCaptchaVerifier::new(url, secret).expect("could not build captcha client");
+                gate.with_captcha(Arc::new(verifier))
+            }
+            _ => gate,
+        }
+    });
+
+    let relayer = env::var("RELAYER_KEY").ok().map(|key| {
+        let wallet = key.parse().expect("RELAYER_KEY must be a private key");
+        let mut config = RelayerConfig::default();
+        if let Ok(confirmations) = env::var("RELAY_CONFIRMATIONS") {
+            config.confirmations = confirmations
+                .parse()
+                .expect("RELAY_CONFIRMATIONS must be a number");
+        }
+        if let Ok(replace_after) = env::var("RELAY_REPLACE_AFTER") {
+            config.replace_after_secs = replace_after
+                .parse()
+                .expect("RELAY_REPLACE_AFTER must be a number");
+        }
+        Relayer::new(wallet, config)
+    });
+    if let Some(relayer) = relayer.clone() {
+        let db = db.clone();
+        let chains = chains.clone();
+        tokio::spawn(async move {
+            loop {
+                process_relays(&db, &chains, &relayer).await;
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        });
+    }
+
+    if chains.chains().any(|chain| chain.indexer.is_some()) {
+        let db = db.clone();
+        let chains = chains.clone();
+        let interval = env::var("INDEXER_INTERVAL")
+            .map(|v| v.parse().expect("INDEXER_INTERVAL must be a number"))
+            .unwrap_or(15);
+        tokio::spawn(async move {
+            loop {
+                run_indexers(&db, &chains).await;
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        });
+    }
+
+    // alerts and recovery codes link to the page that confirms a freeze, see `freeze_account`
+    let freeze_link = env::var("FREEZE_LINK").expect("FREEZE_LINK must be set");
+    let cancel_link = env::var("CANCEL_CHANGE_LINK").expect("CANCEL_CHANGE_LINK must be set");
+
+    let mut recovery = RecoveryConfig::default();
+    if let Ok(cooldown) = env::var("RECOVERY_COOLDOWN") {
+        recovery.cooldown_secs = cooldown
+            .parse()
+            .expect("RECOVERY_COOLDOWN must be a number");
+    }
+    if let Ok(delay) = env::var("RECOVERY_DELAY") {
+        recovery.delay_secs = delay.parse().expect("RECOVERY_DELAY must be a number");
+    }
+
+    let mut email_change = EmailChangeConfig::default();
+    if let Ok(time_lock) = env::var("EMAIL_CHANGE_TIME_LOCK") {
+        email_change.time_lock_secs = time_lock
+            .parse()
+            .expect("EMAIL_CHANGE_TIME_LOCK must be a number");
+    }
+
+    let code_store = env::var("CODE_STORE").unwrap_or_else(|_| "postgres".to_string());
+    let codes: Arc<dyn CodeStore> = match code_store.as_str() {
+        "postgres" => Arc::new(PgCodeStore::new(db.clone()).with_cipher(email_cipher.clone())),
+        "sqlite" => {
+            let url = env::var("CODE_STORE_URL").expect("CODE_STORE_URL must be set");
+            Arc::new(
+                SqliteCodeStore::connect(&url, db.clone())
+                    .await
+                    .expect("could not open code store")
+                    .with_cipher(email_cipher.clone()),
+            )
+        }
+        other => panic!("CODE_STORE must be postgres or sqlite, not {}", other),
+    };
 
     let context = Context {
         db,
-        provider,
-        guardian_address: env::var("GUARDIAN_ADDRESS").expect("GUARDIAN_ADDRESS must be set"),
-        signer: env::var("SIGNER").expect("SIGNER must be set"),
+        codes: codes.clone(),
+        chains,
+        events: events.clone(),
+        challenge,
+        ownership,
+        guardian_hash,
+        relayer,
+        recovery,
+        email_change,
+        email_cipher,
     };
 
+    let mail_events = events.clone();
+    let mail_recovery = context.recovery.clone();
+    let mail_cipher = context.email_cipher.clone();
     tokio::spawn(async move {
-        let smtp_password = env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set");
-        let smtp_user = env::var("SMTP_USER").expect("SMTP_USER must be set");
-        let smtp_host = env::var("SMTP_HOST").expect("SMTP_HOST must be set");
+        let smtp = SmtpConfig {
+            key: env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set"),
+            from: env::var("SMTP_USER").expect("SMTP_USER must be set"),
+            host: env::var("SMTP_HOST").expect("SMTP_HOST must be set"),
+        };
         let db = PgPoolOptions::new()
             .max_connections(50)
             .connect(&database_url)
             .await
             .expect("could not connect to database");
+        let cipher = mail_cipher.as_ref();
         loop {
-            send_mails(&db, &smtp_password, &smtp_user, &smtp_host).await;
+            send_mails(codes.as_ref(), &mail_events, &smtp).await;
+            if code_store != "postgres" {
+                // change codes stay in Postgres, see `issue_code`
+                let changes = PgCodeStore::new(db.clone()).with_cipher(mail_cipher.clone());
+                send_mails(&changes, &mail_events, &smtp).await;
+            }
+            send_alerts(&db, cipher, &freeze_link, &smtp).await;
+            send_recovery_mails(
+                &db,
+                cipher,
+                &mail_events,
+                &mail_recovery,
+                &freeze_link,
+                &smtp,
+            )
+            .await;
+            send_email_change_notices(&db, cipher, &cancel_link, &smtp).await;
+            mail_events.prune(Duration::from_secs(CODE_EXPIRY_SECS as u64));
             tokio::time::sleep(Duration::from_secs(30)).await;
         }
     });
 
+    let mut retention = RetentionConfig::default();
+    if let Ok(period) = env::var("RETENTION_PERIOD") {
+        retention.retention_secs = period.parse().expect("RETENTION_PERIOD must be a number");
+    }
+    if let Ok(batch_size) = env::var("RETENTION_BATCH_SIZE") {
+        retention.batch_size = batch_size
+            .parse()
+            .expect("RETENTION_BATCH_SIZE must be a number");
+    }
+    let retention_db = context.db.clone();
+    let retention_codes = context.codes.clone();
+    let retention_interval = env::var("RETENTION_INTERVAL")
+        .map(|v| v.parse().expect("RETENTION_INTERVAL must be a number"))
+        .unwrap_or(3600);
+    tokio::spawn(async move {
+        loop {
+            run_retention(&retention_db, retention_codes.as_ref(), &retention).await;
+            tokio::time::sleep(Duration::from_secs(retention_interval)).await;
+        }
+    });
+
     let http = HttpRpcHandler::new(context);
-    serve_http("0.0.0.0:3000".parse().unwrap(), http)
+    serve_http("0.0.0.0:3000".parse().unwrap(), http, events, authenticator)
         .await
         .unwrap();
 }
@@ -2,6 +2,13 @@ use core::fmt;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::borrow::Cow;
 
+/// Server error code for requests without valid client credentials.
+pub const UNAUTHORIZED: i64 = -32001;
+/// Server error code for requests the chain could not serve.
+pub const CHAIN_UNAVAILABLE: i64 = -32002;
+/// Server error code for requests over the client's quota.
+pub const LIMIT_EXCEEDED: i64 = -32005;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorCode {
     ParseError,
@@ -110,6 +117,39 @@ impl RpcError {
         }
     }
 
+    pub fn unauthorized<M>(message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        RpcError {
+            code: ErrorCode::ServerError(UNAUTHORIZED),
+            message: message.into().into(),
+            data: None,
+        }
+    }
+
+    pub fn limit_exceeded<M>(message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        RpcError {
+            code: ErrorCode::ServerError(LIMIT_EXCEEDED),
+            message: message.into().into(),
+            data: None,
+        }
+    }
+
+    pub fn chain_unavailable<M>(message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        RpcError {
+            code: ErrorCode::ServerError(CHAIN_UNAVAILABLE),
+            message: message.into().into(),
+            data: None,
+        }
+    }
+
     pub fn internal_error_with<M>(message: M) -> Self
     where
         M: Into<String>,
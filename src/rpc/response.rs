@@ -57,6 +57,18 @@ impl RpcResponse {
     pub fn invalid_request(id: Id) -> Self {
         Self::new(id, RpcError::invalid_request())
     }
+
+    pub fn id(&self) -> Option<&Id> {
+        self.id.as_ref()
+    }
+
+    pub fn result(&self) -> &ResponseResult {
+        &self.result
+    }
+
+    pub fn into_result(self) -> ResponseResult {
+        self.result
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{FromRequest, Query, RequestParts},
+    http::StatusCode,
+    Json,
+};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tracing::{error, warn};
+
+use crate::rpc::{error::RpcError, response::Response};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const API_KEY_QUERY: &str = "api_key";
+
+/// A registered API client. Empty `allowed_origins` or `allowed_methods` allow everything.
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct ApiClient {
+    pub id: i32,
+    pub name: String,
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub quota_per_minute: i32,
+    pub quota_per_day: i32,
+}
+
+impl ApiClient {
+    /// Clients restricted to some origins must send one of them.
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        self.allowed_origins.is_empty()
+            || origin.is_some_and(|origin| self.allowed_origins.iter().any(|o| o == origin))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientIdentity {
+    Anonymous,
+    Client(ApiClient),
+}
+
+impl ClientIdentity {
+    pub fn allows_method(&self, method: &str) -> bool {
+        match self {
+            ClientIdentity::Anonymous => true,
+            ClientIdentity::Client(client) => {
+                client.allowed_methods.is_empty()
+                    || client.allowed_methods.iter().any(|m| m == method)
+            }
+        }
+    }
+}
+
+/// Resolves API keys to clients. Keys are stored as hex encoded sha256 digests.
+#[derive(Clone)]
+pub struct Authenticator {
+    db: Option<PgPool>,
+    allow_anonymous: bool,
+}
+
+impl Authenticator {
+    pub fn new(db: PgPool, allow_anonymous: bool) -> Self {
+        Authenticator {
+            db: Some(db),
+            allow_anonymous,
+        }
+    }
+
+    /// Accepts every request as anonymous without looking up keys.
+    pub fn anonymous() -> Self {
+        Authenticator {
+            db: None,
+            allow_anonymous: true,
+        }
+    }
+
+    pub fn hash_key(key: &str) -> String {
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+
+    pub async fn authenticate(
+        &self,
+        key: Option<&str>,
+        origin: Option<&str>,
+    ) -> Result<ClientIdentity, RpcError> {
+        let (key, db) = match (key, &self.db) {
+            (Some(key), Some(db)) => (key, db),
+            (None, _) | (Some(_), None) if self.allow_anonymous => {
+                return Ok(ClientIdentity::Anonymous)
+            }
+            _ => return Err(RpcError::unauthorized("missing api key")),
+        };
+
+        let client = sqlx::query_as::<_, ApiClient>(
+            "select id, name, allowed_origins, allowed_methods, quota_per_minute, quota_per_day from api_client where key_hash = $1 and enabled",
+        )
+        .bind(Self::hash_key(key))
+        .fetch_optional(db)
+        .await
+        .map_err(|err| {
+            error!(target: "auth", ?err, "query api client error");
+            RpcError::internal_error()
+        })?
+        .ok_or_else(|| RpcError::unauthorized("invalid api key"))?;
+
+        if !client.allows_origin(origin) {
+            warn!(target: "auth", client = ?client.name, ?origin, "origin not allowed");
+            return Err(RpcError::unauthorized("origin not allowed"));
+        }
+        Ok(ClientIdentity::Client(client))
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Send> FromRequest<B> for ClientIdentity {
+    type Rejection = (StatusCode, Json<Response>);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        // a router without an authenticator must not let every request through
+        let authenticator = req
+            .extensions()
+            .get::<Authenticator>()
+            .cloned()
+            .ok_or_else(|| {
+                error!(target: "auth", "no authenticator installed");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(Response::error(RpcError::internal_error())),
+                )
+            })?;
+
+        let header_key = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let key = match header_key {
+            Some(key) => Some(key),
+            None => Query::<HashMap<String, String>>::from_request(req)
+                .await
+                .ok()
+                .and_then(|Query(mut query)| query.remove(API_KEY_QUERY)),
+        };
+        let origin = req
+            .headers()
+            .get("origin")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        authenticator
+            .authenticate(key.as_deref(), origin.as_deref())
+            .await
+            .map_err(|err| (StatusCode::UNAUTHORIZED, Json(Response::error(err))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_allowed_methods() {
+        let client = ApiClient {
+            id: 1,
+            name: "test".to_string(),
+            allowed_origins: vec![],
+            allowed_methods: vec!["get_status".to_string()],
+            quota_per_minute: 10,
+            quota_per_day: 100,
+        };
+        assert!(ClientIdentity::Client(client.clone()).allows_method("get_status"));
+        assert!(!ClientIdentity::Client(client.clone()).allows_method("send_code"));
+        assert!(client.allows_origin(None));
+
+        let browser = ApiClient {
+            allowed_origins: vec!["https://wallet.example.com".to_string()],
+            ..client
+        };
+        assert!(browser.allows_origin(Some("https://wallet.example.com")));
+        assert!(!browser.allows_origin(Some("https://evil.example.com")));
+        assert!(!browser.allows_origin(None));
+        assert!(ClientIdentity::Anonymous.allows_method("send_code"));
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_key_without_anonymous_tier() {
+        let authenticator = Authenticator {
+            db: None,
+            allow_anonymous: false,
+        };
+        assert!(authenticator.authenticate(None, None).await.is_err());
+        assert_eq!(
+            Authenticator::anonymous().authenticate(None, None).await,
+            Ok(ClientIdentity::Anonymous)
+        );
+        assert_eq!(
+            Authenticator::hash_key("key"),
+            "2c70e12b7a0646f92279f427c7b38e7334d8e5389cff167a1dc30e73f826b683"
+        );
+    }
+}
@@ -2,7 +2,7 @@ use std::{fmt, net::SocketAddr};
 
 use axum::{
     extract::{rejection::JsonRejection, Extension},
-    routing::{post, IntoMakeService},
+    routing::{get, post, IntoMakeService},
     Json, Router, Server,
 };
 use futures::{future, FutureExt};
@@ -14,10 +14,17 @@ use tower_http::{
 };
 use tracing::{error, trace, warn};
 
-use crate::rpc::{
-    error::RpcError,
-    request::{Request, RpcCall, RpcMethodCall},
-    response::{Response, ResponseResult, RpcResponse},
+use super::{
+    auth::{Authenticator, ClientIdentity},
+    sse::handle_events,
+};
+use crate::{
+    rpc::{
+        error::RpcError,
+        request::{Request, RpcCall, RpcMethodCall},
+        response::{Response, ResponseResult, RpcResponse},
+    },
+    service::events::EventHub,
 };
 
 pub type RpcServer = Server<AddrIncoming, IntoMakeService<Router>>;
@@ -26,15 +33,20 @@ pub type RpcServer = Server<AddrIncoming, IntoMakeService<Router>>;
 pub trait RpcHandler: Clone + Send + Sync + 'static {
     type Request: DeserializeOwned + Send + Sync + fmt::Debug;
 
-    async fn on_request(&self, request: Self::Request) -> ResponseResult;
+    async fn on_request(&self, request: Self::Request, client: &ClientIdentity) -> ResponseResult;
 
-    async fn on_call(&self, call: RpcMethodCall) -> RpcResponse {
+    async fn on_call(&self, call: RpcMethodCall, client: &ClientIdentity) -> RpcResponse {
         trace!(target: "rpc", id = ?call.id, method = ?call.method, "received method call");
 
         let RpcMethodCall {
             method, params, id, ..
         } = call;
 
+        if !client.allows_method(&method) {
+            warn!(target: "rpc", ?method, "method not allowed for client");
+            return RpcResponse::new(id, RpcError::unauthorized("method not allowed"));
+        }
+
         let params: serde_json::Value = params.into();
         let call = serde_json::json!({
             "method": &method,
@@ -43,7 +55,7 @@ pub trait RpcHandler: Clone + Send + Sync + 'static {
 
         match serde_json::from_value::<Self::Request>(call) {
             Ok(req) => {
-                let result = self.on_request(req).await;
+                let result = self.on_request(req, client).await;
                 RpcResponse::new(id, result)
             }
             Err(err) => {
@@ -61,6 +73,7 @@ pub trait RpcHandler: Clone + Send + Sync + 'static {
 }
 
 pub async fn handle<Handler: RpcHandler>(
+    client: ClientIdentity,
     request: Result<Json<Request>, JsonRejection>,
     Extension(handler): Extension<Handler>,
 ) -> Json<Response> {
@@ -69,7 +82,7 @@ pub async fn handle<Handler: RpcHandler>(
             warn!(target: "rpc", ?err, "invalid request");
             Response::error(RpcError::invalid_request()).into()
         }
-        Ok(req) => handle_request(req.0, handler)
+        Ok(req) => handle_request(req.0, handler, client)
             .await
             .unwrap_or_else(|| Response::error(RpcError::invalid_request()))
             .into(),
@@ -79,6 +92,7 @@ pub async fn handle<Handler: RpcHandler>(
 pub async fn handle_request<Handler: RpcHandler>(
     req: Request,
     handler: Handler,
+    client: ClientIdentity,
 ) -> Option<Response> {
     fn responses_as_batch(outs: Vec<Option<RpcResponse>>) -> Option<Response> {
         let batch: Vec<_> = outs.into_iter().flatten().collect();
@@ -86,12 +100,14 @@ pub async fn handle_request<Handler: RpcHandler>(
     }
 
     match req {
-        Request::Single(call) => handle_call(call, handler).await.map(Response::Single),
+        Request::Single(call) => handle_call(call, handler, &client)
+            .await
+            .map(Response::Single),
         Request::Batch(calls) => {
             future::join_all(
                 calls
                     .into_iter()
-                    .map(move |call| handle_call(call, handler.clone())),
+                    .map(|call| handle_call(call, handler.clone(), &client)),
             )
             .map(responses_as_batch)
             .await
@@ -99,11 +115,15 @@ pub async fn handle_request<Handler: RpcHandler>(
     }
 }
 
-async fn handle_call<Handler: RpcHandler>(call: RpcCall, handler: Handler) -> Option<RpcResponse> {
+async fn handle_call<Handler: RpcHandler>(
+    call: RpcCall,
+    handler: Handler,
+    client: &ClientIdentity,
+) -> Option<RpcResponse> {
     match call {
         RpcCall::MethodCall(call) => {
             trace!(target: "rpc", id = ?call.id , method = ?call.method,  "handling call");
-            Some(handler.on_call(call).await)
+            Some(handler.on_call(call, client).await)
         }
         RpcCall::Notification(notification) => {
             trace!(target: "rpc", method = ?notification.method, "received rpc notification");
@@ -116,13 +136,21 @@ async fn handle_call<Handler: RpcHandler>(call: RpcCall, handler: Handler) -> Op
     }
 }
 
-pub fn serve_http<Http>(addr: SocketAddr, http: Http) -> RpcServer
+pub fn serve_http<Http>(
+    addr: SocketAddr,
+    http: Http,
+    events: EventHub,
+    authenticator: Authenticator,
+) -> RpcServer
 where
     Http: RpcHandler,
 {
     let svc = Router::new()
         .route("/", post(handle::<Http>))
+        .route("/events/:token", get(handle_events))
         .layer(Extension(http))
+        .layer(Extension(events))
+        .layer(Extension(authenticator))
         .layer(TraceLayer::new_for_http())
         .layer(
             CorsLayer::new()
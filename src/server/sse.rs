@@ -0,0 +1,73 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::{Extension, Path},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{future, stream, Stream, StreamExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{trace, warn};
+
+use super::auth::ClientIdentity;
+use crate::service::events::{DeliveryEvent, EventHub};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(3);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+fn to_sse_event(event: &DeliveryEvent) -> Event {
+    Event::default()
+        .id(event.id.to_string())
+        .event("status")
+        .retry(RETRY_INTERVAL)
+        .json_data(event)
+        .unwrap_or_else(|_| Event::default().comment("failed to serialize event"))
+}
+
+fn live_events(
+    receiver: Option<broadcast::Receiver<DeliveryEvent>>,
+) -> impl Stream<Item = DeliveryEvent> {
+    stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let next = (!event.state.is_terminal()).then_some(receiver);
+                    return Some((event, next));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(target: "sse", skipped, "event subscriber lagged");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Streams the delivery events of a request id, to the same API keys as the RPC methods.
+pub async fn handle_events(
+    _client: ClientIdentity,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+    Extension(hub): Extension<EventHub>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    trace!(target: "sse", ?last_event_id, "subscribing to delivery events");
+
+    let subscription = hub
+        .subscribe(&token, last_event_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let replayed = subscription.backlog.last().map_or(0, |event| event.id);
+    let seen = replayed.max(last_event_id.unwrap_or_default());
+    let live =
+        live_events(subscription.receiver).filter(move |event| future::ready(event.id > seen));
+
+    let events = stream::iter(subscription.backlog)
+        .chain(live)
+        .map(|event| Ok(to_sse_event(&event)));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::new().interval(KEEP_ALIVE_INTERVAL)))
+}
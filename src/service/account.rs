@@ -0,0 +1,75 @@
+use ethers::{
+    types::{Address, U256},
+    utils::to_checksum,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    contracts::account::is_contract,
+    service::{
+        chain::Chain,
+        error::{Result, ServiceError},
+        Context,
+    },
+};
+
+/// An ERC-4337 account identified by its factory, owner and salt, deployed or not.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterfactualAccount {
+    pub factory: Address,
+    pub owner: Address,
+    pub salt: U256,
+}
+
+/// Result of `get_account_address`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountAddress {
+    pub address: String,
+    pub deployed: bool,
+}
+
+/// Derives the CREATE2 address of `account` with one of the chain's factories.
+pub fn counterfactual_address(chain: &Chain, account: &CounterfactualAccount) -> Result<Address> {
+    chain
+        .account_factory(account.factory)
+        .map(|factory| factory.account_address(account.owner, account.salt))
+        .ok_or_else(|| ServiceError::InvalidRequest("unsupported account factory".to_string()))
+}
+
+/// Checks that `address` is the account `counterfactual` describes.
+pub fn check_counterfactual(
+    chain: &Chain,
+    address: Address,
+    counterfactual: &CounterfactualAccount,
+) -> Result<()> {
+    if counterfactual_address(chain, counterfactual)? != address {
+        return Err(ServiceError::InvalidRequest(
+            "account does not match its factory, owner and salt".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the account has code, using `eth_getCode`.
+pub async fn is_deployed(chain: &Chain, address: Address) -> Result<bool> {
+    is_contract(chain.provider.clone(), address)
+        .await
+        .map_err(|err| {
+            error!(target: "account", ?err, chain_id = chain.chain_id, "query account code error");
+            ServiceError::ChainError(err.to_string())
+        })
+}
+
+pub async fn get_account_address(
+    context: &Context,
+    account: CounterfactualAccount,
+    chain_id: Option<u64>,
+) -> Result<AccountAddress> {
+    let chain = context.chains.get(chain_id)?;
+    let address = counterfactual_address(chain, &account)?;
+    Ok(AccountAddress {
+        address: to_checksum(&address, None),
+        deployed: is_deployed(chain, address).await?,
+    })
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Utc};
+use ethers::types::Address;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::info;
+
+use crate::service::{
+    chain::Chain,
+    code::CODE_QUEUED,
+    encryption::{reveal_email, EmailCipher},
+    error::{Result, ServiceError},
+    recovery::{RECOVERY_CANCELLED, RECOVERY_PENDING},
+    Context,
+};
+
+pub const ALERT_QUEUED: i16 = 0;
+pub const ALERT_SENT: i16 = 1;
+/// Seconds a mailer holds an alert before another one may send it.
+pub const ALERT_CLAIM_LEASE_SECS: i64 = 300;
+
+/// Deletes a batch of alerts created more than `$1` seconds ago, at most `$2` rows. Freezes
+/// keep their row, without the alert.
+pub(crate) const PURGE_ALERTS: &str = r#"DELETE FROM security_alert WHERE id IN (
+    SELECT id FROM security_alert WHERE created_at < now() - make_interval(secs => $1)
+    LIMIT $2 FOR UPDATE SKIP LOCKED)"#;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SecurityAlert {
+    pub id: i32,
+    pub chain_id: i64,
+    pub account: String,
+    pub email: String,
+    pub kind: String,
+    pub freeze_token: String,
+    /// The email sealed by `EmailCipher`, `email` is then empty until revealed.
+    #[sqlx(default)]
+    pub email_ciphertext: Option<String>,
+    #[sqlx(default)]
+    pub email_key_version: Option<i16>,
+}
+
+impl SecurityAlert {
+    /// Decrypts the email of an alert copied from an encrypted binding.
+    pub(crate) fn reveal(self, cipher: Option<&EmailCipher>) -> Result<Self> {
+        let email = reveal_email(
+            cipher,
+            &self.email,
+            self.email_ciphertext.as_deref(),
+            self.email_key_version,
+        )?;
+        Ok(SecurityAlert { email, ..self })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountFreeze {
+    pub account: String,
+    pub chain_id: u64,
+    pub frozen_at: DateTime<Utc>,
+}
+
+/// Queues an alert for `event_id` to the email whose binding hashed to `email_hash`.
+pub async fn queue_alert(
+    tx: &mut Transaction<'_, Postgres>,
+    chain: &Chain,
+    event_id: i32,
+    kind: &str,
+    account: Address,
+    email_hash: &str,
+) -> Result<()> {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    let queued = sqlx::query(
+        r#"INSERT INTO security_alert(guardian_event_id, chain_id, account, email, kind, freeze_token, email_ciphertext, email_key_version, email_index)
+        SELECT $1, $2, $3, b.email, $4, $5, b.email_ciphertext, b.email_key_version, b.email_index FROM email_binding b JOIN binding_signature s ON s.id = b.signature_id
+        WHERE b.chain_id = $2 AND s.guardian_address = $7 AND b.account = $3 AND b.email_hash = $6 ORDER BY b.id DESC LIMIT 1
+        ON CONFLICT DO NOTHING"#,
+    )
+    .bind(event_id)
+    .bind(chain.chain_id as i64)
+    .bind(format!("{:?}", account))
+    .bind(kind)
+    .bind(hex::encode(token))
+    .bind(email_hash)
+    .bind(&chain.guardian_address)
+    .execute(&mut **tx)
+    .await?;
+    if queued.rows_affected() == 0 {
+        info!(target: "alert", chain_id = chain.chain_id, ?account, kind, "no known email to alert");
+    }
+    Ok(())
+}
+
+/// Subject and body of the mail sent for an alert, `freeze_link` ending with its token.
+pub fn alert_message(alert: &SecurityAlert, freeze_link: &str) -> (String, String) {
+    let (subject, what) = match alert.kind.as_str() {
+        "email_bound" => (
+            "ioPay AA Wallet Security Alert - Email Bound",
+            "this email address was bound as the recovery email of",
+        ),
+        "email_removed" => (
+            "ioPay AA Wallet Security Alert - Email Removed",
+            "this email address was removed as the recovery email of",
+        ),
+        _ => (
+            "ioPay AA Wallet Security Alert - Recovery Started",
+            "a recovery was started for",
+        ),
+    };
+    let body = format!("Dear User,
+
+We noticed that {} your ioPay AA Wallet {} on chain {}.
+
+If this was you, no action is needed.
+
+If this wasn't you, open the link below right away. It stops us from signing any further binding for this wallet until our support team has reviewed it:
+{}{}
+
+Best Regards,
+ioPay Team", what, alert.account, alert.chain_id, freeze_link, alert.freeze_token);
+    (subject.to_string(), body)
+}
+
+/// Freezes the account an alert or a recovery code was sent for, through its "this wasn't
+/// me" token. Pending recoveries of the account are cancelled.
+pub async fn freeze_account(context: &Context, token: String) -> Result<AccountFreeze> {
+    let alert: Option<(Option<i32>, i64, String)> = sqlx::query_as(
+        r#"select id, chain_id, account from security_alert where freeze_token = $1 and status = $2
+        union all select null, r.chain_id, r.account from recovery_request r join bind_code c on c.id = r.bind_code_id where r.freeze_token = $1 and c.status <> $3
+        limit 1"#,
+    )
+    .bind(&token)
+    .bind(ALERT_SENT)
+    .bind(CODE_QUEUED)
+    .fetch_optional(&context.db)
+    .await?;
+    let (alert_id, chain_id, account) =
+        alert.ok_or_else(|| ServiceError::InvalidRequest("invalid freeze token".to_string()))?;
+    let _ = sqlx::query(
+        r#"Update recovery_request set status = $1, updated_at = now() where chain_id = $2 and account = $3 and status = $4"#,
+    )
+    .bind(RECOVERY_CANCELLED)
+    .bind(chain_id)
+    .bind(&account)
+    .bind(RECOVERY_PENDING)
+    .execute(&context.db)
+    .await?;
+
+    let (frozen_at,): (DateTime<Utc>,) = sqlx::query_as(
+        r#"INSERT INTO account_freeze(chain_id, account, alert_id) VALUES ($1, $2, $3)
+        ON CONFLICT (chain_id, account) DO UPDATE SET chain_id = excluded.chain_id RETURNING frozen_at"#,
+    )
+    .bind(chain_id)
+    .bind(&account)
+    .bind(alert_id)
+    .fetch_one(&context.db)
+    .await?;
+    info!(target: "alert", chain_id, account, "account frozen");
+    Ok(AccountFreeze {
+        account,
+        chain_id: chain_id as u64,
+        frozen_at,
+    })
+}
+
+/// Fails when the owner of a bound email froze `account` on `chain`.
+pub async fn ensure_not_frozen(db: &PgPool, chain: &Chain, account: Address) -> Result<()> {
+    let (frozen,): (bool,) = sqlx::query_as(
+        "select exists(select 1 from account_freeze where chain_id = $1 and account = $2)",
+    )
+    .bind(chain.chain_id as i64)
+    .bind(format!("{:?}", account))
+    .fetch_one(db)
+    .await?;
+    if frozen {
+        return Err(ServiceError::InvalidRequest(
+            "account is frozen, contact support".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_freeze_token_in_alert() {
+        let alert = SecurityAlert {
+            id: 1,
+            chain_id: 4690,
+            account: "0x8803daf0ab9bad65a56f4d9aeca56085491c299a".to_string(),
+            email: "test@test.com".to_string(),
+            kind: "recovery_started".to_string(),
+            freeze_token: "ab".repeat(32),
+            email_ciphertext: None,
+            email_key_version: None,
+        };
+        let (subject, body) = alert_message(&alert, "https://localhost/freeze?token=");
+
+        assert!(subject.contains("Recovery Started"));
+        assert!(body.contains(&alert.account));
+        assert!(body.contains(&format!(
+            "https://localhost/freeze?token={}",
+            "ab".repeat(32)
+        )));
+    }
+}
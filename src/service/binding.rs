@@ -0,0 +1,317 @@
+use chrono::{DateTime, Utc};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+
+use crate::service::{
+    commitment::normalize_email,
+    encryption::{reveal_email, EmailCipher, StoredEmail},
+    error::Result,
+};
+
+/// An email the service signed a binding of, kept apart from the transient codes.
+///
+/// Signing the same email hash again refreshes the binding. A binding of another hash
+/// supersedes it once the guardian holds that hash, see [`record_binding`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct EmailBinding {
+    pub id: i32,
+    pub chain_id: i64,
+    pub account: String,
+    pub email: String,
+    pub email_hash: String,
+    pub signer: String,
+    pub signature_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// The email sealed by `EmailCipher`, `email` is then empty until revealed.
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub email_ciphertext: Option<String>,
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub email_key_version: Option<i16>,
+}
+
+const BINDING_COLUMNS: &str = "id, chain_id, account, email, email_hash, signer, signature_id, created_at, updated_at, revoked_at, email_ciphertext, email_key_version";
+
+/// Records the binding signed as `signature_id` in the transaction that stored the signature.
+/// The email is stored normalized, sealed when `cipher` is set.
+///
+/// With `supersede` the other bindings of `account` are revoked right away. Otherwise they stay
+/// active until the indexer sees the new hash on-chain, see [`supersede_bindings`].
+#[allow(clippy::too_many_arguments)]
+pub async fn record_binding(
+    conn: &mut PgConnection,
+    cipher: Option<&EmailCipher>,
+    chain_id: i64,
+    account: &str,
+    email: &str,
+    email_hash: &str,
+    signer: &str,
+    signature_id: i32,
+    supersede: bool,
+) -> Result<EmailBinding> {
+    let account = account.to_lowercase();
+    let email = normalize_email(email);
+    let stored = StoredEmail::seal(cipher, &email)?;
+    let binding = sqlx::query_as::<_, EmailBinding>(&format!(
+        r#"INSERT INTO email_binding(chain_id, account, email, email_hash, signer, signature_id, email_ciphertext, email_key_version, email_index) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT (chain_id, account, email_hash) WHERE revoked_at IS NULL DO UPDATE SET email = excluded.email, signer = excluded.signer, signature_id = excluded.signature_id, email_ciphertext = excluded.email_ciphertext, email_key_version = excluded.email_key_version, email_index = excluded.email_index, updated_at = now()
+        RETURNING {}"#,
+        BINDING_COLUMNS
+    ))
+    .bind(chain_id)
+    .bind(&account)
+    .bind(&stored.email)
+    .bind(email_hash)
+    .bind(signer)
+    .bind(signature_id)
+    .bind(&stored.ciphertext)
+    .bind(stored.key_version)
+    .bind(&stored.index)
+    .fetch_one(&mut *conn)
+    .await?;
+    if supersede {
+        supersede_bindings(conn, chain_id, &account, email_hash).await?;
+    }
+    Ok(EmailBinding { email, ..binding })
+}
+
+/// Revokes the bindings of `account` other than the active one of `email_hash`, if there is
+/// one. Called by the indexer when the guardian binds `email_hash`.
+pub async fn supersede_bindings(
+    conn: &mut PgConnection,
+    chain_id: i64,
+    account: &str,
+    email_hash: &str,
+) -> Result<()> {
+    let _ = sqlx::query(
+        r#"Update email_binding set revoked_at = now(), updated_at = now() where chain_id = $1 and account = $2 and email_hash <> $3 and revoked_at is null
+        and exists (select 1 from email_binding where chain_id = $1 and account = $2 and email_hash = $3 and revoked_at is null)"#,
+    )
+    .bind(chain_id)
+    .bind(account.to_lowercase())
+    .bind(email_hash)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Decrypts the emails of `bindings` stored encrypted.
+fn reveal(
+    cipher: Option<&EmailCipher>,
+    mut bindings: Vec<EmailBinding>,
+) -> Result<Vec<EmailBinding>> {
+    for binding in &mut bindings {
+        binding.email = reveal_email(
+            cipher,
+            &binding.email,
+            binding.email_ciphertext.as_deref(),
+            binding.email_key_version,
+        )?;
+    }
+    Ok(bindings)
+}
+
+/// The bindings of `account` on `chain_id`, the active ones first and newest first.
+pub async fn bindings_by_account(
+    db: &PgPool,
+    cipher: Option<&EmailCipher>,
+    chain_id: i64,
+    account: Address,
+    include_revoked: bool,
+) -> Result<Vec<EmailBinding>> {
+    let bindings = sqlx::query_as::<_, EmailBinding>(&format!(
+        "select {} from email_binding where chain_id = $1 and account = $2 and ($3 or revoked_at is null) order by revoked_at desc nulls first, id desc",
+        BINDING_COLUMNS
+    ))
+    .bind(chain_id)
+    .bind(format!("{:?}", account))
+    .bind(include_revoked)
+    .fetch_all(db)
+    .await?;
+    reveal(cipher, bindings)
+}
+
+/// The active bindings of `email`, the newest one of each chain and account, found by blind
+/// index once emails are encrypted.
+pub async fn bindings_by_email(
+    db: &PgPool,
+    cipher: Option<&EmailCipher>,
+    email: &str,
+) -> Result<Vec<EmailBinding>> {
+    let bindings = sqlx::query_as::<_, EmailBinding>(&format!(
+        "select {} from (select distinct on (chain_id, account) {} from email_binding where (email_index = $1 or (email_index is null and email = $2)) and revoked_at is null order by chain_id, account, id desc) b order by id desc",
+        BINDING_COLUMNS, BINDING_COLUMNS
+    ))
+    .bind(cipher.map(|cipher| cipher.blind_index(email)))
+    .bind(normalize_email(email))
+    .fetch_all(db)
+    .await?;
+    reveal(cipher, bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::testing::{self, TestDb};
+
+    /// A signature row for bindings to reference.
+    async fn signature(db: &PgPool, account: Address) -> i32 {
+        let (code_id,): (i32,) = sqlx::query_as(
+            "insert into bind_code(account, email, code, status) values ($1, 'a@test.com', '123456', 2) returning id",
+        )
+        .bind(format!("{:?}", account))
+        .fetch_one(db)
+        .await
+        .unwrap();
+        sqlx::query_scalar(
+            "insert into binding_signature(bind_code_id, account, email_hash, signer, signature) values ($1, $2, '0x', '0x', '0x') returning id",
+        )
+        .bind(code_id)
+        .bind(format!("{:?}", account))
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn record(
+        db: &PgPool,
+        account: Address,
+        email: &str,
+        email_hash: &str,
+        supersede: bool,
+    ) -> EmailBinding {
+        let signature_id = signature(db, account).await;
+        let mut conn = db.acquire().await.unwrap();
+        record_binding(
+            &mut conn,
+            None,
+            1,
+            &format!("{:?}", account),
+            email,
+            email_hash,
+            "0x0000000000000000000000000000000000000001",
+            signature_id,
+            supersede,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn hash(digit: char) -> String {
+        format!("0x{}", digit.to_string().repeat(64))
+    }
+
+    async fn active(db: &PgPool, account: Address) -> Vec<String> {
+        bindings_by_account(db, None, 1, account, false)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|binding| binding.email_hash)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_refreshes_and_revokes_bindings() {
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        let db = &test_db.pool;
+        let account = Address::repeat_byte(1);
+        let other = Address::repeat_byte(2);
+
+        let first = record(db, account, " A@Test.com", &hash('1'), true).await;
+        assert_eq!(first.email, "a@test.com");
+        // signing the same hash again refreshes the binding
+        let refreshed = record(db, account, "a@test.com", &hash('1'), true).await;
+        assert_eq!(refreshed.id, first.id);
+        assert!(refreshed.signature_id > first.signature_id);
+
+        // another email supersedes it
+        record(db, account, "b@test.com", &hash('2'), true).await;
+        assert_eq!(active(db, account).await, vec![hash('2')]);
+        let all = bindings_by_account(db, None, 1, account, true)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 2);
+        assert!(all[1].revoked_at.is_some());
+
+        // a new hash that is not yet on-chain leaves the current one active
+        record(db, account, "b@test.com", &hash('3'), false).await;
+        assert_eq!(active(db, account).await, vec![hash('3'), hash('2')]);
+        let by_email = bindings_by_email(db, None, "B@test.com").await.unwrap();
+        assert_eq!(by_email.len(), 1);
+        assert_eq!(by_email[0].email_hash, hash('3'));
+
+        // the guardian binding it supersedes the rest
+        let mut conn = db.acquire().await.unwrap();
+        supersede_bindings(&mut conn, 1, &format!("{:?}", account), &hash('3'))
+            .await
+            .unwrap();
+        // hashes without an active binding leave the bindings alone
+        supersede_bindings(&mut conn, 1, &format!("{:?}", account), &hash('9'))
+            .await
+            .unwrap();
+        drop(conn);
+        assert_eq!(active(db, account).await, vec![hash('3')]);
+
+        record(db, other, "b@test.com", &hash('3'), true).await;
+        assert_eq!(
+            bindings_by_email(db, None, "b@test.com")
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(bindings_by_email(db, None, "a@test.com")
+            .await
+            .unwrap()
+            .is_empty());
+        test_db.drop().await;
+    }
+
+    #[tokio::test]
+    async fn finds_sealed_bindings_by_blind_index() {
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        let db = &test_db.pool;
+        let cipher = testing::cipher();
+        let account = Address::repeat_byte(1);
+        let signature_id = signature(db, account).await;
+        let mut conn = db.acquire().await.unwrap();
+        let binding = record_binding(
+            &mut conn,
+            Some(&cipher),
+            1,
+            &format!("{:?}", account),
+            "A@test.com",
+            &hash('1'),
+            "0x0000000000000000000000000000000000000001",
+            signature_id,
+            true,
+        )
+        .await
+        .unwrap();
+        drop(conn);
+        assert_eq!(binding.email, "a@test.com");
+
+        let (stored,): (String,) = sqlx::query_as("select email from email_binding")
+            .fetch_one(db)
+            .await
+            .unwrap();
+        assert_eq!(stored, "");
+        let by_email = bindings_by_email(db, Some(&cipher), " a@TEST.com")
+            .await
+            .unwrap();
+        assert_eq!(by_email.len(), 1);
+        assert_eq!(by_email[0].email, "a@test.com");
+        assert!(bindings_by_account(db, None, 1, account, false)
+            .await
+            .is_err());
+        test_db.drop().await;
+    }
+}
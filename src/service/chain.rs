@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use ethers::{providers::Provider, types::Address};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::{
+    contracts::{
+        account::AccountFactory,
+        entry_point::DEFAULT_ENTRY_POINT,
+        provider::{ChainProvider, FailoverClient, ProviderConfig},
+    },
+    service::commitment::EmailCommitment,
+    service::error::{Result, ServiceError},
+    service::indexer::IndexerConfig,
+    service::relayer::{Relay, RelayConfig},
+};
+
+/// A chain entry of the `CHAINS` configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ChainConfig {
+    pub chain_id: u64,
+    pub rpc_urls: Vec<String>,
+    pub guardian_address: String,
+    /// Overrides the default signer for this chain.
+    #[serde(default)]
+    pub signer: Option<String>,
+    /// Timeouts, retries and quorum of the `rpc_urls`.
+    #[serde(default)]
+    pub provider: ProviderConfig,
+    /// Factories of the counterfactual accounts accepted on this chain.
+    #[serde(default)]
+    pub account_factories: Vec<AccountFactory>,
+    /// ERC-4337 EntryPoint of the chain, the v0.6 one by default.
+    #[serde(default)]
+    pub entry_point: Option<Address>,
+    /// Enables relaying bindings on this chain.
+    #[serde(default)]
+    pub relay: Option<RelayConfig>,
+    /// Enables indexing the guardian's events on this chain.
+    #[serde(default)]
+    pub indexer: Option<IndexerConfig>,
+    /// How email hashes are derived, `keccak256(email)` by default.
+    #[serde(default)]
+    pub email_commitment: EmailCommitment,
+}
+
+#[derive(Clone, Debug)]
+pub struct Chain {
+    pub chain_id: u64,
+    pub provider: ChainProvider,
+    pub guardian: Address,
+    pub guardian_address: String,
+    pub signer: String,
+    pub account_factories: Vec<AccountFactory>,
+    pub entry_point: Address,
+    pub relay: Option<Relay>,
+    pub indexer: Option<IndexerConfig>,
+    pub email_commitment: EmailCommitment,
+    /// Whether binding hashes are derived locally instead of calling the guardian.
+    local_hash: Arc<AtomicBool>,
+}
+
+impl Chain {
+    pub fn account_factory(&self, address: Address) -> Option<&AccountFactory> {
+        self.account_factories
+            .iter()
+            .find(|factory| factory.address == address)
+    }
+
+    pub fn uses_local_hash(&self) -> bool {
+        self.local_hash.load(Ordering::Relaxed)
+    }
+
+    pub fn set_local_hash(&self, enabled: bool) {
+        self.local_hash.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Chains the service can issue signatures for, keyed by chain id.
+#[derive(Clone, Debug)]
+pub struct ChainRegistry {
+    chains: HashMap<u64, Chain>,
+    default_chain_id: u64,
+}
+
+impl ChainRegistry {
+    pub fn new(
+        configs: Vec<ChainConfig>,
+        default_chain_id: Option<u64>,
+        default_signer: Option<String>,
+    ) -> eyre::Result<Self> {
+        let default_chain_id = match default_chain_id.or(configs.first().map(|c| c.chain_id)) {
+            Some(chain_id) => chain_id,
+            None => eyre::bail!("no chain configured"),
+        };
+
+        let mut chains = HashMap::new();
+        for config in configs {
+            if config.rpc_urls.is_empty() {
+                eyre::bail!("chain {} has no rpc url", config.chain_id);
+            }
+            let client = FailoverClient::connect(&config.rpc_urls, config.provider.clone())?;
+            let relay = match config.relay {
+                Some(relay) => Some(Relay::new(relay, config.provider)?),
+                None => None,
+            };
+            let guardian = match config.guardian_address.parse() {
+                Ok(guardian) => guardian,
+                Err(_) => eyre::bail!("chain {} has an invalid guardian address", config.chain_id),
+            };
+            let signer = match config.signer.or_else(|| default_signer.clone()) {
+                Some(signer) => signer,
+                None => eyre::bail!("chain {} has no signer", config.chain_id),
+            };
+            chains.insert(
+                config.chain_id,
+                Chain {
+                    chain_id: config.chain_id,
+                    provider: Provider::new(client),
+                    guardian,
+                    guardian_address: config.guardian_address,
+                    signer,
+                    account_factories: config.account_factories,
+                    entry_point: config
+                        .entry_point
+                        .unwrap_or_else(|| DEFAULT_ENTRY_POINT.parse().unwrap()),
+                    relay,
+                    indexer: config.indexer,
+                    email_commitment: config.email_commitment,
+                    local_hash: Arc::new(AtomicBool::new(false)),
+                },
+            );
+        }
+        if !chains.contains_key(&default_chain_id) {
+            eyre::bail!("default chain {} is not configured", default_chain_id);
+        }
+
+        Ok(ChainRegistry {
+            chains,
+            default_chain_id,
+        })
+    }
+
+    pub fn default_chain_id(&self) -> u64 {
+        self.default_chain_id
+    }
+
+    /// Looks up a chain, falling back to the default chain when none is requested.
+    pub fn get(&self, chain_id: Option<u64>) -> Result<&Chain> {
+        let chain_id = chain_id.unwrap_or(self.default_chain_id);
+        self.chains
+            .get(&chain_id)
+            .ok_or_else(|| ServiceError::InvalidRequest(format!("unsupported chain {}", chain_id)))
+    }
+
+    pub fn chains(&self) -> impl Iterator<Item = &Chain> {
+        self.chains.values()
+    }
+
+    /// A registry of `chain` alone, e.g. one whose provider was replaced by a mock.
+    #[cfg(test)]
+    pub(crate) fn single(chain: Chain) -> Self {
+        ChainRegistry {
+            default_chain_id: chain.chain_id,
+            chains: HashMap::from([(chain.chain_id, chain)]),
+        }
+    }
+}
+
+/// Assigns the codes and signatures stored before chains were configured to `chain`, the
+/// default chain, and records their bindings like the `email_binding` migration does. Returns
+/// the signatures assigned; running it again does nothing.
+pub async fn backfill_chain_id(db: &PgPool, chain: &Chain) -> Result<u64> {
+    let mut tx = db.begin().await?;
+    let _ = sqlx::query("update bind_code set chain_id = $1 where chain_id is null")
+        .bind(chain.chain_id as i64)
+        .execute(&mut *tx)
+        .await?;
+    let signatures: Vec<i32> = sqlx::query_scalar(
+        "update binding_signature set chain_id = $1, guardian_address = coalesce(guardian_address, $2) where chain_id is null returning id",
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .fetch_all(&mut *tx)
+    .await?;
+    let _ = sqlx::query(
+        r#"INSERT INTO email_binding(chain_id, account, email, email_hash, signer, signature_id, created_at, updated_at, email_ciphertext, email_key_version, email_index)
+        SELECT DISTINCT ON (lower(s.account), s.email_hash) s.chain_id, lower(s.account), lower(trim(c.email)), s.email_hash, s.signer, s.id, s.created_at, s.created_at, c.email_ciphertext, c.email_key_version, c.email_index
+        FROM binding_signature s JOIN bind_code c ON c.id = s.bind_code_id
+        WHERE s.id = any($1)
+        ORDER BY lower(s.account), s.email_hash, s.id DESC
+        ON CONFLICT (chain_id, account, email_hash) WHERE revoked_at IS NULL DO NOTHING"#,
+    )
+    .bind(&signatures)
+    .execute(&mut *tx)
+    .await?;
+    let _ = sqlx::query(
+        r#"Update email_binding b set revoked_at = b.updated_at where b.chain_id = $1 and b.revoked_at is null
+        and exists (select 1 from email_binding n where n.chain_id = b.chain_id and n.account = b.account and n.signature_id > b.signature_id)
+        and not exists (select 1 from onchain_binding o where o.chain_id = b.chain_id and o.account = b.account and o.email_hash = b.email_hash and o.bound)
+        and b.signature_id = any($2)"#,
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&signatures)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(signatures.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{
+        binding::bindings_by_account,
+        testing::{self, TestDb},
+    };
+
+    #[test]
+    fn resolves_default_and_configured_chains() {
+        let configs: Vec<ChainConfig> = serde_json::from_str(
+            r#"[
+                {"chain_id": 4690, "rpc_urls": ["https://babel-api.testnet.iotex.io"], "guardian_address": "0xBf081D23317966eEBD59Bc8EDB593A830F373178"},
+                {"chain_id": 4689, "rpc_urls": ["https://babel-api.mainnet.iotex.io", "wss://babel-api.mainnet.iotex.io"], "guardian_address": "0x0000000000000000000000000000000000000001", "signer": "override", "provider": {"quorum": 2}}
+            ]"#,
+        )
+        .unwrap();
+        let registry = ChainRegistry::new(configs, None, Some("default".to_string())).unwrap();
+
+        assert_eq!(registry.get(None).unwrap().chain_id, 4690);
+        assert_eq!(registry.get(None).unwrap().signer, "default");
+        assert_eq!(registry.get(Some(4689)).unwrap().signer, "override");
+        assert!(registry.get(Some(1)).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_default_chain() {
+        let configs = vec![ChainConfig {
+            chain_id: 4690,
+            rpc_urls: vec!["https://babel-api.testnet.iotex.io".to_string()],
+            guardian_address: "0xBf081D23317966eEBD59Bc8EDB593A830F373178".to_string(),
+            ..Default::default()
+        }];
+        assert!(ChainRegistry::new(configs.clone(), Some(1), Some("key".to_string())).is_err());
+        assert!(ChainRegistry::new(configs, None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn assigns_stored_codes_to_the_default_chain() {
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        let db = &test_db.pool;
+        let chain = testing::chain();
+        let (code_id,): (i32,) = sqlx::query_as(
+            "insert into bind_code(email, account, code, status) values ('a@b.c', '0x00000000000000000000000000000000000000aa', '123456', 2) returning id",
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        sqlx::query(
+            "insert into binding_signature(bind_code_id, account, email_hash, signer, signature) values ($1, '0x00000000000000000000000000000000000000aa', $2, $3, '0xsig')",
+        )
+        .bind(code_id)
+        .bind(format!("0x{}", "1".repeat(64)))
+        .bind(format!("0x{}", "2".repeat(40)))
+        .execute(db)
+        .await
+        .unwrap();
+
+        assert_eq!(backfill_chain_id(db, &chain).await.unwrap(), 1);
+        assert_eq!(backfill_chain_id(db, &chain).await.unwrap(), 0);
+        let (code_chain,): (Option<i64>,) = sqlx::query_as("select chain_id from bind_code")
+            .fetch_one(db)
+            .await
+            .unwrap();
+        let (guardian,): (Option<String>,) =
+            sqlx::query_as("select guardian_address from binding_signature")
+                .fetch_one(db)
+                .await
+                .unwrap();
+        assert_eq!(code_chain, Some(4690));
+        assert_eq!(guardian.as_deref(), Some(chain.guardian_address.as_str()));
+        let address: Address = "0x00000000000000000000000000000000000000aa"
+            .parse()
+            .unwrap();
+        let bindings = bindings_by_account(db, None, 4690, address, false)
+            .await
+            .unwrap();
+        assert_eq!(bindings.len(), 1);
+        assert_eq!(bindings[0].email, "a@b.c");
+        test_db.drop().await;
+    }
+}
@@ -0,0 +1,347 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+
+use crate::service::{
+    error::{Result, ServiceError},
+    Context,
+};
+
+/// Seconds a proof-of-work challenge stays valid.
+pub const CHALLENGE_TTL_SECS: i64 = 300;
+
+/// Deletes a batch of used challenges recorded more than `$1` seconds ago, at most `$2`
+/// rows. A challenge is used after it was issued, so it has expired by then.
+pub(crate) const PURGE_USED_CHALLENGES: &str = r#"DELETE FROM used_challenge WHERE challenge IN (
+    SELECT challenge FROM used_challenge WHERE created_at < now() - make_interval(secs => $1)
+    LIMIT $2 FOR UPDATE SKIP LOCKED)"#;
+
+/// Deadline of a `siteverify` call, so a hung CAPTCHA provider can't hold `send_code`.
+const CAPTCHA_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Deadline for connecting to the CAPTCHA provider.
+const CAPTCHA_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Codes issued per minute that add one bit of difficulty.
+const LOAD_STEP: i64 = 10;
+
+/// A hashcash style puzzle: find `solution` such that
+/// `sha256(challenge + ":" + solution)` starts with `difficulty` zero bits.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+    pub expires_at: DateTime<Utc>,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowSolution {
+    pub challenge: String,
+    pub signature: String,
+    pub solution: String,
+}
+
+/// Verifies third-party CAPTCHA tokens.
+#[async_trait::async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    async fn verify(&self, token: &str) -> Result<bool>;
+}
+
+/// Verifies tokens against a reCAPTCHA, hCaptcha or Turnstile compatible `siteverify` endpoint.
+pub struct HttpCaptchaVerifier {
+    http: reqwest::Client,
+    url: String,
+    secret: String,
+}
+
+impl HttpCaptchaVerifier {
+    pub fn new(url: String, secret: String) -> reqwest::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(CAPTCHA_TIMEOUT)
+            .connect_timeout(CAPTCHA_CONNECT_TIMEOUT)
+            .build()?;
+        Ok(HttpCaptchaVerifier { http, url, secret })
+    }
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for HttpCaptchaVerifier {
+    async fn verify(&self, token: &str) -> Result<bool> {
+        let response = self
+            .http
+            .post(&self.url)
+            .form(&[("secret", self.secret.as_str()), ("response", token)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match response {
+            Ok(response) => match response.json::<SiteVerifyResponse>().await {
+                Ok(body) => Ok(body.success),
+                Err(err) => {
+                    error!(target: "challenge", ?err, "decode captcha response error");
+                    Ok(false)
+                }
+            },
+            Err(err) => {
+                error!(target: "challenge", ?err, "captcha verify request error");
+                Err(ServiceError::InvalidRequest(
+                    "captcha verification unavailable".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ChallengeGate {
+    secret: Vec<u8>,
+    base_difficulty: u32,
+    max_difficulty: u32,
+    captcha: Option<Arc<dyn CaptchaVerifier>>,
+}
+
+impl ChallengeGate {
+    pub fn new(secret: Vec<u8>, base_difficulty: u32, max_difficulty: u32) -> Self {
+        ChallengeGate {
+            secret,
+            base_difficulty,
+            max_difficulty: max_difficulty.max(base_difficulty),
+            captcha: None,
+        }
+    }
+
+    pub fn with_captcha(mut self, captcha: Arc<dyn CaptchaVerifier>) -> Self {
+        self.captcha = Some(captcha);
+        self
+    }
+
+    fn mac(&self, challenge: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        mac.update(challenge.as_bytes());
+        mac
+    }
+
+    fn sign(&self, challenge: &str) -> String {
+        hex::encode(self.mac(challenge).finalize().into_bytes())
+    }
+
+    fn verify_signature(&self, challenge: &str, signature: &str) -> bool {
+        match hex::decode(signature) {
+            Ok(signature) => self.mac(challenge).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Difficulty grows by one bit every time the recent issue rate doubles.
+    pub fn difficulty_for_load(&self, codes_last_minute: i64) -> u32 {
+        let steps = codes_last_minute.max(0) / LOAD_STEP;
+        let extra = 64 - (steps as u64).leading_zeros();
+        (self.base_difficulty + extra).min(self.max_difficulty)
+    }
+
+    pub fn issue(&self, difficulty: u32, now: DateTime<Utc>) -> Challenge {
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let expires_at = now + Duration::seconds(CHALLENGE_TTL_SECS);
+        let challenge = format!(
+            "{}:{}:{}",
+            hex::encode(nonce),
+            expires_at.timestamp(),
+            difficulty
+        );
+        Challenge {
+            signature: self.sign(&challenge),
+            challenge,
+            difficulty,
+            expires_at,
+        }
+    }
+
+    /// Checks signature, expiry and work of a solution. Replays are rejected by the caller.
+    pub fn check(&self, solution: &PowSolution, now: DateTime<Utc>) -> Result<()> {
+        let invalid = |reason: &str| Err(ServiceError::InvalidRequest(reason.to_string()));
+
+        if !self.verify_signature(&solution.challenge, &solution.signature) {
+            return invalid("invalid challenge signature");
+        }
+        let parts: Vec<&str> = solution.challenge.split(':').collect();
+        let (expires_at, difficulty) = match parts.as_slice() {
+            [_, expires_at, difficulty] => match (expires_at.parse(), difficulty.parse()) {
+                (Ok(expires_at), Ok(difficulty)) => (expires_at, difficulty),
+                _ => return invalid("invalid challenge"),
+            },
+            _ => return invalid("invalid challenge"),
+        };
+        match Utc.timestamp_opt(expires_at, 0).single() {
+            Some(expires_at) if expires_at >= now => {}
+            _ => return invalid("challenge expired"),
+        }
+        if leading_zero_bits(&work_hash(&solution.challenge, &solution.solution)) < difficulty {
+            return invalid("insufficient proof of work");
+        }
+        Ok(())
+    }
+}
+
+pub fn work_hash(challenge: &str, solution: &str) -> [u8; 32] {
+    Sha256::digest(format!("{}:{}", challenge, solution).as_bytes()).into()
+}
+
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+async fn codes_last_minute(context: &Context) -> Result<i64> {
+    context
+        .codes
+        .count_since(Utc::now() - chrono::Duration::minutes(1))
+        .await
+}
+
+pub async fn get_challenge(context: &Context) -> Result<Challenge> {
+    let gate = match &context.challenge {
+        Some(gate) => gate,
+        None => {
+            return Err(ServiceError::InvalidRequest(
+                "challenge not enabled".to_string(),
+            ))
+        }
+    };
+    let difficulty = gate.difficulty_for_load(codes_last_minute(context).await?);
+    Ok(gate.issue(difficulty, Utc::now()))
+}
+
+/// Passes when the challenge gate is disabled, or the request carries a valid
+/// unused proof of work or an accepted CAPTCHA token.
+pub async fn verify_challenge(
+    context: &Context,
+    pow: Option<&PowSolution>,
+    captcha_token: Option<&str>,
+) -> Result<()> {
+    let gate = match &context.challenge {
+        Some(gate) => gate,
+        None => return Ok(()),
+    };
+
+    if let (Some(captcha), Some(token)) = (&gate.captcha, captcha_token) {
+        return if captcha.verify(token).await? {
+            Ok(())
+        } else {
+            Err(ServiceError::InvalidRequest("invalid captcha".to_string()))
+        };
+    }
+
+    let pow = match pow {
+        Some(pow) => pow,
+        None => {
+            return Err(ServiceError::InvalidRequest(
+                "challenge required".to_string(),
+            ))
+        }
+    };
+    gate.check(pow, Utc::now())?;
+
+    let used = sqlx::query(
+        r#"INSERT INTO used_challenge(challenge) VALUES ($1) ON CONFLICT (challenge) DO NOTHING"#,
+    )
+    .bind(&pow.challenge)
+    .execute(&context.db)
+    .await?;
+    if used.rows_affected() == 0 {
+        warn!(target: "challenge", challenge = ?pow.challenge, "challenge replayed");
+        return Err(ServiceError::InvalidRequest(
+            "challenge already used".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solve(challenge: &Challenge) -> PowSolution {
+        let solution = (0u64..)
+            .find(|n| {
+                leading_zero_bits(&work_hash(&challenge.challenge, &n.to_string()))
+                    >= challenge.difficulty
+            })
+            .unwrap();
+        PowSolution {
+            challenge: challenge.challenge.clone(),
+            signature: challenge.signature.clone(),
+            solution: solution.to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_solved_challenge() {
+        let gate = ChallengeGate::new(b"secret".to_vec(), 8, 20);
+        let now = Utc::now();
+        let challenge = gate.issue(8, now);
+        let solution = solve(&challenge);
+
+        assert!(gate.check(&solution, now).is_ok());
+        assert!(gate
+            .check(&solution, now + Duration::seconds(CHALLENGE_TTL_SECS + 1))
+            .is_err());
+
+        let forged = PowSolution {
+            challenge: challenge.challenge.replace(":8", ":0"),
+            ..solution
+        };
+        assert!(gate.check(&forged, now).is_err());
+    }
+
+    #[test]
+    fn scales_difficulty_with_load() {
+        let gate = ChallengeGate::new(b"secret".to_vec(), 16, 20);
+        assert_eq!(gate.difficulty_for_load(0), 16);
+        assert_eq!(gate.difficulty_for_load(10), 17);
+        assert_eq!(gate.difficulty_for_load(40), 19);
+        assert_eq!(gate.difficulty_for_load(10_000), 20);
+    }
+
+    #[tokio::test]
+    async fn verifies_captcha_over_http() {
+        use axum::{routing::post, Form, Json, Router, Server};
+        use std::collections::HashMap;
+
+        let app = Router::new().route(
+            "/siteverify",
+            post(|Form(form): Form<HashMap<String, String>>| async move {
+                Json(serde_json::json!({
+                    "success": form.get("secret").map(String::as_str) == Some("key")
+                        && form.get("response").map(String::as_str) == Some("pass")
+                }))
+            }),
+        );
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}/siteverify", server.local_addr());
+        tokio::spawn(server);
+
+        let verifier = HttpCaptchaVerifier::new(url, "key".to_string()).unwrap();
+        assert!(verifier.verify("pass").await.unwrap());
+        assert!(!verifier.verify("fail").await.unwrap());
+    }
+}
@@ -1,12 +1,45 @@
-use chrono::{DateTime, Utc};
-use rand::Rng;
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use ethers::types::Address;
+use rand::{distributions::Alphanumeric, Rng};
 use regex::Regex;
-use sqlx::PgPool;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 
-use super::error::ServiceError;
+use super::{
+    account::{check_counterfactual, CounterfactualAccount},
+    chain::Chain,
+    challenge::{verify_challenge, PowSolution},
+    error::ServiceError,
+    events::DeliveryState,
+    ownership::{parse_address, verify_ownership, OwnershipProof},
+    signature::signed_email,
+    store::{CodeStore, PgCodeStore},
+    Context,
+};
 use crate::service::error::Result;
 
-#[derive(Debug, sqlx::FromRow)]
+/// Seconds during which `send_code` reuses the latest unverified code instead of issuing a new one.
+pub const RESEND_INTERVAL_SECS: i64 = 300;
+/// Seconds after creation during which a code is accepted by `verify_code`.
+pub const CODE_EXPIRY_SECS: i64 = 360;
+
+/// `bind_code.status` of a code waiting for its mail.
+pub const CODE_QUEUED: i16 = 0;
+/// `bind_code.status` of a code whose mail was sent.
+pub const CODE_SENT: i16 = 1;
+/// `bind_code.status` of a code accepted by `verify_code`.
+pub const CODE_VERIFIED: i16 = 2;
+
+/// `bind_code.purpose` of a code mailed by `send_code` and accepted by `verify_code`.
+pub const CODE_PURPOSE_BIND: i16 = 0;
+/// `bind_code.purpose` of a code mailed by `start_recovery` and accepted by `approve_recovery`.
+pub const CODE_PURPOSE_RECOVERY: i16 = 1;
+/// `bind_code.purpose` of a code mailed by `change_email` and accepted by `confirm_email_change`.
+pub const CODE_PURPOSE_CHANGE: i16 = 2;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct BindCode {
     pub id: i32,
     pub account: String,
@@ -15,41 +48,245 @@ pub struct BindCode {
     pub status: i16,
     pub created_at: DateTime<Utc>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub request_token: Option<String>,
+    pub chain_id: Option<i64>,
+    /// The email sealed by `EmailCipher`, `email` is then empty until revealed by the store.
+    #[sqlx(default)]
+    pub email_ciphertext: Option<String>,
+    #[sqlx(default)]
+    pub email_key_version: Option<i16>,
+}
+
+pub(crate) fn generate_request_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// A random six digit code, as mailed by `send_code` and `start_recovery`.
+pub(crate) fn generate_digits() -> String {
+    rand::thread_rng().gen_range(100000..999999).to_string()
+}
+
+/// Optional trailing parameter of `send_code`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SendCodeOptions {
+    pub pow: Option<PowSolution>,
+    pub captcha_token: Option<String>,
+    pub idempotency_key: Option<String>,
+    pub ownership: Option<OwnershipProof>,
+    pub chain_id: Option<u64>,
+    /// Factory, owner and salt of an account that may not be deployed yet.
+    pub counterfactual: Option<CounterfactualAccount>,
+}
+
+/// Result of `send_code`. New fields must stay optional so older clients keep decoding it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendCodeResult {
+    pub request_id: String,
+    pub issued: bool,
+    pub expires_at: DateTime<Utc>,
+    pub resend_available_at: DateTime<Utc>,
+    pub email: String,
+    #[serde(default)]
+    pub chain_id: Option<u64>,
+}
+
+impl SendCodeResult {
+    fn new(request_id: String, issued: bool, code: &BindCode) -> Self {
+        SendCodeResult {
+            request_id,
+            issued,
+            expires_at: code.created_at + Duration::seconds(CODE_EXPIRY_SECS),
+            resend_available_at: code.created_at + Duration::seconds(RESEND_INTERVAL_SECS),
+            email: mask_email(&code.email),
+            chain_id: code.chain_id.map(|chain_id| chain_id as u64),
+        }
+    }
+}
+
+/// Masks the local part of an email, keeping its first character: `t***@test.com`.
+pub fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let first = local.chars().next().map(String::from).unwrap_or_default();
+            format!("{}***@{}", first, domain)
+        }
+        None => "***".to_string(),
+    }
+}
+
+pub async fn generate_code(
+    context: &Context,
+    account: String,
+    email: String,
+    options: SendCodeOptions,
+) -> Result<SendCodeResult> {
+    validate_email(&email)?;
+
+    let chain = context.chains.get(options.chain_id)?;
+    if let Some(counterfactual) = &options.counterfactual {
+        check_counterfactual(chain, parse_address(&account)?, counterfactual)?;
+    }
+
+    verify_challenge(
+        context,
+        options.pow.as_ref(),
+        options.captcha_token.as_deref(),
+    )
+    .await?;
+
+    match &options.ownership {
+        Some(proof) => {
+            verify_ownership(
+                context,
+                chain,
+                &account,
+                proof,
+                options.counterfactual.as_ref(),
+            )
+            .await?
+        }
+        None if context.ownership.required => {
+            return Err(ServiceError::InvalidRequest(
+                "ownership proof required".to_string(),
+            ))
+        }
+        None => {}
+    }
+    ensure_unbound(context, chain, parse_address(&account)?, &email).await?;
+
+    issue_code(context, chain, &account, &email, CODE_PURPOSE_BIND).await
 }
 
-pub async fn generate_code(db: &PgPool, account: String, email: String) -> Result<String> {
+pub(crate) fn validate_email(email: &str) -> Result<()> {
     let email_regex = Regex::new(
-        r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@([a-z0-9]+([\-\.]{1}[a-z0-9]+)*\.[a-z]{2,6})",
+        r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@([a-z0-9]+([\-\.]{1}[a-z0-9]+)*\.[a-z]{2,6})$",
     )
     .unwrap();
-    if !email_regex.is_match(&email) {
+    if !email_regex.is_match(email) {
         return Err(ServiceError::InvalidRequest(String::from("invalid email")));
     }
+    Ok(())
+}
 
-    let codes = sqlx::query_as::<_, BindCode>(
-        "select id, account, email, code, status, created_at, updated_at from bind_code where account = $1 and email = $2 order by id desc limit 1",
-    ).bind(&account).bind(&email).fetch_all(db).await?;
+/// Refuses codes for another email than the one bound to `account`, which only `change_email`
+/// may replace.
+pub(crate) async fn ensure_unbound(
+    context: &Context,
+    chain: &Chain,
+    address: Address,
+    email: &str,
+) -> Result<()> {
+    match signed_email(context, chain, address).await? {
+        Some((bound, _)) if bound != email => Err(ServiceError::InvalidRequest(
+            "account has an email bound, use change_email".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
 
-    if !codes.is_empty()
-        && codes[0].status < 2
-        && codes[0].created_at.timestamp() + 300 > chrono::Local::now().timestamp()
-    {
-        return Ok("Success".to_string());
+/// The store holding codes of `purpose`. Recovery and change codes stay in Postgres, where their
+/// requests reference them.
+fn code_store(context: &Context, purpose: i16) -> Arc<dyn CodeStore> {
+    if purpose == CODE_PURPOSE_BIND {
+        context.codes.clone()
+    } else {
+        Arc::new(PgCodeStore::new(context.db.clone()).with_cipher(context.email_cipher.clone()))
     }
+}
+
+/// Queues a code of `purpose` to `email`, or reuses the one issued within the resend interval.
+pub(crate) async fn issue_code(
+    context: &Context,
+    chain: &Chain,
+    account: &str,
+    email: &str,
+    purpose: i16,
+) -> Result<SendCodeResult> {
+    let store = code_store(context, purpose);
+    let chain_id = chain.chain_id as i64;
+    let latest = store
+        .latest_for_pair(chain_id, account, email, purpose)
+        .await?;
 
-    let code = {
-        let mut rng = rand::thread_rng();
-        rng.gen_range(100000..999999).to_string()
-    };
+    if let Some(latest) = latest.filter(|latest| {
+        latest.status < CODE_VERIFIED
+            && latest.created_at.timestamp() + RESEND_INTERVAL_SECS
+                > chrono::Local::now().timestamp()
+    }) {
+        if let Some(token) = &latest.request_token {
+            return Ok(SendCodeResult::new(token.clone(), false, &latest));
+        }
+        let token = generate_request_token();
+        store.set_request_token(latest.id, &token).await?;
+        context.events.open(
+            &token,
+            if latest.status == CODE_SENT {
+                DeliveryState::Sent
+            } else {
+                DeliveryState::Pending
+            },
+        );
+        return Ok(SendCodeResult::new(token, false, &latest));
+    }
 
-    let _ = sqlx::query(
-        r#"INSERT INTO bind_code(account, email, code, status) VALUES ($1, $2, $3, $4)"#,
+    let code = generate_digits();
+    let token = generate_request_token();
+
+    let inserted = store
+        .insert_code(chain_id, account, email, &code, &token, purpose)
+        .await?;
+    context.events.open(&token, DeliveryState::Pending);
+    Ok(SendCodeResult::new(token, true, &inserted))
+}
+
+/// The latest mailed and unused code `code` of `purpose` for the pair. Callers check its age.
+pub(crate) async fn find_sent_code(
+    context: &Context,
+    chain_id: i64,
+    account: &str,
+    email: &str,
+    code: &str,
+    purpose: i16,
+) -> Result<Option<BindCode>> {
+    code_store(context, purpose)
+        .find_sent(chain_id, account, email, code, purpose)
+        .await
+}
+
+/// Marks a mailed Postgres code verified within a transaction. Returns false when another call
+/// used it first.
+pub(crate) async fn claim_code(conn: &mut PgConnection, id: i32) -> Result<bool> {
+    let claimed = sqlx::query(
+        r#"Update bind_code set status = $1, updated_at = now() where id = $2 and status = $3"#,
     )
-    .bind(&account)
-    .bind(&email)
-    .bind(&code)
-    .bind(0i16)
-    .execute(db)
+    .bind(CODE_VERIFIED)
+    .bind(id)
+    .bind(CODE_SENT)
+    .execute(conn)
     .await?;
-    Ok("Success".to_string())
+    Ok(claimed.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_email_local_part() {
+        assert_eq!(mask_email("test@test.com"), "t***@test.com");
+        assert_eq!(mask_email("@test.com"), "***@test.com");
+        assert_eq!(mask_email("invalid"), "***");
+    }
+
+    #[test]
+    fn validates_whole_email() {
+        assert!(validate_email("test@test.co.uk").is_ok());
+        assert!(validate_email("test@test.com>").is_err());
+        assert!(validate_email("test@test.com\nbcc: other@test.com").is_err());
+    }
 }
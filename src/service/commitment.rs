@@ -0,0 +1,373 @@
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::{Address, H256},
+    utils::keccak256,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::service::{
+    chain::Chain,
+    code::BindCode,
+    encryption::{reveal_email, EmailCipher},
+    error::{Result, ServiceError},
+    guardian::{binding_hash, HashConfig},
+    signature::{save_signature, SignedBinding},
+};
+
+/// How the email hash passed to the guardian is derived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailCommitment {
+    /// `keccak256(email)`, which anyone can recompute for a guessed address.
+    #[default]
+    Keccak,
+    /// `keccak256(salt ++ normalized email)` with a secret salt of the account.
+    Salted,
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub fn salted_commitment(email: &str, salt: [u8; 32]) -> [u8; 32] {
+    keccak256([&salt[..], normalize_email(email).as_bytes()].concat())
+}
+
+/// The salt of `account`, created and held by the service unless the user brought their own
+/// the first time. User-held salts are never stored and must be passed on every binding.
+///
+/// The salt mode is only recorded once `owner_verified`, so nobody else can pick it for the account.
+pub async fn account_salt(
+    db: &PgPool,
+    account: Address,
+    user_salt: Option<H256>,
+    owner_verified: bool,
+) -> Result<[u8; 32]> {
+    if !owner_verified {
+        let stored: Option<(Option<String>,)> =
+            sqlx::query_as("select salt from email_salt where account = $1")
+                .bind(format!("{:?}", account))
+                .fetch_optional(db)
+                .await?;
+        return match stored {
+            Some((stored,)) => resolve_salt(stored, user_salt),
+            None => Err(ServiceError::InvalidRequest(
+                "an ownership proof is required to choose the salt mode".to_string(),
+            )),
+        };
+    }
+
+    let service_salt = match user_salt {
+        Some(_) => None,
+        None => {
+            let mut salt = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut salt);
+            Some(format!("0x{}", hex::encode(salt)))
+        }
+    };
+    let (stored,): (Option<String>,) = sqlx::query_as(
+        r#"INSERT INTO email_salt(account, salt) VALUES ($1, $2)
+        ON CONFLICT (account) DO UPDATE SET account = excluded.account RETURNING salt"#,
+    )
+    .bind(format!("{:?}", account))
+    .bind(&service_salt)
+    .fetch_one(db)
+    .await?;
+    resolve_salt(stored, user_salt)
+}
+
+/// The salt to use given the `stored` service salt, if any, and the salt the user passed.
+fn resolve_salt(stored: Option<String>, user_salt: Option<H256>) -> Result<[u8; 32]> {
+    match (stored, user_salt) {
+        (Some(stored), None) => {
+            let salt: H256 = stored
+                .parse()
+                .map_err(|_| ServiceError::DatabaseError("invalid email salt".to_string()))?;
+            Ok(salt.0)
+        }
+        (Some(_), Some(_)) => Err(ServiceError::InvalidRequest(
+            "account uses a salt held by the service".to_string(),
+        )),
+        (None, Some(user_salt)) => Ok(user_salt.0),
+        (None, None) => Err(ServiceError::InvalidRequest(
+            "email salt required".to_string(),
+        )),
+    }
+}
+
+/// The email hash bound to `account` on `chain`, see `account_salt` for `owner_verified`.
+pub async fn email_commitment(
+    db: &PgPool,
+    chain: &Chain,
+    account: Address,
+    email: &str,
+    user_salt: Option<H256>,
+    owner_verified: bool,
+) -> Result<[u8; 32]> {
+    match chain.email_commitment {
+        EmailCommitment::Keccak => Ok(keccak256(email)),
+        EmailCommitment::Salted => Ok(salted_commitment(
+            email,
+            account_salt(db, account, user_salt, owner_verified).await?,
+        )),
+    }
+}
+
+/// Signs salted bindings replacing the `keccak256(email)` ones of `chain`, returning how many.
+///
+/// Accounts pick the new signature up with `get_signature` and submit it to the guardian. Until
+/// then the guardian holds the keccak hash, which `bound_email` keeps using on chains with an
+/// indexer. Accounts with a user-held salt are skipped, they rebind with `verify_code`.
+pub async fn migrate_commitments(
+    db: &PgPool,
+    cipher: Option<&EmailCipher>,
+    chain: &Chain,
+    config: &HashConfig,
+) -> Result<usize> {
+    if chain.email_commitment != EmailCommitment::Salted {
+        return Ok(0);
+    }
+    let wallet = match chain.signer.parse::<LocalWallet>() {
+        Ok(w) => w,
+        Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
+    };
+    #[derive(sqlx::FromRow)]
+    struct Latest {
+        bind_code_id: i32,
+        email: String,
+        email_ciphertext: Option<String>,
+        email_key_version: Option<i16>,
+        email_hash: String,
+    }
+    // the latest active binding of each account
+    let latest = sqlx::query_as::<_, Latest>(
+        "select distinct on (b.account) s.bind_code_id, b.email, b.email_ciphertext, b.email_key_version, b.email_hash from email_binding b join binding_signature s on s.id = b.signature_id where b.chain_id = $1 and s.guardian_address = $2 and b.revoked_at is null order by b.account, b.id desc",
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .fetch_all(db)
+    .await?;
+
+    let mut migrated = 0;
+    for Latest {
+        bind_code_id,
+        email,
+        email_ciphertext,
+        email_key_version,
+        email_hash,
+    } in latest
+    {
+        let code = sqlx::query_as::<_, BindCode>(
+            "select id, account, email, code, status, created_at, updated_at, request_token, chain_id from bind_code where id = $1",
+        )
+        .bind(bind_code_id)
+        .fetch_one(db)
+        .await?;
+        // the code itself may have been anonymized by the retention job
+        let email = reveal_email(
+            cipher,
+            &email,
+            email_ciphertext.as_deref(),
+            email_key_version,
+        )?;
+        let code = BindCode { email, ..code };
+        if email_hash.trim() != format!("0x{}", hex::encode(keccak256(&code.email))) {
+            continue;
+        }
+        let account: Address = match code.account.parse() {
+            Ok(account) => account,
+            Err(_) => continue,
+        };
+        // the service moves its own bindings, so it picks the service salt
+        let commitment = match account_salt(db, account, None, true).await {
+            Ok(salt) => salted_commitment(&code.email, salt),
+            Err(ServiceError::InvalidRequest(_)) => continue,
+            Err(err) => return Err(err),
+        };
+
+        let (hash, nonce) = binding_hash(config, chain, account, commitment).await?;
+        let signature = match wallet.sign_message(hash).await {
+            Ok(s) => format!("0x{}", s),
+            Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
+        };
+        // the code was verified by the binding it replaces
+        save_signature(
+            db,
+            cipher,
+            chain,
+            &code,
+            SignedBinding {
+                email_hash: commitment,
+                signer: &format!("{:?}", wallet.address()),
+                signature: &signature,
+                nonce,
+            },
+            // the guardian holds the keccak hash until the account submits the new signature
+            false,
+        )
+        .await?;
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        providers::{MockProvider, Provider},
+        types::Bytes,
+    };
+
+    use crate::{
+        contracts::provider::{FailoverClient, ProviderConfig},
+        service::{
+            binding::bindings_by_account,
+            code::CODE_VERIFIED,
+            signature::bound_email,
+            testing::{self, TestDb},
+        },
+    };
+
+    #[test]
+    fn salts_normalized_email() {
+        let salt = [7u8; 32];
+        let commitment = salted_commitment(" Test@Test.com ", salt);
+
+        assert_eq!(commitment, salted_commitment("test@test.com", salt));
+        assert_ne!(commitment, salted_commitment("test@test.com", [8u8; 32]));
+        assert_ne!(commitment, keccak256("test@test.com"));
+    }
+
+    /// Marks `email_hash` as the hash the guardian holds for `account`.
+    async fn bind_onchain(db: &PgPool, chain: &Chain, account: Address, email_hash: &str) {
+        sqlx::query(
+            "insert into onchain_binding(chain_id, guardian_address, account, email_hash, bound, block_number, tx_hash) values ($1, $2, $3, $4, true, 1, $5) on conflict (chain_id, guardian_address, account) do update set email_hash = excluded.email_hash",
+        )
+        .bind(chain.chain_id as i64)
+        .bind(&chain.guardian_address)
+        .bind(format!("{:?}", account))
+        .bind(email_hash)
+        .bind(format!("0x{}", "0".repeat(64)))
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_keccak_binding_until_salted_one_is_onchain() {
+        let Some(db) = TestDb::new().await else {
+            return;
+        };
+        let mock = MockProvider::new();
+        mock.push::<Bytes, _>(Bytes::from([9u8; 32].to_vec()))
+            .unwrap();
+        let mut chain = testing::chain();
+        chain.provider = Provider::new(
+            FailoverClient::new(
+                vec![("mock".to_string(), Box::new(mock))],
+                ProviderConfig::default(),
+            )
+            .unwrap(),
+        );
+        chain.email_commitment = EmailCommitment::Salted;
+        chain.indexer = Some(serde_json::from_value(serde_json::json!({})).unwrap());
+        let mut context = testing::context(db.pool.clone(), chain.clone());
+        // the bindings are stored encrypted
+        let cipher = testing::cipher();
+        context.email_cipher = Some(cipher.clone());
+
+        let account = Address::repeat_byte(1);
+        let keccak = format!("0x{}", hex::encode(keccak256("test@test.com")));
+        let (code_id,): (i32,) = sqlx::query_as(
+            "insert into bind_code(account, email, code, status, chain_id) values ($1, 'test@test.com', '123456', $2, $3) returning id",
+        )
+        .bind(format!("{:?}", account))
+        .bind(CODE_VERIFIED)
+        .bind(chain.chain_id as i64)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        let code = sqlx::query_as::<_, BindCode>("select * from bind_code where id = $1")
+            .bind(code_id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        // the keccak binding signed twice, it is migrated once
+        for _ in 0..2 {
+            save_signature(
+                &db.pool,
+                Some(&cipher),
+                &chain,
+                &code,
+                SignedBinding {
+                    email_hash: keccak256("test@test.com"),
+                    signer: "0x0000000000000000000000000000000000000001",
+                    signature: "0x",
+                    nonce: None,
+                },
+                true,
+            )
+            .await
+            .unwrap();
+        }
+        bind_onchain(&db.pool, &chain, account, &keccak).await;
+
+        let config = context.guardian_hash.clone();
+        assert_eq!(
+            migrate_commitments(&db.pool, Some(&cipher), &chain, &config)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            migrate_commitments(&db.pool, Some(&cipher), &chain, &config)
+                .await
+                .unwrap(),
+            0
+        );
+        let salted: String =
+            sqlx::query_scalar("select email_hash from binding_signature order by id desc limit 1")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        let salt = account_salt(&db.pool, account, None, false).await.unwrap();
+        assert_eq!(
+            salted,
+            format!(
+                "0x{}",
+                hex::encode(salted_commitment("test@test.com", salt))
+            )
+        );
+
+        // the guardian still holds the keccak hash, so its binding stays active
+        assert_eq!(
+            bindings_by_account(
+                &db.pool,
+                Some(&cipher),
+                chain.chain_id as i64,
+                account,
+                false
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|binding| binding.email_hash.clone())
+            .collect::<Vec<_>>(),
+            vec![salted.clone(), keccak.clone()]
+        );
+        assert_eq!(
+            bound_email(&context, &chain, account).await.unwrap(),
+            "test@test.com"
+        );
+        bind_onchain(&db.pool, &chain, account, &salted).await;
+        assert_eq!(
+            bound_email(&context, &chain, account).await.unwrap(),
+            "test@test.com"
+        );
+
+        db.drop().await;
+    }
+}
@@ -2,22 +2,72 @@ use lettre::{transport::smtp::authentication::Credentials, Message, SmtpTranspor
 use sqlx::PgPool;
 use tracing::{error, info};
 
-use crate::service::code::BindCode;
+use crate::service::{
+    alert::{alert_message, SecurityAlert, ALERT_CLAIM_LEASE_SECS, ALERT_QUEUED, ALERT_SENT},
+    code::{mask_email, CODE_QUEUED, CODE_SENT},
+    email_change::{
+        time_lock_message, EmailChange, CHANGE_CLAIM_LEASE_SECS, CHANGE_COLUMNS, CHANGE_TIME_LOCKED,
+    },
+    encryption::EmailCipher,
+    events::{DeliveryState, EventHub},
+    recovery::{
+        approved_message, code_message, RecoveryConfig, RecoveryRequest, RECOVERY_APPROVED,
+        RECOVERY_CLAIM_LEASE_SECS, RECOVERY_COLUMNS, RECOVERY_PENDING,
+    },
+    store::CodeStore,
+};
 
-pub async fn send_mails(db: &PgPool, key: &str, from: &str, host: &str) {
-    let codes = sqlx::query_as::<_, BindCode>(
-        "select id, account, email, code, status, created_at, updated_at from bind_code where status = 0 order by id desc limit 100",
-    ).fetch_all(db).await;
+/// The SMTP relay mails are sent through, `from` logging in with `key`.
+#[derive(Clone)]
+pub struct SmtpConfig {
+    pub key: String,
+    pub from: String,
+    pub host: String,
+}
+
+fn deliver(
+    smtp: &SmtpConfig,
+    to: &str,
+    subject: String,
+    body: String,
+) -> Result<(), lettre::transport::smtp::Error> {
+    let email: Message = Message::builder()
+        .from(smtp.from.parse().unwrap())
+        .to(to.parse().unwrap())
+        .subject(subject)
+        .body(body)
+        .unwrap();
+
+    let creds: Credentials = Credentials::new(smtp.from.to_string(), smtp.key.to_string());
+    let mailer: SmtpTransport = SmtpTransport::relay(&smtp.host)
+        .unwrap()
+        .credentials(creds)
+        .build();
+    mailer.send(&email).map(|_| ())
+}
+
+/// Mails the queued codes of `store`. Recovery codes are mailed with their request by
+/// `send_recovery_mails`.
+pub async fn send_mails(store: &dyn CodeStore, events: &EventHub, smtp: &SmtpConfig) {
+    let codes = store.claim_pending(100).await;
 
     match codes {
         Ok(codes) => {
             for code in codes {
-                let email: Message = Message::builder()
-                    .from(from.parse().unwrap())
-                    .to(code.email.parse().unwrap())
-                    .subject(format!("ioPay AA Wallet Verification Code - {}", code.code))
-                    .body(
-format!("Dear User,
+                // encrypted emails are only decrypted to address the mail
+                let to = match store.reveal_email(&code) {
+                    Ok(to) => to,
+                    Err(err) => {
+                        let _ = store.mark_failed(code.id).await;
+                        if let Some(token) = &code.request_token {
+                            events.publish(token, DeliveryState::SendFailed);
+                        }
+                        error!(target: "email", id = ?code.id, ?err, "reveal email error");
+                        continue;
+                    }
+                };
+                let subject = format!("ioPay AA Wallet Verification Code - {}", code.code);
+                let body = format!("Dear User,
 
 I hope this message finds you well. We are writing to provide you with an important piece of information regarding your ioPay AA Wallet.
 
@@ -33,35 +83,232 @@ Please remember to store this code in a safe and secure place where only you can
 Thank you for your attention to this matter. We appreciate your cooperation in maintaining the security of your ioPay AA Wallet.
 
 Best Regards,
-ioPay Team", code.code))
-                    .unwrap();
-
-                let creds: Credentials = Credentials::new(from.to_string(), key.to_string());
-                let mailer: SmtpTransport = SmtpTransport::relay(host)
-                    .unwrap()
-                    .credentials(creds)
-                    .build();
-                match mailer.send(&email) {
+ioPay Team", code.code);
+                match deliver(smtp, &to, subject, body) {
+                    Ok(_) => {
+                        let _ = store.mark_sent(code.id).await;
+                        if let Some(token) = &code.request_token {
+                            events.publish(token, DeliveryState::Sent);
+                        }
+                        info!(target: "email", id = ?code.id, email = ?mask_email(&to), "send email success")
+                    }
+                    Err(err) => {
+                        let _ = store.mark_failed(code.id).await;
+                        if let Some(token) = &code.request_token {
+                            events.publish(token, DeliveryState::SendFailed);
+                        }
+                        error!(target: "email", id = ?code.id, email = ?mask_email(&to), err = ?err, "send email")
+                    }
+                };
+            }
+        }
+        Err(err) => {
+            error!(target: "email", ?err, "query codes error")
+        }
+    }
+}
+
+/// Mails queued security alerts, each with a "this wasn't me" link made of `freeze_link` and its token.
+pub async fn send_alerts(
+    db: &PgPool,
+    cipher: Option<&EmailCipher>,
+    freeze_link: &str,
+    smtp: &SmtpConfig,
+) {
+    // claimed for a lease, so concurrent mailers don't send an alert twice
+    let alerts = sqlx::query_as::<_, SecurityAlert>(
+        r#"Update security_alert set claimed_at = now() where id in (select id from security_alert where status = $1 and (claimed_at is null or claimed_at < now() - make_interval(secs => $2)) order by id limit 100 for update skip locked) RETURNING id, chain_id, account, email, kind, freeze_token, email_ciphertext, email_key_version"#,
+    )
+    .bind(ALERT_QUEUED)
+    .bind(ALERT_CLAIM_LEASE_SECS as f64)
+    .fetch_all(db)
+    .await;
+
+    match alerts {
+        Ok(alerts) => {
+            for alert in alerts {
+                let id = alert.id;
+                let alert = match alert.reveal(cipher) {
+                    Ok(alert) => alert,
+                    Err(err) => {
+                        error!(target: "email", id, ?err, "reveal alert email error");
+                        continue;
+                    }
+                };
+                let (subject, body) = alert_message(&alert, freeze_link);
+                match deliver(smtp, &alert.email, subject, body) {
                     Ok(_) => {
                         let _ = sqlx::query(
-                            r#"Update bind_code set status = $1, updated_at = now() where id = $2"#,
+                            r#"Update security_alert set status = $1, updated_at = now() where id = $2"#,
                         )
-                        .bind(1i16)
-                        .bind(code.id)
+                        .bind(ALERT_SENT)
+                        .bind(alert.id)
                         .execute(db)
                         .await;
-                        info!(target: "email", id = ?code.id, email = ?code.email, "send email success")
+                        info!(target: "email", id = ?alert.id, email = ?mask_email(&alert.email), kind = ?alert.kind, "send alert success")
                     }
                     Err(err) => {
-                        println!("Send email error: {}", err);
-                        error!(target: "email", id = ?code.id, email = ?code.email, err = ?err, "send email")
+                        let _ = sqlx::query(
+                            r#"Update security_alert set claimed_at = null where id = $1"#,
+                        )
+                        .bind(alert.id)
+                        .execute(db)
+                        .await;
+                        error!(target: "email", id = ?alert.id, email = ?mask_email(&alert.email), err = ?err, "send alert")
                     }
                 };
             }
         }
         Err(err) => {
-            println!("Query codes error: {}", err);
-            error!(target: "email", ?err, "query codes error")
+            error!(target: "email", ?err, "query alerts error")
+        }
+    }
+}
+
+/// Mails the codes of recovery requests with a freeze link made of `freeze_link` and their
+/// token, and notifies the bound email of approved recoveries.
+pub async fn send_recovery_mails(
+    db: &PgPool,
+    cipher: Option<&EmailCipher>,
+    events: &EventHub,
+    config: &RecoveryConfig,
+    freeze_link: &str,
+    smtp: &SmtpConfig,
+) {
+    // claimed for a lease, so concurrent mailers don't send a mail twice
+    let requests = sqlx::query_as::<_, RecoveryRequest>(&format!(
+        r#"WITH claimed AS (Update recovery_request set claimed_at = now() where id in (select r.id from recovery_request r join bind_code c on c.id = r.bind_code_id where ((r.status = $1 and c.status = $2) or (r.status = $3 and not r.approval_notified)) and (r.claimed_at is null or r.claimed_at < now() - make_interval(secs => $4)) order by r.id limit 100 for update of r skip locked) RETURNING *)
+        select {} from claimed r join bind_code c on c.id = r.bind_code_id"#,
+        RECOVERY_COLUMNS
+    ))
+    .bind(RECOVERY_PENDING)
+    .bind(CODE_QUEUED)
+    .bind(RECOVERY_APPROVED)
+    .bind(RECOVERY_CLAIM_LEASE_SECS as f64)
+    .fetch_all(db)
+    .await;
+
+    match requests {
+        Ok(requests) => {
+            for request in requests {
+                let id = request.id;
+                let request = match request.reveal(cipher) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        error!(target: "email", id, ?err, "reveal recovery email error");
+                        continue;
+                    }
+                };
+                let approved = request.status == RECOVERY_APPROVED;
+                let (subject, body) = if approved {
+                    approved_message(&request)
+                } else {
+                    code_message(&request, config, freeze_link)
+                };
+                match deliver(smtp, &request.email, subject, body) {
+                    Ok(_) => {
+                        let _ = if approved {
+                            sqlx::query(
+                                r#"Update recovery_request set approval_notified = true, claimed_at = null, updated_at = now() where id = $1"#,
+                            )
+                            .bind(request.id)
+                            .execute(db)
+                            .await
+                        } else {
+                            events.publish(&request.request_token, DeliveryState::Sent);
+                            let _ = sqlx::query(
+                                r#"Update recovery_request set claimed_at = null where id = $1"#,
+                            )
+                            .bind(request.id)
+                            .execute(db)
+                            .await;
+                            sqlx::query(
+                                r#"Update bind_code set status = $1, updated_at = now() where id = $2 and status = $3"#,
+                            )
+                            .bind(CODE_SENT)
+                            .bind(request.bind_code_id)
+                            .bind(CODE_QUEUED)
+                            .execute(db)
+                            .await
+                        };
+                        info!(target: "email", id = ?request.id, email = ?mask_email(&request.email), approved, "send recovery mail success")
+                    }
+                    Err(err) => {
+                        let _ = sqlx::query(
+                            r#"Update recovery_request set claimed_at = null where id = $1"#,
+                        )
+                        .bind(request.id)
+                        .execute(db)
+                        .await;
+                        if !approved {
+                            events.publish(&request.request_token, DeliveryState::SendFailed);
+                        }
+                        error!(target: "email", id = ?request.id, email = ?mask_email(&request.email), err = ?err, "send recovery mail")
+                    }
+                };
+            }
+        }
+        Err(err) => {
+            error!(target: "email", ?err, "query recovery requests error")
+        }
+    }
+}
+
+/// Warns the bound address of time-locked email changes, with a link made of `cancel_link` and
+/// their token.
+pub async fn send_email_change_notices(
+    db: &PgPool,
+    cipher: Option<&EmailCipher>,
+    cancel_link: &str,
+    smtp: &SmtpConfig,
+) {
+    // claimed for a lease, so concurrent mailers don't send a notice twice
+    let changes = sqlx::query_as::<_, EmailChange>(&format!(
+        r#"WITH claimed AS (Update email_change set claimed_at = now() where id in (select id from email_change where status = $1 and not notified and (claimed_at is null or claimed_at < now() - make_interval(secs => $2)) order by id limit 100 for update skip locked) RETURNING *)
+        select {} from claimed e join bind_code o on o.id = e.old_code_id join bind_code n on n.id = e.new_code_id"#,
+        CHANGE_COLUMNS
+    ))
+    .bind(CHANGE_TIME_LOCKED)
+    .bind(CHANGE_CLAIM_LEASE_SECS as f64)
+    .fetch_all(db)
+    .await;
+
+    match changes {
+        Ok(changes) => {
+            for change in changes {
+                let id = change.id;
+                let change = match change.reveal(cipher) {
+                    Ok(change) => change,
+                    Err(err) => {
+                        error!(target: "email", id, ?err, "reveal email change error");
+                        continue;
+                    }
+                };
+                let (subject, body) = time_lock_message(&change, cancel_link);
+                match deliver(smtp, &change.old_email, subject, body) {
+                    Ok(_) => {
+                        let _ = sqlx::query(
+                            r#"Update email_change set notified = true, claimed_at = null, updated_at = now() where id = $1"#,
+                        )
+                        .bind(change.id)
+                        .execute(db)
+                        .await;
+                        info!(target: "email", id = ?change.id, email = ?mask_email(&change.old_email), "send email change notice success")
+                    }
+                    Err(err) => {
+                        let _ = sqlx::query(
+                            r#"Update email_change set claimed_at = null where id = $1"#,
+                        )
+                        .bind(change.id)
+                        .execute(db)
+                        .await;
+                        error!(target: "email", id = ?change.id, email = ?mask_email(&change.old_email), err = ?err, "send email change notice")
+                    }
+                };
+            }
+        }
+        Err(err) => {
+            error!(target: "email", ?err, "query email changes error")
         }
     }
 }
@@ -0,0 +1,667 @@
+use chrono::{DateTime, Duration, Utc};
+use ethers::types::H256;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::service::{
+    account::CounterfactualAccount,
+    alert::ensure_not_frozen,
+    chain::Chain,
+    challenge::{verify_challenge, PowSolution},
+    code::{
+        claim_code, find_sent_code, issue_code, mask_email, validate_email, BindCode,
+        SendCodeResult, CODE_EXPIRY_SECS, CODE_PURPOSE_CHANGE, CODE_SENT,
+    },
+    encryption::{reveal_email, EmailCipher},
+    error::{Result, ServiceError},
+    ownership::{parse_address, verify_ownership, OwnershipProof},
+    signature::bound_email,
+    verify::{sign_binding, VerifyCodeOptions, VerifyCodeResult},
+    Context,
+};
+
+pub const CHANGE_PENDING: i16 = 0;
+pub const CHANGE_TIME_LOCKED: i16 = 1;
+pub const CHANGE_COMPLETED: i16 = 2;
+pub const CHANGE_CANCELLED: i16 = 3;
+
+/// Seconds a mailer holds a time-lock notice before another one may mail it.
+pub const CHANGE_CLAIM_LEASE_SECS: i64 = 300;
+
+/// Deletes a batch of email changes created, or unlocked, more than `$1` seconds ago, at
+/// most `$2` rows.
+pub(crate) const PURGE_EMAIL_CHANGES: &str = r#"DELETE FROM email_change WHERE id IN (
+    SELECT id FROM email_change WHERE coalesce(unlock_at, created_at) < now() - make_interval(secs => $1)
+    LIMIT $2 FOR UPDATE SKIP LOCKED)"#;
+
+#[derive(Debug, Clone)]
+pub struct EmailChangeConfig {
+    /// Seconds a change confirmed by the new address only waits before it can complete.
+    pub time_lock_secs: i64,
+}
+
+impl Default for EmailChangeConfig {
+    fn default() -> Self {
+        EmailChangeConfig {
+            time_lock_secs: 259200,
+        }
+    }
+}
+
+/// An email change with the emails of the `bind_code` rows holding its codes.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct EmailChange {
+    pub id: i32,
+    pub chain_id: i64,
+    pub account: String,
+    pub old_email: String,
+    pub new_email: String,
+    pub old_code_id: i32,
+    pub new_code_id: i32,
+    pub status: i16,
+    pub unlock_at: Option<DateTime<Utc>>,
+    /// Token of the link cancelling the change, mailed to the bound address.
+    pub cancel_token: String,
+    /// The emails sealed by `EmailCipher`, the plain ones are then empty until revealed.
+    #[sqlx(default)]
+    pub old_email_ciphertext: Option<String>,
+    #[sqlx(default)]
+    pub old_email_key_version: Option<i16>,
+    #[sqlx(default)]
+    pub new_email_ciphertext: Option<String>,
+    #[sqlx(default)]
+    pub new_email_key_version: Option<i16>,
+}
+
+impl EmailChange {
+    /// Decrypts the emails of a change whose codes store them encrypted.
+    pub(crate) fn reveal(self, cipher: Option<&EmailCipher>) -> Result<Self> {
+        let old_email = reveal_email(
+            cipher,
+            &self.old_email,
+            self.old_email_ciphertext.as_deref(),
+            self.old_email_key_version,
+        )?;
+        let new_email = reveal_email(
+            cipher,
+            &self.new_email,
+            self.new_email_ciphertext.as_deref(),
+            self.new_email_key_version,
+        )?;
+        Ok(EmailChange {
+            old_email,
+            new_email,
+            ..self
+        })
+    }
+}
+
+/// Optional trailing parameter of `change_email`, checked as by `send_code`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChangeEmailOptions {
+    pub pow: Option<PowSolution>,
+    pub captcha_token: Option<String>,
+    /// Proof of owning the account, always required.
+    pub ownership: Option<OwnershipProof>,
+    pub chain_id: Option<u64>,
+}
+
+/// Optional trailing parameter of `confirm_email_change`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfirmEmailChangeOptions {
+    /// Code mailed to the new address, required until the change is time-locked.
+    pub new_code: Option<String>,
+    /// Code mailed to the bound address; without it the change is time-locked.
+    pub old_code: Option<String>,
+    pub chain_id: Option<u64>,
+    pub user_operation: bool,
+    pub counterfactual: Option<CounterfactualAccount>,
+    pub relay: bool,
+    /// Proof of owning the account, required to relay.
+    pub ownership: Option<OwnershipProof>,
+    /// The user-held salt of the account on chains with salted email commitments.
+    pub email_salt: Option<H256>,
+}
+
+/// Result of `change_email`: the codes queued to both addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEmailResult {
+    pub old_email: SendCodeResult,
+    pub new_email: SendCodeResult,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailChangeState {
+    TimeLocked,
+    Completed,
+    Cancelled,
+}
+
+/// Result of `confirm_email_change` and `cancel_email_change`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmailChangeResult {
+    pub state: EmailChangeState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unlock_at: Option<DateTime<Utc>>,
+    /// The signature binding the new address, once completed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binding: Option<VerifyCodeResult>,
+}
+
+/// Columns of an [`EmailChange`], selected from `email_change e` joined with the `bind_code o`
+/// of the old address and `n` of the new one.
+pub(crate) const CHANGE_COLUMNS: &str = "e.id, e.chain_id, e.account, o.email as old_email, n.email as new_email, e.old_code_id, e.new_code_id, e.status, e.unlock_at, e.cancel_token, o.email_ciphertext as old_email_ciphertext, o.email_key_version as old_email_key_version, n.email_ciphertext as new_email_ciphertext, n.email_key_version as new_email_key_version";
+
+/// Mails codes to both the bound and the new address of `account`, replacing the pending
+/// change of the account.
+pub async fn change_email(
+    context: &Context,
+    account: String,
+    new_email: String,
+    options: ChangeEmailOptions,
+) -> Result<ChangeEmailResult> {
+    let chain = context.chains.get(options.chain_id)?;
+    let address = parse_address(&account)?;
+    ensure_not_frozen(&context.db, chain, address).await?;
+    let old_email = bound_email(context, chain, address).await?;
+    if old_email == new_email {
+        return Err(ServiceError::InvalidRequest(
+            "email is already bound".to_string(),
+        ));
+    }
+
+    validate_email(&new_email)?;
+    verify_challenge(
+        context,
+        options.pow.as_ref(),
+        options.captcha_token.as_deref(),
+    )
+    .await?;
+    let proof = options.ownership.as_ref().ok_or_else(|| {
+        ServiceError::InvalidRequest("changing the email requires an ownership proof".to_string())
+    })?;
+    verify_ownership(context, chain, &account, proof, None).await?;
+
+    let account = format!("{:?}", address);
+    let new_code = issue_code(context, chain, &account, &new_email, CODE_PURPOSE_CHANGE).await?;
+    let old_code = issue_code(context, chain, &account, &old_email, CODE_PURPOSE_CHANGE).await?;
+
+    let mut cancel_token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut cancel_token);
+    let mut tx = context.db.begin().await?;
+    let _ = sqlx::query(
+        r#"Update email_change set status = $1, updated_at = now() where chain_id = $2 and account = $3 and status < $4"#,
+    )
+    .bind(CHANGE_CANCELLED)
+    .bind(chain.chain_id as i64)
+    .bind(&account)
+    .bind(CHANGE_COMPLETED)
+    .execute(&mut *tx)
+    .await?;
+    let _ = sqlx::query(
+        r#"INSERT INTO email_change(chain_id, account, old_code_id, new_code_id, status, cancel_token) VALUES ($1, $2, (select id from bind_code where request_token = $3), (select id from bind_code where request_token = $4), $5, $6)"#,
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&account)
+    .bind(&old_code.request_id)
+    .bind(&new_code.request_id)
+    .bind(CHANGE_PENDING)
+    .bind(hex::encode(cancel_token))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(ChangeEmailResult {
+        old_email: old_code,
+        new_email: new_code,
+    })
+}
+
+/// Whether `given` is the mailed and unexpired code `id` of `email`.
+async fn accepts(
+    context: &Context,
+    change: &EmailChange,
+    id: i32,
+    email: &str,
+    given: Option<&str>,
+) -> Result<bool> {
+    let Some(given) = given else {
+        return Ok(false);
+    };
+    let found = find_sent_code(
+        context,
+        change.chain_id,
+        &change.account,
+        email,
+        given,
+        CODE_PURPOSE_CHANGE,
+    )
+    .await?;
+    Ok(found.is_some_and(|code| {
+        code.id == id && code.created_at + Duration::seconds(CODE_EXPIRY_SECS) >= Utc::now()
+    }))
+}
+
+/// Claims the change from `status` and the `codes` not claimed yet, then signs the binding of
+/// the new address. The claims are handed back when signing fails, so the change can be
+/// confirmed again.
+async fn complete(
+    context: &Context,
+    chain: &Chain,
+    change: &EmailChange,
+    status: i16,
+    codes: &[i32],
+    options: ConfirmEmailChangeOptions,
+) -> Result<EmailChangeResult> {
+    let mut tx = context.db.begin().await?;
+    let claimed = sqlx::query(
+        r#"Update email_change set status = $1, updated_at = now() where id = $2 and status = $3"#,
+    )
+    .bind(CHANGE_COMPLETED)
+    .bind(change.id)
+    .bind(status)
+    .execute(&mut *tx)
+    .await?;
+    if claimed.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Err(ServiceError::InvalidRequest(
+            "no email change pending".to_string(),
+        ));
+    }
+    for id in codes {
+        if !claim_code(&mut tx, *id).await? {
+            tx.rollback().await?;
+            return Err(ServiceError::InvalidRequest("error code".to_string()));
+        }
+    }
+    tx.commit().await?;
+
+    let new_code = sqlx::query_as::<_, BindCode>(
+        "select id, account, email, code, status, created_at, updated_at, request_token, chain_id from bind_code where id = $1",
+    )
+    .bind(change.new_code_id)
+    .fetch_one(&context.db)
+    .await?;
+    let new_code = BindCode {
+        email: change.new_email.clone(),
+        ..new_code
+    };
+    let verify_options = VerifyCodeOptions {
+        chain_id: Some(chain.chain_id),
+        user_operation: options.user_operation,
+        counterfactual: options.counterfactual,
+        relay: options.relay,
+        ownership: options.ownership,
+        email_salt: options.email_salt,
+        ..Default::default()
+    };
+    let address = parse_address(&change.account)?;
+    match sign_binding(context, chain, address, &new_code, &verify_options, true).await {
+        Ok(binding) => Ok(EmailChangeResult {
+            state: EmailChangeState::Completed,
+            unlock_at: None,
+            binding: Some(binding),
+        }),
+        Err(err) => {
+            let mut tx = context.db.begin().await?;
+            let _ = sqlx::query(
+                r#"Update email_change set status = $1, updated_at = now() where id = $2"#,
+            )
+            .bind(status)
+            .bind(change.id)
+            .execute(&mut *tx)
+            .await?;
+            let _ = sqlx::query(
+                r#"Update bind_code set status = $1, updated_at = now() where id = any($2)"#,
+            )
+            .bind(CODE_SENT)
+            .bind(codes)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            Err(err)
+        }
+    }
+}
+
+/// Completes the pending change of `account` when both addresses confirmed it, or time-locks it
+/// when only the new one did. A time-locked change completes once unlocked.
+pub async fn confirm_email_change(
+    context: &Context,
+    account: String,
+    options: ConfirmEmailChangeOptions,
+) -> Result<EmailChangeResult> {
+    let chain = context.chains.get(options.chain_id)?;
+    let address = parse_address(&account)?;
+    ensure_not_frozen(&context.db, chain, address).await?;
+    let change = sqlx::query_as::<_, EmailChange>(&format!(
+        "select {} from email_change e join bind_code o on o.id = e.old_code_id join bind_code n on n.id = e.new_code_id where e.chain_id = $1 and e.account = $2 and e.status < $3 order by e.id desc limit 1",
+        CHANGE_COLUMNS
+    ))
+    .bind(chain.chain_id as i64)
+    .bind(format!("{:?}", address))
+    .bind(CHANGE_COMPLETED)
+    .fetch_optional(&context.db)
+    .await?
+    .ok_or_else(|| ServiceError::InvalidRequest("no email change pending".to_string()))?
+    .reveal(context.email_cipher.as_ref())?;
+    if bound_email(context, chain, address).await? != change.old_email {
+        return Err(ServiceError::InvalidRequest(
+            "bound email changed, start over".to_string(),
+        ));
+    }
+
+    if change.status == CHANGE_TIME_LOCKED {
+        return match change.unlock_at {
+            Some(unlock_at) if unlock_at <= Utc::now() => {
+                complete(context, chain, &change, CHANGE_TIME_LOCKED, &[], options).await
+            }
+            unlock_at => Ok(EmailChangeResult {
+                state: EmailChangeState::TimeLocked,
+                unlock_at,
+                binding: None,
+            }),
+        };
+    }
+
+    if !accepts(
+        context,
+        &change,
+        change.new_code_id,
+        &change.new_email,
+        options.new_code.as_deref(),
+    )
+    .await?
+    {
+        return Err(ServiceError::InvalidRequest("error code".to_string()));
+    }
+    if options.old_code.is_some() {
+        if !accepts(
+            context,
+            &change,
+            change.old_code_id,
+            &change.old_email,
+            options.old_code.as_deref(),
+        )
+        .await?
+        {
+            return Err(ServiceError::InvalidRequest("error code".to_string()));
+        }
+        let codes = [change.old_code_id, change.new_code_id];
+        return complete(context, chain, &change, CHANGE_PENDING, &codes, options).await;
+    }
+
+    let mut tx = context.db.begin().await?;
+    let unlock_at: Option<DateTime<Utc>> = sqlx::query_scalar(
+        r#"Update email_change set status = $1, unlock_at = now() + make_interval(secs => $2), updated_at = now() where id = $3 and status = $4 RETURNING unlock_at"#,
+    )
+    .bind(CHANGE_TIME_LOCKED)
+    .bind(context.email_change.time_lock_secs as f64)
+    .bind(change.id)
+    .bind(CHANGE_PENDING)
+    .fetch_optional(&mut *tx)
+    .await?;
+    // the new address confirmed the change, its code is spent
+    if unlock_at.is_none() || !claim_code(&mut tx, change.new_code_id).await? {
+        tx.rollback().await?;
+        return Err(ServiceError::InvalidRequest("error code".to_string()));
+    }
+    tx.commit().await?;
+    Ok(EmailChangeResult {
+        state: EmailChangeState::TimeLocked,
+        unlock_at,
+        binding: None,
+    })
+}
+
+/// Cancels a pending change with the token mailed to the bound address.
+pub async fn cancel_email_change(context: &Context, token: String) -> Result<EmailChangeResult> {
+    let cancelled = sqlx::query(
+        r#"Update email_change set status = $1, updated_at = now() where cancel_token = $2 and status < $3"#,
+    )
+    .bind(CHANGE_CANCELLED)
+    .bind(&token)
+    .bind(CHANGE_COMPLETED)
+    .execute(&context.db)
+    .await?;
+    if cancelled.rows_affected() == 0 {
+        return Err(ServiceError::InvalidRequest(
+            "invalid cancel token".to_string(),
+        ));
+    }
+    Ok(EmailChangeResult {
+        state: EmailChangeState::Cancelled,
+        unlock_at: None,
+        binding: None,
+    })
+}
+
+/// Subject and body of the mail warning the bound address of a time-locked change.
+pub fn time_lock_message(change: &EmailChange, cancel_link: &str) -> (String, String) {
+    let subject = "ioPay AA Wallet Security Alert - Email Change Requested".to_string();
+    let unlock_at = change
+        .unlock_at
+        .map(|unlock_at| unlock_at.to_rfc2822())
+        .unwrap_or_default();
+    let body = format!("Dear User,
+
+A change of the recovery email of your ioPay AA Wallet {} on chain {} to {} was requested without confirming it from this email address.
+
+The change takes effect after {}. If this wasn't you, cancel it before then:
+{}{}
+
+Best Regards,
+ioPay Team", change.account, change.chain_id, mask_email(&change.new_email), unlock_at, cancel_link, change.cancel_token);
+    (subject, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        providers::{MockProvider, Provider},
+        signers::{LocalWallet, Signer},
+        types::Bytes,
+    };
+
+    use crate::{
+        contracts::provider::{FailoverClient, ProviderConfig},
+        service::{
+            code::{generate_code, SendCodeOptions, CODE_QUEUED, CODE_VERIFIED},
+            ownership::issue_challenge,
+            testing::{self, TestDb},
+        },
+    };
+
+    #[test]
+    fn warns_bound_address_of_time_lock() {
+        let change = EmailChange {
+            id: 1,
+            chain_id: 4690,
+            account: "0x8803daf0ab9bad65a56f4d9aeca56085491c299a".to_string(),
+            old_email: "old@test.com".to_string(),
+            new_email: "new@test.com".to_string(),
+            old_code_id: 1,
+            new_code_id: 2,
+            status: CHANGE_TIME_LOCKED,
+            unlock_at: Some(Utc::now()),
+            cancel_token: "cd".repeat(32),
+            old_email_ciphertext: None,
+            old_email_key_version: None,
+            new_email_ciphertext: None,
+            new_email_key_version: None,
+        };
+        let (_, body) = time_lock_message(&change, "https://localhost/cancel?token=");
+
+        assert!(body.contains("n***@test.com"));
+        assert!(!body.contains("new@test.com"));
+        assert!(body.contains(&format!(
+            "https://localhost/cancel?token={}",
+            "cd".repeat(32)
+        )));
+    }
+
+    /// Starts a change of the account of `wallet` to `new_email`, mailing its codes.
+    async fn start(context: &Context, wallet: &LocalWallet, new_email: &str) -> (String, String) {
+        let account = format!("{:?}", wallet.address());
+        let challenge = issue_challenge(context, account.clone(), None)
+            .await
+            .unwrap();
+        let signature = wallet.sign_message(&challenge.message).await.unwrap();
+        let options = ChangeEmailOptions {
+            ownership: Some(OwnershipProof {
+                nonce: challenge.nonce,
+                signature: format!("0x{}", signature),
+            }),
+            ..Default::default()
+        };
+        change_email(context, account.clone(), new_email.to_string(), options)
+            .await
+            .unwrap();
+
+        sqlx::query("update bind_code set status = $1 where status = $2")
+            .bind(CODE_SENT)
+            .bind(CODE_QUEUED)
+            .execute(&context.db)
+            .await
+            .unwrap();
+        let codes: Vec<String> = sqlx::query_scalar(
+            "select c.code from email_change e join bind_code c on c.id in (e.old_code_id, e.new_code_id) where e.account = $1 and e.status = $2 order by c.id = e.new_code_id",
+        )
+        .bind(&account)
+        .bind(CHANGE_PENDING)
+        .fetch_all(&context.db)
+        .await
+        .unwrap();
+        (codes[0].clone(), codes[1].clone())
+    }
+
+    async fn statuses(context: &Context) -> Vec<i16> {
+        sqlx::query_scalar("select status from email_change order by id")
+            .fetch_all(&context.db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn completes_a_change_confirmed_by_both_addresses_or_unlocked() {
+        let Some(db) = TestDb::new().await else {
+            return;
+        };
+        let mock = MockProvider::new();
+        // binding hashes of both completed changes
+        mock.push::<Bytes, _>(Bytes::from([8u8; 32].to_vec()))
+            .unwrap();
+        mock.push::<Bytes, _>(Bytes::from([9u8; 32].to_vec()))
+            .unwrap();
+        let mut chain = testing::chain();
+        chain.provider = Provider::new(
+            FailoverClient::new(
+                vec![("mock".to_string(), Box::new(mock))],
+                ProviderConfig::default(),
+            )
+            .unwrap(),
+        );
+        let context = testing::context(db.pool.clone(), chain.clone());
+
+        let wallet = LocalWallet::new(&mut rand::thread_rng());
+        let account = format!("{:?}", wallet.address());
+        testing::bind_email(&db.pool, &chain, &account, "old@test.com").await;
+        let confirm = |new_code: Option<String>, old_code: Option<String>| {
+            confirm_email_change(
+                &context,
+                account.clone(),
+                ConfirmEmailChangeOptions {
+                    new_code,
+                    old_code,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // a change replaces the pending one
+        start(&context, &wallet, "first@test.com").await;
+        let (old_code, new_code) = start(&context, &wallet, "new@test.com").await;
+        assert_eq!(
+            statuses(&context).await,
+            vec![CHANGE_CANCELLED, CHANGE_PENDING]
+        );
+        assert!(confirm(Some(old_code.clone()), None).await.is_err());
+
+        let completed = confirm(Some(new_code.clone()), Some(old_code.clone()))
+            .await
+            .unwrap();
+        assert_eq!(completed.state, EmailChangeState::Completed);
+        assert!(completed.binding.is_some());
+        assert_eq!(
+            bound_email(&context, &chain, wallet.address())
+                .await
+                .unwrap(),
+            "new@test.com"
+        );
+        // the codes are spent and the change is done
+        assert!(confirm(Some(new_code), Some(old_code)).await.is_err());
+        // other emails can only be bound through another change
+        let unbound = generate_code(
+            &context,
+            account.clone(),
+            "old@test.com".to_string(),
+            SendCodeOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:?}", unbound).contains("use change_email"));
+
+        let (_, new_code) = start(&context, &wallet, "later@test.com").await;
+        let locked = confirm(Some(new_code.clone()), None).await.unwrap();
+        assert_eq!(locked.state, EmailChangeState::TimeLocked);
+        let (status,): (i16,) = sqlx::query_as(
+            "select c.status from email_change e join bind_code c on c.id = e.new_code_id where e.status = $1",
+        )
+        .bind(CHANGE_TIME_LOCKED)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(status, CODE_VERIFIED);
+        // still locked, whatever the codes given
+        let waiting = confirm(Some(new_code), None).await.unwrap();
+        assert_eq!(waiting.state, EmailChangeState::TimeLocked);
+        assert_eq!(waiting.unlock_at, locked.unlock_at);
+
+        sqlx::query("update email_change set unlock_at = now() where status = $1")
+            .bind(CHANGE_TIME_LOCKED)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let unlocked = confirm(None, None).await.unwrap();
+        assert_eq!(unlocked.state, EmailChangeState::Completed);
+        assert_eq!(
+            bound_email(&context, &chain, wallet.address())
+                .await
+                .unwrap(),
+            "later@test.com"
+        );
+
+        let cancel_token: String =
+            sqlx::query_scalar("select cancel_token from email_change order by id desc limit 1")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        // a completed change can't be cancelled
+        assert!(cancel_email_change(&context, cancel_token).await.is_err());
+        start(&context, &wallet, "last@test.com").await;
+        let cancel_token: String =
+            sqlx::query_scalar("select cancel_token from email_change order by id desc limit 1")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        let cancelled = cancel_email_change(&context, cancel_token).await.unwrap();
+        assert_eq!(cancelled.state, EmailChangeState::Cancelled);
+        assert!(confirm(None, None).await.is_err());
+
+        db.drop().await;
+    }
+}
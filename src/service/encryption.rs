@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::PgPool;
+
+use crate::service::{
+    commitment::normalize_email,
+    error::{Result, ServiceError},
+};
+
+const NONCE_LEN: usize = 12;
+/// A wrapped 32 byte data key and its tag.
+const WRAPPED_KEY_LEN: usize = 48;
+
+/// Envelope encryption of stored emails.
+///
+/// Each email is encrypted with AES-256-GCM under a random data key, and the data key under
+/// the key-encryption key of `version`. Rotating a key only rewraps data keys. Lookups go
+/// through an HMAC-SHA256 blind index of the normalized email, whose key never rotates.
+#[derive(Clone)]
+pub struct EmailCipher {
+    keys: HashMap<i16, Key<Aes256Gcm>>,
+    version: i16,
+    index_key: Vec<u8>,
+}
+
+impl std::fmt::Debug for EmailCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmailCipher")
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
+}
+
+fn cipher_error(message: &str) -> ServiceError {
+    ServiceError::DatabaseError(message.to_string())
+}
+
+impl EmailCipher {
+    /// Encrypts with the key of `version`, and decrypts with any key of `keys`.
+    pub fn new(keys: HashMap<i16, [u8; 32]>, version: i16, index_key: Vec<u8>) -> Result<Self> {
+        if !keys.contains_key(&version) {
+            return Err(ServiceError::InvalidRequest(format!(
+                "no email key of version {}",
+                version
+            )));
+        }
+        Ok(EmailCipher {
+            keys: keys
+                .into_iter()
+                .map(|(version, key)| (version, key.into()))
+                .collect(),
+            version,
+            index_key,
+        })
+    }
+
+    /// The version new emails are encrypted with.
+    pub fn version(&self) -> i16 {
+        self.version
+    }
+
+    /// The keyed hash rows are looked up by, the same for every spelling of an email.
+    pub fn blind_index(&self, email: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.index_key)
+            .expect("hmac accepts any key length");
+        mac.update(normalize_email(email).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn kek(&self, version: i16) -> Result<Aes256Gcm> {
+        match self.keys.get(&version) {
+            Some(key) => Ok(Aes256Gcm::new(key)),
+            None => Err(cipher_error("unknown email key version")),
+        }
+    }
+
+    fn wrap(&self, data_key: &Key<Aes256Gcm>) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped = self
+            .kek(self.version)?
+            .encrypt(&nonce, data_key.as_slice())
+            .map_err(|_| cipher_error("wrap email key error"))?;
+        Ok([nonce.as_slice(), &wrapped].concat())
+    }
+
+    fn unwrap(&self, sealed: &[u8], version: i16) -> Result<Key<Aes256Gcm>> {
+        if sealed.len() < NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN {
+            return Err(cipher_error("invalid encrypted email"));
+        }
+        let data_key = self
+            .kek(version)?
+            .decrypt(
+                Nonce::from_slice(&sealed[..NONCE_LEN]),
+                &sealed[NONCE_LEN..NONCE_LEN + WRAPPED_KEY_LEN],
+            )
+            .map_err(|_| cipher_error("unwrap email key error"))?;
+        Ok(*Key::<Aes256Gcm>::from_slice(&data_key))
+    }
+
+    /// Encrypts `email` under the current version: hex of the wrapped data key, then the
+    /// nonce and ciphertext of the email.
+    pub fn seal(&self, email: &str) -> Result<String> {
+        let data_key = Aes256Gcm::generate_key(&mut OsRng);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(&nonce, email.as_bytes())
+            .map_err(|_| cipher_error("encrypt email error"))?;
+        Ok(hex::encode(
+            [&self.wrap(&data_key)?, nonce.as_slice(), &ciphertext].concat(),
+        ))
+    }
+
+    /// Decrypts an email sealed under `version`.
+    pub fn open(&self, sealed: &str, version: i16) -> Result<String> {
+        let sealed = hex::decode(sealed).map_err(|_| cipher_error("invalid encrypted email"))?;
+        let data_key = self.unwrap(&sealed, version)?;
+        let body = &sealed[NONCE_LEN + WRAPPED_KEY_LEN..];
+        let email = Aes256Gcm::new(&data_key)
+            .decrypt(Nonce::from_slice(&body[..NONCE_LEN]), &body[NONCE_LEN..])
+            .map_err(|_| cipher_error("decrypt email error"))?;
+        String::from_utf8(email).map_err(|_| cipher_error("invalid encrypted email"))
+    }
+
+    /// Rewraps the data key of an email sealed under `version` with the current version.
+    pub fn rewrap(&self, sealed: &str, version: i16) -> Result<String> {
+        let sealed = hex::decode(sealed).map_err(|_| cipher_error("invalid encrypted email"))?;
+        let data_key = self.unwrap(&sealed, version)?;
+        Ok(hex::encode(
+            [
+                &self.wrap(&data_key)?,
+                &sealed[NONCE_LEN + WRAPPED_KEY_LEN..],
+            ]
+            .concat(),
+        ))
+    }
+}
+
+/// The columns an email is stored in: the plain text, or an empty plain text column with the
+/// email sealed by `EmailCipher` and its blind index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredEmail {
+    pub email: String,
+    pub ciphertext: Option<String>,
+    pub key_version: Option<i16>,
+    pub index: Option<String>,
+}
+
+impl StoredEmail {
+    /// Seals `email` when a cipher is configured, and keeps it in plain text otherwise.
+    pub fn seal(cipher: Option<&EmailCipher>, email: &str) -> Result<Self> {
+        Ok(match cipher {
+            Some(cipher) => StoredEmail {
+                email: String::new(),
+                ciphertext: Some(cipher.seal(email)?),
+                key_version: Some(cipher.version()),
+                index: Some(cipher.blind_index(email)),
+            },
+            None => StoredEmail {
+                email: email.to_string(),
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// The email of a row stored as `email`, `ciphertext` and `key_version`, decrypted if sealed.
+pub fn reveal_email(
+    cipher: Option<&EmailCipher>,
+    email: &str,
+    ciphertext: Option<&str>,
+    key_version: Option<i16>,
+) -> Result<String> {
+    match (ciphertext, key_version, cipher) {
+        (Some(sealed), Some(version), Some(cipher)) => cipher.open(sealed, version),
+        (Some(_), _, _) => Err(ServiceError::DatabaseError(
+            "email is encrypted but no email key is configured".to_string(),
+        )),
+        _ => Ok(email.to_string()),
+    }
+}
+
+/// Tables storing emails in `email`, `email_ciphertext`, `email_key_version` and
+/// `email_index`. Recovery requests and email changes read theirs from their codes.
+const EMAIL_TABLES: [&str; 3] = ["bind_code", "email_binding", "security_alert"];
+
+/// Encrypts the plaintext emails of every table and rewraps those of older key versions,
+/// returning how many emails were updated.
+pub async fn rotate_email_keys(db: &PgPool, cipher: &EmailCipher) -> Result<u64> {
+    let mut rotated = 0;
+    for table in EMAIL_TABLES {
+        loop {
+            let rows: Vec<(i32, String, Option<String>, Option<i16>)> = sqlx::query_as(&format!(
+                "select id, email, email_ciphertext, email_key_version from {table} where (email_ciphertext is null and email <> '') or email_key_version <> $1 order by id limit 1000",
+            ))
+            .bind(cipher.version())
+            .fetch_all(db)
+            .await?;
+            if rows.is_empty() {
+                break;
+            }
+            for (id, email, ciphertext, version) in rows {
+                let (sealed, index) = match (ciphertext, version) {
+                    (Some(ciphertext), Some(version)) => {
+                        (cipher.rewrap(&ciphertext, version)?, None)
+                    }
+                    _ => (cipher.seal(&email)?, Some(cipher.blind_index(&email))),
+                };
+                let _ = sqlx::query(&format!(
+                    r#"Update {table} set email = '', email_ciphertext = $1, email_key_version = $2, email_index = coalesce($3, email_index) where id = $4"#,
+                ))
+                .bind(&sealed)
+                .bind(cipher.version())
+                .bind(&index)
+                .bind(id)
+                .execute(db)
+                .await?;
+                rotated += 1;
+            }
+        }
+    }
+    Ok(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_keys_without_reencrypting() {
+        let old = EmailCipher::new(HashMap::from([(1, [1u8; 32])]), 1, vec![9]).unwrap();
+        let sealed = old.seal("test@test.com").unwrap();
+        assert_eq!(old.open(&sealed, 1).unwrap(), "test@test.com");
+
+        let new =
+            EmailCipher::new(HashMap::from([(1, [1u8; 32]), (2, [2u8; 32])]), 2, vec![9]).unwrap();
+        let rewrapped = new.rewrap(&sealed, 1).unwrap();
+        assert_eq!(
+            sealed[2 * (NONCE_LEN + WRAPPED_KEY_LEN)..],
+            rewrapped[2 * (NONCE_LEN + WRAPPED_KEY_LEN)..]
+        );
+        assert_eq!(new.open(&rewrapped, 2).unwrap(), "test@test.com");
+        assert!(new.open(&rewrapped, 1).is_err());
+
+        assert_eq!(
+            old.blind_index(" Test@test.com"),
+            new.blind_index("test@test.com")
+        );
+
+        let stored = StoredEmail::seal(Some(&new), "test@test.com").unwrap();
+        assert_eq!(stored.email, "");
+        assert_eq!(
+            reveal_email(
+                Some(&new),
+                &stored.email,
+                stored.ciphertext.as_deref(),
+                stored.key_version
+            )
+            .unwrap(),
+            "test@test.com"
+        );
+        assert!(reveal_email(None, "", stored.ciphertext.as_deref(), stored.key_version).is_err());
+    }
+}
@@ -8,6 +8,9 @@ pub(crate) type Result<T> = std::result::Result<T, ServiceError>;
 pub enum ServiceError {
     DatabaseError(String),
     InvalidRequest(String),
+    QuotaExceeded(String),
+    /// The chain could not be reached or its call failed.
+    ChainError(String),
 }
 
 impl From<sqlx::error::Error> for ServiceError {
@@ -37,6 +40,8 @@ impl<T: Serialize> ToRpcResponseResult for Result<T> {
             Err(err) => match err {
                 ServiceError::DatabaseError(err) => RpcError::internal_error_with(err.to_string()),
                 ServiceError::InvalidRequest(str) => RpcError::internal_error_with(str),
+                ServiceError::QuotaExceeded(str) => RpcError::limit_exceeded(str),
+                ServiceError::ChainError(str) => RpcError::chain_unavailable(str),
             }
             .into(),
         }
@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// How long a finished stream is kept around so late reconnects can replay it.
+const CLOSED_RETENTION: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Pending,
+    Sent,
+    SendFailed,
+    Verified,
+    Expired,
+}
+
+impl DeliveryState {
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, DeliveryState::Verified | DeliveryState::Expired)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryEvent {
+    #[serde(skip)]
+    pub id: u64,
+    pub state: DeliveryState,
+    pub at: DateTime<Utc>,
+}
+
+struct EventStream {
+    history: Vec<DeliveryEvent>,
+    sender: broadcast::Sender<DeliveryEvent>,
+    created_at: Instant,
+    closed_at: Option<Instant>,
+}
+
+impl EventStream {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(16);
+        EventStream {
+            history: Vec::new(),
+            sender,
+            created_at: Instant::now(),
+            closed_at: None,
+        }
+    }
+}
+
+/// A subscription to a request token: events the caller has not seen yet and,
+/// unless the stream already reached a terminal state, a receiver for the rest.
+pub struct Subscription {
+    pub backlog: Vec<DeliveryEvent>,
+    pub receiver: Option<broadcast::Receiver<DeliveryEvent>>,
+}
+
+/// In-process fan-out of code delivery state changes keyed by request token.
+#[derive(Clone, Default)]
+pub struct EventHub {
+    streams: Arc<Mutex<HashMap<String, EventStream>>>,
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the stream of a newly issued request token with its first state.
+    pub fn open(&self, token: &str, state: DeliveryState) {
+        self.streams
+            .lock()
+            .unwrap()
+            .entry(token.to_string())
+            .or_insert_with(EventStream::new);
+        self.publish(token, state);
+    }
+
+    /// Appends to an open stream. Unknown tokens, including streams already pruned, are ignored.
+    pub fn publish(&self, token: &str, state: DeliveryState) {
+        let mut streams = self.streams.lock().unwrap();
+        let stream = match streams.get_mut(token) {
+            Some(stream) if stream.closed_at.is_none() => stream,
+            _ => return,
+        };
+
+        let event = DeliveryEvent {
+            id: stream.history.len() as u64 + 1,
+            state,
+            at: Utc::now(),
+        };
+        stream.history.push(event.clone());
+        if state.is_terminal() {
+            stream.closed_at = Some(Instant::now());
+        }
+        let _ = stream.sender.send(event);
+    }
+
+    pub fn subscribe(&self, token: &str, last_event_id: Option<u64>) -> Option<Subscription> {
+        let streams = self.streams.lock().unwrap();
+        let stream = streams.get(token)?;
+        let last_event_id = last_event_id.unwrap_or_default();
+
+        Some(Subscription {
+            backlog: stream
+                .history
+                .iter()
+                .filter(|event| event.id > last_event_id)
+                .cloned()
+                .collect(),
+            receiver: stream
+                .closed_at
+                .is_none()
+                .then(|| stream.sender.subscribe()),
+        })
+    }
+
+    /// Expires streams older than `max_age` and drops the ones closed long enough ago.
+    pub fn prune(&self, max_age: Duration) {
+        let expired: Vec<String> = {
+            let mut streams = self.streams.lock().unwrap();
+            streams.retain(|_, stream| match stream.closed_at {
+                Some(closed_at) => closed_at.elapsed() < CLOSED_RETENTION,
+                None => true,
+            });
+            streams
+                .iter()
+                .filter(|(_, stream)| {
+                    stream.closed_at.is_none() && stream.created_at.elapsed() > max_age
+                })
+                .map(|(token, _)| token.clone())
+                .collect()
+        };
+
+        for token in expired {
+            self.publish(&token, DeliveryState::Expired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_events_after_last_event_id() {
+        let hub = EventHub::new();
+        hub.open("token", DeliveryState::Pending);
+        hub.publish("token", DeliveryState::Sent);
+
+        let subscription = hub.subscribe("token", Some(1)).unwrap();
+        assert_eq!(subscription.backlog.len(), 1);
+        assert_eq!(subscription.backlog[0].state, DeliveryState::Sent);
+        assert!(subscription.receiver.is_some());
+        assert!(hub.subscribe("unknown", None).is_none());
+    }
+
+    #[test]
+    fn closes_stream_on_terminal_state() {
+        let hub = EventHub::new();
+        hub.open("token", DeliveryState::Pending);
+        hub.publish("token", DeliveryState::Verified);
+        hub.publish("token", DeliveryState::Sent);
+
+        let subscription = hub.subscribe("token", None).unwrap();
+        assert_eq!(subscription.backlog.len(), 2);
+        assert!(subscription.receiver.is_none());
+
+        hub.streams.lock().unwrap().clear();
+        hub.publish("token", DeliveryState::Sent);
+        assert!(hub.subscribe("token", None).is_none());
+    }
+}
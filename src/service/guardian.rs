@@ -0,0 +1,160 @@
+use ethers::types::Address;
+use rand::Rng;
+use tracing::{error, info, warn};
+
+use crate::{
+    contracts::guardian::{get_hash, get_nonce, local_hash},
+    service::{
+        chain::{Chain, ChainRegistry},
+        error::{Result, ServiceError},
+    },
+};
+
+/// How `verify_code` derives the binding hashes it signs.
+#[derive(Debug, Clone)]
+pub struct HashConfig {
+    /// Derive hashes locally on chains that passed the startup self-check.
+    pub local: bool,
+    /// Share of locally derived hashes also compared with the guardian.
+    pub sample_rate: f64,
+}
+
+async fn chain_hash(chain: &Chain, account: Address, email_hash: [u8; 32]) -> Result<[u8; 32]> {
+    get_hash(chain.provider.clone(), chain.guardian, account, email_hash)
+        .await
+        .map_err(|err| {
+            error!(target: "guardian", ?err, chain_id = chain.chain_id, "query guardian hash error");
+            ServiceError::ChainError(err.to_string())
+        })
+}
+
+/// The guardian nonce of `account`, read from the guardian so bindings made without this
+/// service count too.
+async fn chain_nonce(chain: &Chain, account: Address) -> Result<u64> {
+    get_nonce(chain.provider.clone(), chain.guardian, account)
+        .await
+        .map_err(|err| {
+            error!(target: "guardian", ?err, chain_id = chain.chain_id, "query guardian nonce error");
+            ServiceError::ChainError(err.to_string())
+        })
+}
+
+fn alert_mismatch(chain: &Chain, account: Address, local: [u8; 32], on_chain: [u8; 32]) {
+    error!(
+        target: "alert",
+        chain_id = chain.chain_id,
+        guardian = %chain.guardian_address,
+        ?account,
+        local = %hex::encode(local),
+        on_chain = %hex::encode(on_chain),
+        "guardian hash parity mismatch, falling back to on-chain hashes"
+    );
+    chain.set_local_hash(false);
+}
+
+/// Compares the local derivation with the guardian for `account`, at its guardian nonce.
+pub async fn self_check(chain: &Chain, account: Address) -> Result<bool> {
+    let email_hash = rand::thread_rng().gen::<[u8; 32]>();
+    let nonce = chain_nonce(chain, account).await?;
+    let on_chain = chain_hash(chain, account, email_hash).await?;
+    let local = local_hash(chain.chain_id, chain.guardian, account, email_hash, nonce);
+    if local != on_chain {
+        alert_mismatch(chain, account, local, on_chain);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Enables local hashes on every chain that passes [`self_check`].
+pub async fn enable_local_hashes(chains: &ChainRegistry) {
+    for chain in chains.chains() {
+        match self_check(chain, Address::random()).await {
+            Ok(true) => {
+                info!(target: "guardian", chain_id = chain.chain_id, "guardian hash self-check passed");
+                chain.set_local_hash(true);
+            }
+            Ok(false) => {}
+            Err(_) => {
+                warn!(target: "guardian", chain_id = chain.chain_id, "guardian hash self-check skipped, using on-chain hashes");
+            }
+        }
+    }
+}
+
+/// Re-runs [`self_check`] on the chains deriving hashes locally, disabling it on mismatch.
+pub async fn check_parity(chains: &ChainRegistry) {
+    for chain in chains.chains().filter(|chain| chain.uses_local_hash()) {
+        let _ = self_check(chain, Address::random()).await;
+    }
+}
+
+/// The hash `verify_code` signs for binding `email_hash` to `account`, with the guardian nonce
+/// it was derived from when derived locally.
+///
+/// Locally derived hashes are compared with the guardian for a sample of calls;
+/// the on-chain hash wins on mismatch.
+pub async fn binding_hash(
+    config: &HashConfig,
+    chain: &Chain,
+    account: Address,
+    email_hash: [u8; 32],
+) -> Result<([u8; 32], Option<u64>)> {
+    if !config.local || !chain.uses_local_hash() {
+        return Ok((chain_hash(chain, account, email_hash).await?, None));
+    }
+
+    let nonce = chain_nonce(chain, account).await?;
+    let local = local_hash(chain.chain_id, chain.guardian, account, email_hash, nonce);
+    if rand::thread_rng().gen_bool(config.sample_rate) {
+        let on_chain = chain_hash(chain, account, email_hash).await?;
+        if local != on_chain {
+            alert_mismatch(chain, account, local, on_chain);
+            return Ok((on_chain, None));
+        }
+    }
+    Ok((local, Some(nonce)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        contracts::provider::{FailoverClient, ProviderConfig},
+        service::chain::ChainConfig,
+    };
+    use ethers::{
+        providers::{MockProvider, Provider},
+        types::Bytes,
+    };
+
+    #[tokio::test]
+    async fn disables_local_hashes_on_mismatch() {
+        let registry = ChainRegistry::new(
+            vec![ChainConfig {
+                chain_id: 4690,
+                rpc_urls: vec!["http://localhost:8545".to_string()],
+                guardian_address: "0xBf081D23317966eEBD59Bc8EDB593A830F373178".to_string(),
+                ..Default::default()
+            }],
+            None,
+            Some("key".to_string()),
+        )
+        .unwrap();
+        // responses are popped last first: the nonce, then the hash
+        let mock = MockProvider::new();
+        mock.push::<Bytes, _>(Bytes::from(vec![1u8; 32])).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(vec![0u8; 32])).unwrap();
+        let mut chain = registry.get(None).unwrap().clone();
+        chain.provider = Provider::new(
+            FailoverClient::new(
+                vec![("mock".to_string(), Box::new(mock))],
+                ProviderConfig::default(),
+            )
+            .unwrap(),
+        );
+        chain.set_local_hash(true);
+
+        assert!(!self_check(&chain, Address::random()).await.unwrap());
+        assert!(!registry.get(None).unwrap().uses_local_hash());
+    }
+}
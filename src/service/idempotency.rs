@@ -0,0 +1,199 @@
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::{
+    rpc::response::ResponseResult,
+    server::auth::ClientIdentity,
+    service::{
+        error::{Result, ServiceError},
+        ApiRequest,
+    },
+};
+
+/// Hours a stored response can be replayed.
+pub const IDEMPOTENCY_KEY_TTL_HOURS: i32 = 24;
+
+/// Seconds a request holds its key before a retry can take it over.
+pub const IDEMPOTENCY_LEASE_SECS: i64 = 60;
+
+/// Deletes a batch of keys created more than `$1` seconds ago, at most `$2` rows.
+pub(crate) const PURGE_IDEMPOTENCY_KEYS: &str = r#"DELETE FROM idempotency_key WHERE (scope, key) IN (
+    SELECT scope, key FROM idempotency_key WHERE created_at < now() - make_interval(secs => $1) AND locked_until < now()
+    LIMIT $2 FOR UPDATE SKIP LOCKED)"#;
+
+pub enum Replay {
+    /// First use of the key, or a takeover of an abandoned one; the request must run and
+    /// its outcome be recorded with the lease.
+    Fresh(DateTime<Utc>),
+    /// The stored response of the original request.
+    Response(serde_json::Value),
+}
+
+#[derive(sqlx::FromRow)]
+struct IdempotencyRecord {
+    fingerprint: String,
+    response: Option<serde_json::Value>,
+}
+
+/// Keys are scoped to an API client. Anonymous callers can't be told apart, so they can't
+/// use keys.
+pub fn scope(client: &ClientIdentity) -> Result<i32> {
+    match client {
+        ClientIdentity::Anonymous => Err(ServiceError::InvalidRequest(
+            "idempotency keys require an api key".to_string(),
+        )),
+        ClientIdentity::Client(client) => Ok(client.id),
+    }
+}
+
+/// Hash of the request with its idempotency key removed.
+pub fn fingerprint(request: &ApiRequest) -> String {
+    let mut request = request.clone();
+    request.take_idempotency_key();
+    let payload = serde_json::to_vec(&request).unwrap_or_default();
+    hex::encode(Sha256::digest(payload))
+}
+
+/// Claims `key` for `fingerprint`, or returns the response recorded for it. Expired keys,
+/// and keys whose request did not finish within its lease, are claimed again.
+pub async fn begin(db: &PgPool, scope: i32, key: &str, fingerprint: &str) -> Result<Replay> {
+    let claimed: Option<(DateTime<Utc>,)> = sqlx::query_as(
+        r#"INSERT INTO idempotency_key(scope, key, fingerprint, locked_until) VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+        ON CONFLICT (scope, key) DO UPDATE SET fingerprint = excluded.fingerprint, response = null, created_at = now(), locked_until = excluded.locked_until
+        WHERE idempotency_key.created_at < now() - make_interval(hours => $5)
+        OR (idempotency_key.response IS NULL AND idempotency_key.locked_until < now() AND idempotency_key.fingerprint = excluded.fingerprint)
+        RETURNING locked_until"#,
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(fingerprint)
+    .bind(IDEMPOTENCY_LEASE_SECS as f64)
+    .bind(IDEMPOTENCY_KEY_TTL_HOURS)
+    .fetch_optional(db)
+    .await?;
+    if let Some((lease,)) = claimed {
+        return Ok(Replay::Fresh(lease));
+    }
+
+    let record = sqlx::query_as::<_, IdempotencyRecord>(
+        "select fingerprint, response from idempotency_key where scope = $1 and key = $2",
+    )
+    .bind(scope)
+    .bind(key)
+    .fetch_one(db)
+    .await?;
+    if record.fingerprint != fingerprint {
+        warn!(target: "idempotency", ?key, "idempotency key reused with a different payload");
+        return Err(ServiceError::InvalidRequest(
+            "idempotency key reused with different params".to_string(),
+        ));
+    }
+    match record.response {
+        Some(response) => Ok(Replay::Response(response)),
+        None => Err(ServiceError::InvalidRequest(
+            "request with this idempotency key is in progress".to_string(),
+        )),
+    }
+}
+
+/// Stores a successful response for replay, or releases the key so the request can be retried.
+/// Does nothing when another request took the key over after `lease` ran out.
+pub async fn finish(
+    db: &PgPool,
+    scope: i32,
+    key: &str,
+    lease: DateTime<Utc>,
+    result: &ResponseResult,
+) -> Result<()> {
+    let outcome = match result {
+        ResponseResult::Success(response) => {
+            sqlx::query(
+                r#"Update idempotency_key set response = $1 where scope = $2 and key = $3 and locked_until = $4 and response is null"#,
+            )
+            .bind(response)
+            .bind(scope)
+            .bind(key)
+            .bind(lease)
+            .execute(db)
+            .await?
+        }
+        ResponseResult::Error(_) => {
+            sqlx::query(
+                "delete from idempotency_key where scope = $1 and key = $2 and locked_until = $3 and response is null",
+            )
+            .bind(scope)
+            .bind(key)
+            .bind(lease)
+            .execute(db)
+            .await?
+        }
+    };
+    if outcome.rows_affected() == 0 {
+        warn!(target: "idempotency", ?key, "idempotency key was taken over before the request finished");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::testing::TestDb;
+
+    #[tokio::test]
+    async fn replays_responses_and_takes_over_expired_leases() {
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        let db = &test_db.pool;
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+
+        let lease = match begin(db, 1, "key", &a).await.unwrap() {
+            Replay::Fresh(lease) => lease,
+            Replay::Response(_) => panic!("new key replayed"),
+        };
+        // the same request while the first one runs, and another request under the key
+        assert!(begin(db, 1, "key", &a).await.is_err());
+        assert!(begin(db, 1, "key", &b).await.is_err());
+        // keys are scoped per client
+        assert!(matches!(
+            begin(db, 2, "key", &b).await.unwrap(),
+            Replay::Fresh(_)
+        ));
+
+        let response = ResponseResult::Success(serde_json::json!({"issued": true}));
+        finish(db, 1, "key", lease, &response).await.unwrap();
+        match begin(db, 1, "key", &a).await.unwrap() {
+            Replay::Response(replayed) => assert_eq!(replayed["issued"], true),
+            Replay::Fresh(_) => panic!("finished key claimed again"),
+        }
+
+        // a request that outlived its lease loses the key to a retry
+        let stale = match begin(db, 1, "other", &a).await.unwrap() {
+            Replay::Fresh(lease) => lease,
+            Replay::Response(_) => panic!("new key replayed"),
+        };
+        sqlx::query("update idempotency_key set locked_until = now() - interval '1 second' where key = 'other'")
+            .execute(db)
+            .await
+            .unwrap();
+        let retry = match begin(db, 1, "other", &a).await.unwrap() {
+            Replay::Fresh(lease) => lease,
+            Replay::Response(_) => panic!("abandoned key replayed"),
+        };
+        finish(db, 1, "other", stale, &response).await.unwrap();
+        assert!(begin(db, 1, "other", &a).await.is_err());
+
+        // failures release the key
+        let failed = ResponseResult::Error(crate::rpc::error::RpcError::internal_error());
+        finish(db, 1, "other", retry, &failed).await.unwrap();
+        assert!(matches!(
+            begin(db, 1, "other", &a).await.unwrap(),
+            Replay::Fresh(_)
+        ));
+
+        assert!(scope(&ClientIdentity::Anonymous).is_err());
+        test_db.drop().await;
+    }
+}
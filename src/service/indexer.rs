@@ -0,0 +1,545 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use chrono::Utc;
+use ethers::{
+    abi::RawLog,
+    contract::EthLogDecode,
+    providers::Middleware,
+    types::{Address, Filter, Log, H256},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{error, info, warn};
+
+use crate::{
+    contracts::guardian::IEmailGuardianEvents,
+    service::{
+        alert::queue_alert,
+        binding::supersede_bindings,
+        chain::{Chain, ChainRegistry},
+        error::{Result, ServiceError},
+    },
+};
+
+fn default_confirmations() -> u64 {
+    12
+}
+
+fn default_batch_size() -> u64 {
+    1000
+}
+
+fn default_alert_max_age_secs() -> u64 {
+    86400
+}
+
+/// Enables the guardian event indexer of a chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexerConfig {
+    /// First block to index, the latest confirmed one by default.
+    #[serde(default)]
+    pub start_block: Option<u64>,
+    /// Blocks a log must be buried under before it is indexed.
+    #[serde(default = "default_confirmations")]
+    pub confirmations: u64,
+    /// Blocks requested per `eth_getLogs` call.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u64,
+    /// Seconds after which an event is too old to alert about, e.g. while backfilling.
+    #[serde(default = "default_alert_max_age_secs")]
+    pub alert_max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuardianEvent {
+    EmailBound {
+        account: Address,
+        email_hash: [u8; 32],
+    },
+    EmailRemoved {
+        account: Address,
+        email_hash: [u8; 32],
+    },
+    RecoveryStarted {
+        account: Address,
+        new_owner: Address,
+    },
+}
+
+impl GuardianEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GuardianEvent::EmailBound { .. } => "email_bound",
+            GuardianEvent::EmailRemoved { .. } => "email_removed",
+            GuardianEvent::RecoveryStarted { .. } => "recovery_started",
+        }
+    }
+
+    pub fn account(&self) -> Address {
+        match self {
+            GuardianEvent::EmailBound { account, .. }
+            | GuardianEvent::EmailRemoved { account, .. }
+            | GuardianEvent::RecoveryStarted { account, .. } => *account,
+        }
+    }
+}
+
+pub fn parse_log(log: &Log) -> Option<GuardianEvent> {
+    let raw = RawLog {
+        topics: log.topics.clone(),
+        data: log.data.to_vec(),
+    };
+    match IEmailGuardianEvents::decode_log(&raw).ok()? {
+        IEmailGuardianEvents::EmailBoundFilter(event) => Some(GuardianEvent::EmailBound {
+            account: event.account,
+            email_hash: event.email,
+        }),
+        IEmailGuardianEvents::EmailRemovedFilter(event) => Some(GuardianEvent::EmailRemoved {
+            account: event.account,
+            email_hash: event.email,
+        }),
+        IEmailGuardianEvents::RecoveryStartedFilter(event) => {
+            Some(GuardianEvent::RecoveryStarted {
+                account: event.account,
+                new_owner: event.new_owner,
+            })
+        }
+    }
+}
+
+/// What the indexer saw on-chain for a signed binding, reported by `get_status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ChainBinding {
+    /// Signed, but the guardian never bound it.
+    NotSubmitted,
+    Bound {
+        block_number: u64,
+        tx_hash: String,
+    },
+    Removed {
+        block_number: u64,
+        tx_hash: String,
+    },
+}
+
+/// The on-chain state of the binding signed for a `bind_code`.
+pub async fn chain_binding(db: &PgPool, bind_code_id: i32) -> Result<ChainBinding> {
+    let record: Option<(bool, i64, String)> = sqlx::query_as(
+        "select o.bound, o.block_number, o.tx_hash from binding_signature s join onchain_binding o on o.chain_id = s.chain_id and o.guardian_address = s.guardian_address and o.account = lower(s.account) and o.email_hash = s.email_hash where s.bind_code_id = $1 order by s.id desc limit 1",
+    )
+    .bind(bind_code_id)
+    .fetch_optional(db)
+    .await?;
+    Ok(match record {
+        Some((true, block_number, tx_hash)) => ChainBinding::Bound {
+            block_number: block_number as u64,
+            tx_hash,
+        },
+        Some((false, block_number, tx_hash)) => ChainBinding::Removed {
+            block_number: block_number as u64,
+            tx_hash,
+        },
+        None => ChainBinding::NotSubmitted,
+    })
+}
+
+fn chain_error<E: std::fmt::Display>(err: E) -> ServiceError {
+    ServiceError::ChainError(err.to_string())
+}
+
+async fn block_hash(chain: &Chain, number: u64) -> Result<H256> {
+    chain
+        .provider
+        .get_block(number)
+        .await
+        .map_err(chain_error)?
+        .and_then(|block| block.hash)
+        .ok_or_else(|| ServiceError::ChainError(format!("block {} not found", number)))
+}
+
+async fn save_checkpoint(
+    tx: &mut Transaction<'_, Postgres>,
+    chain: &Chain,
+    number: u64,
+    hash: H256,
+) -> Result<()> {
+    let _ = sqlx::query(
+        r#"INSERT INTO indexer_checkpoint(chain_id, guardian_address, block_number, block_hash) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (chain_id, guardian_address) DO UPDATE SET block_number = excluded.block_number, block_hash = excluded.block_hash, updated_at = now()"#,
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .bind(number as i64)
+    .bind(format!("{:?}", hash))
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Drops what was indexed for the guardian of `chain` after block `number`, whose hash is
+/// `hash`, and rebuilds the affected on-chain bindings.
+async fn rewind(db: &PgPool, chain: &Chain, number: u64, hash: H256) -> Result<()> {
+    let mut tx = db.begin().await?;
+    let _ = sqlx::query(
+        r#"WITH affected AS (DELETE FROM onchain_binding WHERE chain_id = $1 AND guardian_address = $2 AND block_number > $3 RETURNING account)
+        INSERT INTO onchain_binding(chain_id, guardian_address, account, email_hash, bound, block_number, tx_hash)
+        SELECT DISTINCT ON (e.account) e.chain_id, e.guardian_address, e.account, e.email_hash, e.kind = 'email_bound', e.block_number, e.tx_hash
+        FROM guardian_event e JOIN affected a ON a.account = e.account
+        WHERE e.chain_id = $1 AND e.guardian_address = $2 AND e.block_number <= $3 AND e.kind IN ('email_bound', 'email_removed')
+        ORDER BY e.account, e.block_number DESC, e.log_index DESC"#,
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .bind(number as i64)
+    .execute(&mut *tx)
+    .await?;
+    let _ = sqlx::query(
+        r#"DELETE FROM guardian_event WHERE chain_id = $1 AND guardian_address = $2 AND block_number > $3"#,
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .bind(number as i64)
+    .execute(&mut *tx)
+    .await?;
+    save_checkpoint(&mut tx, chain, number, hash).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn apply(
+    tx: &mut Transaction<'_, Postgres>,
+    chain: &Chain,
+    log: &Log,
+    event: &GuardianEvent,
+    alert: bool,
+) -> Result<()> {
+    let block_number = log.block_number.unwrap_or_default().as_u64() as i64;
+    let tx_hash = format!("{:?}", log.transaction_hash.unwrap_or_default());
+    let account = format!("{:?}", event.account());
+    let (email_hash, new_owner) = match event {
+        GuardianEvent::EmailBound { email_hash, .. }
+        | GuardianEvent::EmailRemoved { email_hash, .. } => {
+            (Some(format!("0x{}", hex::encode(email_hash))), None)
+        }
+        GuardianEvent::RecoveryStarted { new_owner, .. } => {
+            (None, Some(format!("{:?}", new_owner)))
+        }
+    };
+
+    let inserted: Option<(i32,)> = sqlx::query_as(
+        r#"INSERT INTO guardian_event(chain_id, guardian_address, block_number, tx_hash, log_index, kind, account, email_hash, new_owner) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) ON CONFLICT DO NOTHING RETURNING id"#,
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .bind(block_number)
+    .bind(&tx_hash)
+    .bind(log.log_index.unwrap_or_default().as_u64() as i32)
+    .bind(event.kind())
+    .bind(&account)
+    .bind(&email_hash)
+    .bind(&new_owner)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let event_id = match inserted {
+        Some((id,)) => id,
+        None => return Ok(()),
+    };
+
+    let bound: Option<(String,)> = sqlx::query_as(
+        "select email_hash from onchain_binding where chain_id = $1 and guardian_address = $2 and account = $3 and bound",
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .bind(&account)
+    .fetch_optional(&mut **tx)
+    .await?;
+    if alert {
+        let previous = bound.as_ref().map(|(hash,)| hash.as_str());
+        let recipients: Vec<&str> = match (event, email_hash.as_deref()) {
+            // the new email, and the one it replaced
+            (GuardianEvent::EmailBound { .. }, Some(new)) => std::iter::once(new)
+                .chain(previous.filter(|previous| *previous != new))
+                .collect(),
+            (GuardianEvent::EmailRemoved { .. }, Some(removed)) => vec![removed],
+            // the email of the account being recovered
+            _ => previous.into_iter().collect(),
+        };
+        for email_hash in recipients {
+            queue_alert(
+                tx,
+                chain,
+                event_id,
+                event.kind(),
+                event.account(),
+                email_hash,
+            )
+            .await?;
+        }
+    }
+
+    if let Some(email_hash) = email_hash {
+        let _ = sqlx::query(
+            r#"INSERT INTO onchain_binding(chain_id, guardian_address, account, email_hash, bound, block_number, tx_hash) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (chain_id, guardian_address, account) DO UPDATE SET email_hash = excluded.email_hash, bound = excluded.bound, block_number = excluded.block_number, tx_hash = excluded.tx_hash, updated_at = now()"#,
+        )
+        .bind(chain.chain_id as i64)
+        .bind(&chain.guardian_address)
+        .bind(&account)
+        .bind(&email_hash)
+        .bind(matches!(event, GuardianEvent::EmailBound { .. }))
+        .bind(block_number)
+        .bind(&tx_hash)
+        .execute(&mut **tx)
+        .await?;
+        if let GuardianEvent::EmailBound { .. } = event {
+            supersede_bindings(&mut *tx, chain.chain_id as i64, &account, &email_hash).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Indexes the next batch of confirmed guardian events of `chain`, returning how many were found.
+pub async fn index_chain(db: &PgPool, chain: &Chain, config: &IndexerConfig) -> Result<usize> {
+    let head = chain
+        .provider
+        .get_block_number()
+        .await
+        .map_err(chain_error)?
+        .as_u64();
+    let safe = head.saturating_sub(config.confirmations);
+
+    let checkpoint: Option<(i64, String)> = sqlx::query_as(
+        "select block_number, block_hash from indexer_checkpoint where chain_id = $1 and guardian_address = $2",
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .fetch_optional(db)
+    .await?;
+    let from = match checkpoint {
+        Some((number, hash)) => {
+            let number = number as u64;
+            if format!("{:?}", block_hash(chain, number).await?) != hash.trim() {
+                let rewound = number
+                    .saturating_sub(config.confirmations)
+                    .max(config.start_block.unwrap_or_default());
+                warn!(target: "indexer", chain_id = chain.chain_id, number, rewound, "reorg below confirmation depth, rewinding");
+                rewind(db, chain, rewound, block_hash(chain, rewound).await?).await?;
+                return Ok(0);
+            }
+            number + 1
+        }
+        None => config.start_block.unwrap_or(safe),
+    };
+    if from > safe {
+        return Ok(0);
+    }
+    let to = safe.min(from + config.batch_size.max(1) - 1);
+
+    let filter = Filter::new()
+        .address(chain.guardian)
+        .from_block(from)
+        .to_block(to);
+    let mut logs: Vec<(Log, GuardianEvent)> = chain
+        .provider
+        .get_logs(&filter)
+        .await
+        .map_err(chain_error)?
+        .into_iter()
+        .filter(|log| log.removed != Some(true))
+        .filter_map(|log| parse_log(&log).map(|event| (log, event)))
+        .collect();
+    logs.sort_by_key(|(log, _)| (log.block_number, log.log_index));
+    let hash = block_hash(chain, to).await?;
+    // only alert about recent events, so backfilling old blocks doesn't mail past events
+    let mut timestamps = HashMap::new();
+    for (log, _) in &logs {
+        let number = log.block_number.unwrap_or_default().as_u64();
+        if let Entry::Vacant(entry) = timestamps.entry(number) {
+            let block = chain
+                .provider
+                .get_block(number)
+                .await
+                .map_err(chain_error)?;
+            entry.insert(block.map_or(0, |block| block.timestamp.as_u64()));
+        }
+    }
+    let now = Utc::now().timestamp() as u64;
+
+    let mut tx = db.begin().await?;
+    for (log, event) in &logs {
+        let timestamp = timestamps[&log.block_number.unwrap_or_default().as_u64()];
+        let alert = timestamp + config.alert_max_age_secs >= now;
+        apply(&mut tx, chain, log, event, alert).await?;
+    }
+    save_checkpoint(&mut tx, chain, to, hash).await?;
+    tx.commit().await?;
+    if !logs.is_empty() {
+        info!(target: "indexer", chain_id = chain.chain_id, from, to, events = logs.len(), "indexed guardian events");
+    }
+    Ok(logs.len())
+}
+
+/// Runs one indexing pass over every chain with an indexer.
+pub async fn run_indexers(db: &PgPool, chains: &ChainRegistry) {
+    for chain in chains.chains() {
+        if let Some(config) = &chain.indexer {
+            if let Err(err) = index_chain(db, chain, config).await {
+                error!(target: "indexer", chain_id = chain.chain_id, ?err, "index guardian events error");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        abi::{encode, Token},
+        contract::EthEvent,
+        types::Bytes,
+        utils::keccak256,
+    };
+
+    use crate::{
+        contracts::guardian::{EmailBoundFilter, RecoveryStartedFilter},
+        service::testing::{self, TestDb},
+    };
+
+    #[test]
+    fn parses_guardian_logs() {
+        let account: Address = "0x8803DAF0AB9Bad65a56F4D9AEcA56085491C299A"
+            .parse()
+            .unwrap();
+        let email_hash = keccak256("test@test.com");
+
+        let bound = Log {
+            topics: vec![
+                EmailBoundFilter::signature(),
+                H256::from(account),
+                H256::from(email_hash),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_log(&bound),
+            Some(GuardianEvent::EmailBound {
+                account,
+                email_hash
+            })
+        );
+
+        let new_owner = Address::repeat_byte(1);
+        let recovery = Log {
+            topics: vec![RecoveryStartedFilter::signature(), H256::from(account)],
+            data: Bytes::from(encode(&[Token::Address(new_owner)])),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_log(&recovery).map(|event| event.kind()),
+            Some("recovery_started")
+        );
+
+        let unknown = Log {
+            topics: vec![H256::repeat_byte(9)],
+            ..Default::default()
+        };
+        assert_eq!(parse_log(&unknown), None);
+    }
+
+    fn log_at(block_number: u64, log_index: u64) -> Log {
+        Log {
+            block_number: Some(block_number.into()),
+            transaction_hash: Some(H256::from_low_u64_be(block_number)),
+            log_index: Some(log_index.into()),
+            ..Default::default()
+        }
+    }
+
+    async fn onchain(db: &PgPool, chain: &Chain) -> Vec<(String, i64)> {
+        sqlx::query_as(
+            "select email_hash, block_number from onchain_binding where chain_id = $1 and guardian_address = $2",
+        )
+        .bind(chain.chain_id as i64)
+        .bind(&chain.guardian_address)
+        .fetch_all(db)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rewinds_the_bindings_of_its_guardian() {
+        let Some(db) = TestDb::new().await else {
+            return;
+        };
+        let chain = testing::chain();
+        let mut other = testing::chain();
+        other.guardian_address = format!("{:?}", Address::repeat_byte(2));
+        let account = Address::repeat_byte(1);
+        let bound = |email: &str| GuardianEvent::EmailBound {
+            account,
+            email_hash: keccak256(email),
+        };
+        let hex_hash = |email: &str| format!("0x{}", hex::encode(keccak256(email)));
+
+        let mut tx = db.pool.begin().await.unwrap();
+        apply(
+            &mut tx,
+            &chain,
+            &log_at(10, 0),
+            &bound("old@test.com"),
+            false,
+        )
+        .await
+        .unwrap();
+        apply(
+            &mut tx,
+            &chain,
+            &log_at(20, 0),
+            &bound("new@test.com"),
+            false,
+        )
+        .await
+        .unwrap();
+        apply(
+            &mut tx,
+            &other,
+            &log_at(20, 0),
+            &bound("other@test.com"),
+            false,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(
+            onchain(&db.pool, &chain).await,
+            vec![(hex_hash("new@test.com"), 20)]
+        );
+
+        rewind(&db.pool, &chain, 15, H256::zero()).await.unwrap();
+        assert_eq!(
+            onchain(&db.pool, &chain).await,
+            vec![(hex_hash("old@test.com"), 10)]
+        );
+        assert_eq!(
+            onchain(&db.pool, &other).await,
+            vec![(hex_hash("other@test.com"), 20)]
+        );
+        let (events,): (i64,) =
+            sqlx::query_as("select count(*) from guardian_event where block_number > 15")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(events, 1);
+        let (checkpoint,): (i64,) = sqlx::query_as(
+            "select block_number from indexer_checkpoint where guardian_address = $1",
+        )
+        .bind(&chain.guardian_address)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(checkpoint, 15);
+
+        db.drop().await;
+    }
+}
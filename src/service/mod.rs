@@ -1,31 +1,130 @@
+pub mod account;
+pub mod alert;
+pub mod binding;
+pub mod chain;
+pub mod challenge;
 pub mod code;
+pub mod commitment;
 pub mod email;
+pub mod email_change;
+pub mod encryption;
 pub mod error;
+pub mod events;
+pub mod guardian;
+pub mod idempotency;
+pub mod indexer;
+pub mod ownership;
+pub mod quota;
+pub mod recovery;
+pub mod relayer;
+pub mod retention;
+pub mod schema;
 pub mod serde_helpers;
+pub mod signature;
+pub mod status;
+pub mod store;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod user_operation;
 pub mod verify;
 
-use ethers::providers::{Http, Provider};
+use std::sync::Arc;
+
 use sqlx::PgPool;
-use tracing::trace;
+use tracing::{error, trace};
 
 use self::error::ToRpcResponseResult;
-use crate::{rpc::response::ResponseResult, server::handler::RpcHandler};
+use crate::{
+    rpc::response::ResponseResult,
+    server::{auth::ClientIdentity, handler::RpcHandler},
+};
 
-#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "method", content = "params")]
 pub enum ApiRequest {
     #[serde(rename = "send_code")]
-    SendCode(String, String),
+    SendCode(String, String, #[serde(default)] code::SendCodeOptions),
     #[serde(rename = "verify_code")]
-    VerifyCode(String, String, String),
+    VerifyCode(
+        String,
+        String,
+        String,
+        #[serde(default)] verify::VerifyCodeOptions,
+    ),
+    #[serde(rename = "get_status")]
+    GetStatus(String, String, #[serde(default)] status::StatusOptions),
+    #[serde(rename = "get_challenge", with = "serde_helpers::empty_params")]
+    GetChallenge(()),
+    #[serde(rename = "get_ownership_challenge")]
+    GetOwnershipChallenge(String, #[serde(default)] Option<u64>),
+    #[serde(rename = "get_signature")]
+    GetSignature(
+        String,
+        String,
+        String,
+        #[serde(default)] Option<u64>,
+        #[serde(default)] Option<account::CounterfactualAccount>,
+    ),
+    #[serde(rename = "get_account_address")]
+    GetAccountAddress(
+        account::CounterfactualAccount,
+        #[serde(default)] Option<u64>,
+    ),
+    #[serde(rename = "freeze_account")]
+    FreezeAccount(String),
+    #[serde(rename = "start_recovery")]
+    StartRecovery(
+        String,
+        String,
+        #[serde(default)] recovery::StartRecoveryOptions,
+    ),
+    #[serde(rename = "approve_recovery")]
+    ApproveRecovery(
+        String,
+        String,
+        #[serde(default)] recovery::ApproveRecoveryOptions,
+    ),
+    #[serde(rename = "change_email")]
+    ChangeEmail(
+        String,
+        String,
+        #[serde(default)] email_change::ChangeEmailOptions,
+    ),
+    #[serde(rename = "confirm_email_change")]
+    ConfirmEmailChange(
+        String,
+        #[serde(default)] email_change::ConfirmEmailChangeOptions,
+    ),
+    #[serde(rename = "cancel_email_change")]
+    CancelEmailChange(String),
+}
+
+impl ApiRequest {
+    /// Removes and returns the idempotency key of methods that accept one.
+    pub fn take_idempotency_key(&mut self) -> Option<String> {
+        match self {
+            ApiRequest::SendCode(_, _, options) => options.idempotency_key.take(),
+            ApiRequest::VerifyCode(_, _, _, options) => options.idempotency_key.take(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Context {
     pub db: PgPool,
-    pub provider: Provider<Http>,
-    pub guardian_address: String,
-    pub signer: String,
+    /// Codes mailed by `send_code`, in the store picked by `CODE_STORE`.
+    pub codes: Arc<dyn store::CodeStore>,
+    pub chains: chain::ChainRegistry,
+    pub events: events::EventHub,
+    pub challenge: Option<challenge::ChallengeGate>,
+    pub ownership: ownership::OwnershipConfig,
+    pub guardian_hash: guardian::HashConfig,
+    pub relayer: Option<relayer::Relayer>,
+    pub recovery: recovery::RecoveryConfig,
+    pub email_change: email_change::EmailChangeConfig,
+    /// Seals the emails of bindings and alerts, `codes` seals its own.
+    pub email_cipher: Option<encryption::EmailCipher>,
 }
 
 #[derive(Clone)]
@@ -41,13 +140,71 @@ impl HttpRpcHandler {
     pub async fn execute(&self, request: ApiRequest) -> ResponseResult {
         trace!(target: "rpc::api", "executing eth request");
         match request {
-            ApiRequest::SendCode(account, email) => {
-                code::generate_code(&self.context.db, account, email)
+            ApiRequest::SendCode(account, email, options) => {
+                code::generate_code(&self.context, account, email, options)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::VerifyCode(account, email, code, options) => {
+                verify::verify_code(&self.context, account, email, code, options)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::GetStatus(account, email, options) => {
+                status::get_status(&self.context, account, email, options)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::GetChallenge(()) => challenge::get_challenge(&self.context)
+                .await
+                .to_rpc_result(),
+            ApiRequest::GetOwnershipChallenge(account, chain_id) => {
+                ownership::issue_challenge(&self.context, account, chain_id)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::GetSignature(account, nonce, signature, chain_id, counterfactual) => {
+                signature::get_signature(
+                    &self.context,
+                    account,
+                    nonce,
+                    signature,
+                    chain_id,
+                    counterfactual,
+                )
+                .await
+                .to_rpc_result()
+            }
+            ApiRequest::GetAccountAddress(account, chain_id) => {
+                account::get_account_address(&self.context, account, chain_id)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::FreezeAccount(token) => alert::freeze_account(&self.context, token)
+                .await
+                .to_rpc_result(),
+            ApiRequest::StartRecovery(account, new_owner, options) => {
+                recovery::start_recovery(&self.context, account, new_owner, options)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::ApproveRecovery(account, code, options) => {
+                recovery::approve_recovery(&self.context, account, code, options)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::ChangeEmail(account, new_email, options) => {
+                email_change::change_email(&self.context, account, new_email, options)
+                    .await
+                    .to_rpc_result()
+            }
+            ApiRequest::ConfirmEmailChange(account, options) => {
+                email_change::confirm_email_change(&self.context, account, options)
                     .await
                     .to_rpc_result()
             }
-            ApiRequest::VerifyCode(account, email, code) => {
-                verify::verify_code(&self.context, account, email, code)
+            ApiRequest::CancelEmailChange(token) => {
+                email_change::cancel_email_change(&self.context, token)
                     .await
                     .to_rpc_result()
             }
@@ -59,7 +216,122 @@ impl HttpRpcHandler {
 impl RpcHandler for HttpRpcHandler {
     type Request = ApiRequest;
 
-    async fn on_request(&self, request: Self::Request) -> ResponseResult {
-        self.execute(request).await
+    async fn on_request(&self, request: Self::Request, client: &ClientIdentity) -> ResponseResult {
+        if let ClientIdentity::Client(client) = client {
+            if let Err(err) = quota::consume(&self.context.db, client).await {
+                return Err::<(), _>(err).to_rpc_result();
+            }
+        }
+
+        let mut request = request;
+        let key = match request.take_idempotency_key() {
+            Some(key) => key,
+            None => return self.execute(request).await,
+        };
+        let scope = match idempotency::scope(client) {
+            Ok(scope) => scope,
+            Err(err) => return Err::<(), _>(err).to_rpc_result(),
+        };
+        let fingerprint = idempotency::fingerprint(&request);
+        let lease = match idempotency::begin(&self.context.db, scope, &key, &fingerprint).await {
+            Ok(idempotency::Replay::Fresh(lease)) => lease,
+            Ok(idempotency::Replay::Response(response)) => {
+                return ResponseResult::Success(response)
+            }
+            Err(err) => return Err::<(), _>(err).to_rpc_result(),
+        };
+
+        let result = self.execute(request).await;
+        if let Err(err) = idempotency::finish(&self.context.db, scope, &key, lease, &result).await {
+            error!(target: "rpc::api", ?err, "failed to record idempotent response");
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_deserialize_optional_trailing_params() {
+        let req: ApiRequest = serde_json::from_value(serde_json::json!({
+            "method": "send_code",
+            "params": ["0x01", "test@test.com"]
+        }))
+        .unwrap();
+        assert_eq!(
+            req,
+            ApiRequest::SendCode(
+                "0x01".to_string(),
+                "test@test.com".to_string(),
+                Default::default()
+            )
+        );
+
+        let req: ApiRequest = serde_json::from_value(serde_json::json!({
+            "method": "send_code",
+            "params": ["0x01", "test@test.com", {"captcha_token": "token"}]
+        }))
+        .unwrap();
+        assert!(
+            matches!(req, ApiRequest::SendCode(_, _, options) if options.captcha_token.as_deref() == Some("token"))
+        );
+
+        let req: ApiRequest = serde_json::from_value(serde_json::json!({
+            "method": "get_challenge",
+            "params": null
+        }))
+        .unwrap();
+        assert_eq!(req, ApiRequest::GetChallenge(()));
+
+        let req: ApiRequest = serde_json::from_value(serde_json::json!({
+            "method": "get_account_address",
+            "params": [{
+                "factory": "0x9406cc6185a346906296840746125a0e44976454",
+                "owner": "0x8803daf0ab9bad65a56f4d9aeca56085491c299a",
+                "salt": "0x0"
+            }]
+        }))
+        .unwrap();
+        assert!(
+            matches!(req, ApiRequest::GetAccountAddress(account, None) if account.salt.is_zero())
+        );
+    }
+
+    #[test]
+    fn fingerprint_ignores_idempotency_key() {
+        let mut options = verify::VerifyCodeOptions {
+            idempotency_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        let with_key = ApiRequest::VerifyCode(
+            "0x01".to_string(),
+            "test@test.com".to_string(),
+            "123456".to_string(),
+            options.clone(),
+        );
+        options.idempotency_key = None;
+        let without_key = ApiRequest::VerifyCode(
+            "0x01".to_string(),
+            "test@test.com".to_string(),
+            "123456".to_string(),
+            options.clone(),
+        );
+        let other_code = ApiRequest::VerifyCode(
+            "0x01".to_string(),
+            "test@test.com".to_string(),
+            "654321".to_string(),
+            options,
+        );
+
+        assert_eq!(
+            idempotency::fingerprint(&with_key),
+            idempotency::fingerprint(&without_key)
+        );
+        assert_ne!(
+            idempotency::fingerprint(&with_key),
+            idempotency::fingerprint(&other_code)
+        );
     }
 }
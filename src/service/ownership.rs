@@ -0,0 +1,258 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use ethers::{
+    types::{Address, Bytes, Signature},
+    utils::{hash_message, to_checksum},
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{
+    contracts::account::{is_contract, is_valid_signature},
+    service::{
+        account::{check_counterfactual, CounterfactualAccount},
+        chain::Chain,
+        error::{Result, ServiceError},
+        Context,
+    },
+};
+
+/// Seconds an ownership nonce stays valid.
+pub const NONCE_TTL_SECS: i64 = 300;
+
+const STATEMENT: &str = "Prove ownership of this account to the email binder.";
+
+/// Parameters of the EIP-4361 messages this service asks accounts to sign.
+#[derive(Debug, Clone)]
+pub struct OwnershipConfig {
+    pub domain: String,
+    pub uri: String,
+    /// Whether `send_code` requires an ownership proof.
+    pub required: bool,
+}
+
+/// An EIP-4361 message the account has to sign with `personal_sign`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnershipChallenge {
+    pub nonce: String,
+    pub message: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A signature over the message of an [`OwnershipChallenge`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnershipProof {
+    pub nonce: String,
+    pub signature: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct OwnershipNonce {
+    nonce: String,
+    chain_id: i64,
+    issued_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+pub fn ownership_message(
+    config: &OwnershipConfig,
+    chain_id: u64,
+    account: Address,
+    nonce: &str,
+    issued_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+) -> String {
+    format!(
+        "{domain} wants you to sign in with your Ethereum account:\n\
+        {address}\n\
+        \n\
+        {statement}\n\
+        \n\
+        URI: {uri}\n\
+        Version: 1\n\
+        Chain ID: {chain_id}\n\
+        Nonce: {nonce}\n\
+        Issued At: {issued_at}\n\
+        Expiration Time: {expires_at}",
+        domain = config.domain,
+        address = to_checksum(&account, None),
+        statement = STATEMENT,
+        uri = config.uri,
+        chain_id = chain_id,
+        nonce = nonce,
+        issued_at = issued_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        expires_at = expires_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+    )
+}
+
+pub(crate) fn parse_address(account: &str) -> Result<Address> {
+    account
+        .parse::<Address>()
+        .map_err(|_| ServiceError::InvalidRequest("invalid account".to_string()))
+}
+
+pub async fn issue_challenge(
+    context: &Context,
+    account: String,
+    chain_id: Option<u64>,
+) -> Result<OwnershipChallenge> {
+    let address = parse_address(&account)?;
+    let chain = context.chains.get(chain_id)?;
+
+    let nonce: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(17)
+        .map(char::from)
+        .collect();
+    let record = sqlx::query_as::<_, OwnershipNonce>(
+        r#"INSERT INTO ownership_nonce(nonce, account, chain_id, expires_at) VALUES ($1, $2, $3, now() + make_interval(secs => $4)) RETURNING nonce, chain_id, issued_at, expires_at"#,
+    )
+    .bind(&nonce)
+    .bind(format!("{:?}", address))
+    .bind(chain.chain_id as i64)
+    .bind(NONCE_TTL_SECS as f64)
+    .fetch_one(&context.db)
+    .await?;
+
+    Ok(OwnershipChallenge {
+        message: ownership_message(
+            &context.ownership,
+            chain.chain_id,
+            address,
+            &record.nonce,
+            record.issued_at,
+            record.expires_at,
+        ),
+        nonce: record.nonce,
+        expires_at: record.expires_at,
+    })
+}
+
+/// Checks a `personal_sign` signature of `account` over `message`, falling back to
+/// ERC-1271 `isValidSignature` for smart accounts. Accounts that are not deployed yet
+/// are checked against their `owner`, when known.
+pub async fn check_signature(
+    chain: &Chain,
+    account: Address,
+    message: &str,
+    signature: &str,
+    owner: Option<Address>,
+) -> Result<bool> {
+    let signature: Bytes = signature
+        .parse()
+        .map_err(|_| ServiceError::InvalidRequest("invalid signature".to_string()))?;
+
+    let signer = Signature::try_from(signature.as_ref())
+        .ok()
+        .and_then(|signature| signature.recover(message).ok());
+    if signer == Some(account) {
+        return Ok(true);
+    }
+
+    let provider = chain.provider.clone();
+    match is_contract(provider.clone(), account).await {
+        Ok(true) => Ok(
+            is_valid_signature(provider, account, hash_message(message).0, signature)
+                .await
+                .unwrap_or(false),
+        ),
+        Ok(false) => Ok(owner.is_some() && signer == owner),
+        Err(err) => {
+            error!(target: "ownership", ?err, "query account code error");
+            Err(ServiceError::ChainError(err.to_string()))
+        }
+    }
+}
+
+/// Checks that the proof's message was signed by `account` on `chain`, or by the owner of a
+/// `counterfactual` account that is not deployed yet, and only then consumes its nonce.
+pub async fn verify_ownership(
+    context: &Context,
+    chain: &Chain,
+    account: &str,
+    proof: &OwnershipProof,
+    counterfactual: Option<&CounterfactualAccount>,
+) -> Result<()> {
+    let address = parse_address(account)?;
+    if let Some(counterfactual) = counterfactual {
+        check_counterfactual(chain, address, counterfactual)?;
+    }
+
+    let record = sqlx::query_as::<_, OwnershipNonce>(
+        r#"select nonce, chain_id, issued_at, expires_at from ownership_nonce where nonce = $1 and account = $2 and chain_id = $3 and used_at is null and expires_at > now()"#,
+    )
+    .bind(&proof.nonce)
+    .bind(format!("{:?}", address))
+    .bind(chain.chain_id as i64)
+    .fetch_optional(&context.db)
+    .await?
+    .ok_or_else(|| ServiceError::InvalidRequest("invalid or expired nonce".to_string()))?;
+
+    let message = ownership_message(
+        &context.ownership,
+        record.chain_id as u64,
+        address,
+        &record.nonce,
+        record.issued_at,
+        record.expires_at,
+    );
+    let owner = counterfactual.map(|counterfactual| counterfactual.owner);
+    if !check_signature(chain, address, &message, &proof.signature, owner).await? {
+        warn!(target: "ownership", ?account, "ownership signature mismatch");
+        return Err(ServiceError::InvalidRequest(
+            "invalid signature".to_string(),
+        ));
+    }
+
+    // a valid signature uses the nonce, unless a concurrent request used it first
+    let consumed = sqlx::query(
+        r#"Update ownership_nonce set used_at = now() where nonce = $1 and used_at is null RETURNING nonce"#,
+    )
+    .bind(&record.nonce)
+    .fetch_optional(&context.db)
+    .await?;
+    if consumed.is_none() {
+        return Err(ServiceError::InvalidRequest(
+            "invalid or expired nonce".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+
+    #[tokio::test]
+    async fn formats_eip4361_message() {
+        let config = OwnershipConfig {
+            domain: "email-binder.testnet.iotex.io".to_string(),
+            uri: "https://email-binder.testnet.iotex.io".to_string(),
+            required: true,
+        };
+        let wallet = LocalWallet::new(&mut rand::thread_rng());
+        let issued_at = DateTime::parse_from_rfc3339("2023-09-01T08:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let message = ownership_message(
+            &config,
+            4690,
+            wallet.address(),
+            "abcdefgh12345678",
+            issued_at,
+            issued_at + chrono::Duration::seconds(NONCE_TTL_SECS),
+        );
+
+        assert_eq!(
+            message,
+            format!(
+                "email-binder.testnet.iotex.io wants you to sign in with your Ethereum account:\n{}\n\n{}\n\nURI: https://email-binder.testnet.iotex.io\nVersion: 1\nChain ID: 4690\nNonce: abcdefgh12345678\nIssued At: 2023-09-01T08:00:00Z\nExpiration Time: 2023-09-01T08:05:00Z",
+                to_checksum(&wallet.address(), None),
+                STATEMENT
+            )
+        );
+        let signature = wallet.sign_message(&message).await.unwrap();
+        assert_eq!(signature.recover(message).unwrap(), wallet.address());
+    }
+}
@@ -0,0 +1,64 @@
+use sqlx::PgPool;
+
+use crate::{
+    server::auth::ApiClient,
+    service::error::{Result, ServiceError},
+};
+
+/// Seconds usage counters are kept, past the longest quota period.
+pub const USAGE_RETENTION_SECS: i64 = 2 * 86400;
+
+/// Deletes a batch of usage counters older than `$1` seconds, at most `$2` rows.
+pub(crate) const PURGE_USAGE: &str = r#"DELETE FROM api_client_usage WHERE (client_id, period, period_start) IN (
+    SELECT client_id, period, period_start FROM api_client_usage WHERE period_start < now() - make_interval(secs => $1)
+    LIMIT $2 FOR UPDATE SKIP LOCKED)"#;
+
+async fn increment(db: &PgPool, client_id: i32, period: &str) -> Result<i32> {
+    let (count,): (i32,) = sqlx::query_as(
+        r#"INSERT INTO api_client_usage(client_id, period, period_start, count) VALUES ($1, $2, date_trunc($2, now()), 1)
+        ON CONFLICT (client_id, period, period_start) DO UPDATE SET count = api_client_usage.count + 1
+        RETURNING count"#,
+    )
+    .bind(client_id)
+    .bind(period)
+    .fetch_one(db)
+    .await?;
+    Ok(count)
+}
+
+/// Fails once `count` calls of a period passed its `quota`.
+fn check(count: i32, quota: i32, message: &str) -> Result<()> {
+    if count > quota {
+        return Err(ServiceError::QuotaExceeded(message.to_string()));
+    }
+    Ok(())
+}
+
+/// Counts a call against the client's per-minute and per-day quotas.
+pub async fn consume(db: &PgPool, client: &ApiClient) -> Result<()> {
+    check(
+        increment(db, client.id, "minute").await?,
+        client.quota_per_minute,
+        "per minute quota exceeded",
+    )?;
+    check(
+        increment(db, client.id, "day").await?,
+        client.quota_per_day,
+        "daily quota exceeded",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_calls_over_quota() {
+        assert!(check(1, 1, "per minute quota exceeded").is_ok());
+        assert!(matches!(
+            check(2, 1, "per minute quota exceeded"),
+            Err(ServiceError::QuotaExceeded(_))
+        ));
+        assert!(check(1, 0, "daily quota exceeded").is_err());
+    }
+}
@@ -0,0 +1,585 @@
+use chrono::{DateTime, Duration, Utc};
+use ethers::signers::{LocalWallet, Signer};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{
+    contracts::guardian::get_recovery_hash,
+    service::{
+        alert::ensure_not_frozen,
+        challenge::{verify_challenge, PowSolution},
+        code::{
+            claim_code, find_sent_code, generate_digits, generate_request_token, mask_email,
+            SendCodeResult, CODE_EXPIRY_SECS, CODE_PURPOSE_RECOVERY, CODE_QUEUED,
+        },
+        encryption::{reveal_email, EmailCipher, StoredEmail},
+        error::{Result, ServiceError},
+        events::DeliveryState,
+        ownership::parse_address,
+        signature::bound_email,
+        Context,
+    },
+};
+
+pub const RECOVERY_PENDING: i16 = 0;
+pub const RECOVERY_APPROVED: i16 = 1;
+pub const RECOVERY_CANCELLED: i16 = 2;
+
+/// Seconds a mailer holds a recovery request before another one may mail it.
+pub const RECOVERY_CLAIM_LEASE_SECS: i64 = 300;
+
+/// Deletes a batch of recovery requests created more than `$1` seconds ago, at most `$2` rows.
+pub(crate) const PURGE_RECOVERY_REQUESTS: &str = r#"DELETE FROM recovery_request WHERE id IN (
+    SELECT id FROM recovery_request WHERE created_at < now() - make_interval(secs => $1)
+    LIMIT $2 FOR UPDATE SKIP LOCKED)"#;
+
+/// Safeguards of the recovery flow.
+#[derive(Debug, Clone)]
+pub struct RecoveryConfig {
+    /// Seconds after an approved recovery during which the account can't start another one.
+    pub cooldown_secs: i64,
+    /// Seconds after `start_recovery` before the code approves it, so the owner of the bound
+    /// email has time to freeze the account.
+    pub delay_secs: i64,
+    /// Wrong codes after which the pending requests of an account are cancelled.
+    pub max_attempts: i16,
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        RecoveryConfig {
+            cooldown_secs: 86400,
+            delay_secs: 3600,
+            max_attempts: 5,
+        }
+    }
+}
+
+/// A recovery request with the `bind_code` row holding its code.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RecoveryRequest {
+    pub id: i32,
+    pub chain_id: i64,
+    pub account: String,
+    pub new_owner: String,
+    pub bind_code_id: i32,
+    pub email: String,
+    /// The email sealed by `EmailCipher`, `email` is then empty until revealed.
+    #[sqlx(default)]
+    pub email_ciphertext: Option<String>,
+    #[sqlx(default)]
+    pub email_key_version: Option<i16>,
+    pub code: String,
+    pub code_status: i16,
+    pub status: i16,
+    pub request_token: String,
+    /// Token of the link freezing the account, mailed with the code.
+    pub freeze_token: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl RecoveryRequest {
+    /// Decrypts the email of a request whose code stores it encrypted.
+    pub(crate) fn reveal(self, cipher: Option<&EmailCipher>) -> Result<Self> {
+        let email = reveal_email(
+            cipher,
+            &self.email,
+            self.email_ciphertext.as_deref(),
+            self.email_key_version,
+        )?;
+        Ok(RecoveryRequest { email, ..self })
+    }
+}
+
+/// Optional trailing parameter of `start_recovery`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StartRecoveryOptions {
+    pub pow: Option<PowSolution>,
+    pub captcha_token: Option<String>,
+    pub chain_id: Option<u64>,
+}
+
+/// Optional trailing parameter of `approve_recovery`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApproveRecoveryOptions {
+    pub chain_id: Option<u64>,
+}
+
+/// The guardian signer's approval of moving `account` to `new_owner`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryApproval {
+    pub signature: String,
+    pub chain_id: u64,
+    pub guardian: String,
+    pub new_owner: String,
+}
+
+/// Columns of a [`RecoveryRequest`], selected from `recovery_request r` joined with its
+/// `bind_code c`.
+pub(crate) const RECOVERY_COLUMNS: &str = "r.id, r.chain_id, r.account, r.new_owner, r.bind_code_id, c.email, c.email_ciphertext, c.email_key_version, c.code, c.status as code_status, r.status, c.request_token, r.freeze_token, r.created_at";
+
+/// When the code of `request` starts and stops approving it.
+fn approval_window(
+    request: &RecoveryRequest,
+    config: &RecoveryConfig,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    let approvable_at = request.created_at + Duration::seconds(config.delay_secs);
+    (
+        approvable_at,
+        approvable_at + Duration::seconds(CODE_EXPIRY_SECS),
+    )
+}
+
+fn started(request: &RecoveryRequest, config: &RecoveryConfig, issued: bool) -> SendCodeResult {
+    let expires_at = approval_window(request, config).1;
+    SendCodeResult {
+        request_id: request.request_token.clone(),
+        issued,
+        expires_at,
+        // the request for this new owner is reused until it expires
+        resend_available_at: expires_at,
+        email: mask_email(&request.email),
+        chain_id: Some(request.chain_id as u64),
+    }
+}
+
+/// Mails a code to the email bound to `account` to approve moving it to `new_owner`.
+///
+/// Pending requests for other new owners are left alone: only the bound email picks one, by
+/// approving it with its code.
+pub async fn start_recovery(
+    context: &Context,
+    account: String,
+    new_owner: String,
+    options: StartRecoveryOptions,
+) -> Result<SendCodeResult> {
+    let chain = context.chains.get(options.chain_id)?;
+    let address = parse_address(&account)?;
+    let account = format!("{:?}", address);
+    let new_owner = format!("{:?}", parse_address(&new_owner)?);
+    ensure_not_frozen(&context.db, chain, address).await?;
+
+    verify_challenge(
+        context,
+        options.pow.as_ref(),
+        options.captcha_token.as_deref(),
+    )
+    .await?;
+
+    let (cooling,): (bool,) = sqlx::query_as(
+        "select exists(select 1 from recovery_request where chain_id = $1 and account = $2 and status = $3 and approved_at > now() - make_interval(secs => $4))",
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&account)
+    .bind(RECOVERY_APPROVED)
+    .bind(context.recovery.cooldown_secs as f64)
+    .fetch_one(&context.db)
+    .await?;
+    if cooling {
+        return Err(ServiceError::InvalidRequest(
+            "account was recovered recently, try again later".to_string(),
+        ));
+    }
+
+    let email = bound_email(context, chain, address).await?;
+
+    let pending = sqlx::query_as::<_, RecoveryRequest>(&format!(
+        "select {} from recovery_request r join bind_code c on c.id = r.bind_code_id where r.chain_id = $1 and r.account = $2 and r.new_owner = $3 and r.status = $4 order by r.id desc limit 1",
+        RECOVERY_COLUMNS
+    ))
+    .bind(chain.chain_id as i64)
+    .bind(&account)
+    .bind(&new_owner)
+    .bind(RECOVERY_PENDING)
+    .fetch_optional(&context.db)
+    .await?
+    .map(|pending| pending.reveal(context.email_cipher.as_ref()))
+    .transpose()?;
+    // restarting would push back the approval window of the pending request
+    if let Some(pending) = pending.filter(|pending| {
+        pending.email == email && approval_window(pending, &context.recovery).1 > Utc::now()
+    }) {
+        return Ok(started(&pending, &context.recovery, false));
+    }
+
+    let token = generate_request_token();
+    let mut freeze_token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut freeze_token);
+    let stored = StoredEmail::seal(context.email_cipher.as_ref(), &email)?;
+    let mut tx = context.db.begin().await?;
+    let (bind_code_id,): (i32,) = sqlx::query_as(
+        r#"INSERT INTO bind_code(account, email, code, status, request_token, chain_id, purpose, email_ciphertext, email_key_version, email_index) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id"#,
+    )
+    .bind(&account)
+    .bind(&stored.email)
+    .bind(generate_digits())
+    .bind(CODE_QUEUED)
+    .bind(&token)
+    .bind(chain.chain_id as i64)
+    .bind(CODE_PURPOSE_RECOVERY)
+    .bind(&stored.ciphertext)
+    .bind(stored.key_version)
+    .bind(&stored.index)
+    .fetch_one(&mut *tx)
+    .await?;
+    let (id,): (i32,) = sqlx::query_as(
+        r#"INSERT INTO recovery_request(chain_id, account, new_owner, bind_code_id, status, freeze_token) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id"#,
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&account)
+    .bind(&new_owner)
+    .bind(bind_code_id)
+    .bind(RECOVERY_PENDING)
+    .bind(hex::encode(freeze_token))
+    .fetch_one(&mut *tx)
+    .await?;
+    let inserted = sqlx::query_as::<_, RecoveryRequest>(&format!(
+        "select {} from recovery_request r join bind_code c on c.id = r.bind_code_id where r.id = $1",
+        RECOVERY_COLUMNS
+    ))
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    context.events.open(&token, DeliveryState::Pending);
+    let inserted = RecoveryRequest { email, ..inserted };
+    Ok(started(&inserted, &context.recovery, true))
+}
+
+/// Checks the mailed code and signs the guardian's recovery hash of its request, once its
+/// delay passed. Approving a request cancels the other pending ones of the account, and too
+/// many wrong codes cancel them all.
+pub async fn approve_recovery(
+    context: &Context,
+    account: String,
+    code: String,
+    options: ApproveRecoveryOptions,
+) -> Result<RecoveryApproval> {
+    let chain = context.chains.get(options.chain_id)?;
+    let address = parse_address(&account)?;
+    let account = format!("{:?}", address);
+    ensure_not_frozen(&context.db, chain, address).await?;
+
+    // the binding may have changed since the code was mailed
+    let email = bound_email(context, chain, address).await?;
+    let found = find_sent_code(
+        context,
+        chain.chain_id as i64,
+        &account,
+        &email,
+        &code,
+        CODE_PURPOSE_RECOVERY,
+    )
+    .await?;
+    let request = match found {
+        Some(found) => {
+            sqlx::query_as::<_, RecoveryRequest>(&format!(
+                "select {} from recovery_request r join bind_code c on c.id = r.bind_code_id where r.bind_code_id = $1 and r.status = $2",
+                RECOVERY_COLUMNS
+            ))
+            .bind(found.id)
+            .bind(RECOVERY_PENDING)
+            .fetch_optional(&context.db)
+            .await?
+        }
+        None => None,
+    };
+    let request = match request {
+        Some(request) => request,
+        None => {
+            let cancelled = sqlx::query_scalar::<_, bool>(
+                r#"Update recovery_request set failed_attempts = failed_attempts + 1, status = case when failed_attempts + 1 >= $1 then $2 else status end, updated_at = now() where chain_id = $3 and account = $4 and status = $5 RETURNING status = $2"#,
+            )
+            .bind(context.recovery.max_attempts)
+            .bind(RECOVERY_CANCELLED)
+            .bind(chain.chain_id as i64)
+            .bind(&account)
+            .bind(RECOVERY_PENDING)
+            .fetch_all(&context.db)
+            .await?;
+            if cancelled.into_iter().any(|cancelled| cancelled) {
+                warn!(target: "recovery", chain_id = chain.chain_id, account, "recovery cancelled after too many wrong codes");
+            }
+            return Err(ServiceError::InvalidRequest("error code".to_string()));
+        }
+    };
+    let (approvable_at, expires_at) = approval_window(&request, &context.recovery);
+    let now = Utc::now();
+    if approvable_at > now {
+        return Err(ServiceError::InvalidRequest(format!(
+            "recovery can be approved after {}",
+            approvable_at.to_rfc3339()
+        )));
+    }
+    if expires_at < now {
+        return Err(ServiceError::InvalidRequest("error code".to_string()));
+    }
+
+    let wallet = match chain.signer.parse::<LocalWallet>() {
+        Ok(w) => w,
+        Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
+    };
+    let new_owner = parse_address(&request.new_owner)?;
+    let hash = get_recovery_hash(chain.provider.clone(), chain.guardian, address, new_owner)
+        .await
+        .map_err(|err| {
+            error!(target: "recovery", ?err, chain_id = chain.chain_id, "query recovery hash error");
+            ServiceError::ChainError(err.to_string())
+        })?;
+    let signature = match wallet.sign_message(hash).await {
+        Ok(s) => format!("0x{}", s),
+        Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
+    };
+
+    // another call, a freeze or a last wrong code may have used or cancelled it meanwhile
+    let mut tx = context.db.begin().await?;
+    let approved = sqlx::query(
+        r#"Update recovery_request set status = $1, signature = $2, approved_at = now(), updated_at = now() where id = $3 and status = $4"#,
+    )
+    .bind(RECOVERY_APPROVED)
+    .bind(&signature)
+    .bind(request.id)
+    .bind(RECOVERY_PENDING)
+    .execute(&mut *tx)
+    .await?;
+    if approved.rows_affected() == 0 || !claim_code(&mut tx, request.bind_code_id).await? {
+        tx.rollback().await?;
+        return Err(ServiceError::InvalidRequest("error code".to_string()));
+    }
+    let _ = sqlx::query(
+        r#"Update recovery_request set status = $1, updated_at = now() where chain_id = $2 and account = $3 and status = $4"#,
+    )
+    .bind(RECOVERY_CANCELLED)
+    .bind(chain.chain_id as i64)
+    .bind(&account)
+    .bind(RECOVERY_PENDING)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    context
+        .events
+        .publish(&request.request_token, DeliveryState::Verified);
+    Ok(RecoveryApproval {
+        signature,
+        chain_id: chain.chain_id,
+        guardian: chain.guardian_address.clone(),
+        new_owner: request.new_owner,
+    })
+}
+
+/// Subject and body of the mail carrying the code of a recovery request, `freeze_link`
+/// ending with its freeze token.
+pub fn code_message(
+    request: &RecoveryRequest,
+    config: &RecoveryConfig,
+    freeze_link: &str,
+) -> (String, String) {
+    let subject = format!("ioPay AA Wallet Recovery Code - {}", request.code);
+    let (approvable_at, expires_at) = approval_window(request, config);
+    let body = format!("Dear User,
+
+A recovery was requested for your ioPay AA Wallet {} on chain {}, moving it to the new owner {}.
+
+If you requested it, your recovery code is:
+{}
+It can be used from {} until {}.
+
+If you did not request it, do not share this code with anyone, and open the link below right away. It cancels the recovery and stops us from signing anything further for this wallet until our support team has reviewed it:
+{}{}
+
+Best Regards,
+ioPay Team", request.account, request.chain_id, request.new_owner, request.code, approvable_at.to_rfc3339(), expires_at.to_rfc3339(), freeze_link, request.freeze_token);
+    (subject, body)
+}
+
+/// Subject and body of the mail notifying that a recovery was approved.
+pub fn approved_message(request: &RecoveryRequest) -> (String, String) {
+    let subject = "ioPay AA Wallet Security Alert - Recovery Approved".to_string();
+    let body = format!("Dear User,
+
+The recovery of your ioPay AA Wallet {} on chain {} to the new owner {} was approved with the code sent to this email address.
+
+If this wasn't you, contact our support team immediately.
+
+Best Regards,
+ioPay Team", request.account, request.chain_id, request.new_owner);
+    (subject, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        providers::{MockProvider, Provider},
+        types::{Address, Bytes, Signature},
+    };
+
+    use crate::{
+        contracts::provider::{FailoverClient, ProviderConfig},
+        service::{
+            code::CODE_SENT,
+            testing::{self, TestDb},
+        },
+    };
+
+    #[test]
+    fn mails_new_owner_with_recovery_code() {
+        let request = RecoveryRequest {
+            id: 1,
+            chain_id: 4690,
+            account: "0x8803daf0ab9bad65a56f4d9aeca56085491c299a".to_string(),
+            new_owner: "0x0101010101010101010101010101010101010101".to_string(),
+            bind_code_id: 1,
+            email: "test@test.com".to_string(),
+            email_ciphertext: None,
+            email_key_version: None,
+            code: "123456".to_string(),
+            code_status: CODE_QUEUED,
+            status: RECOVERY_PENDING,
+            request_token: "token".to_string(),
+            freeze_token: "ab".repeat(32),
+            created_at: Utc::now(),
+        };
+        let config = RecoveryConfig::default();
+
+        let (subject, body) = code_message(&request, &config, "https://localhost/freeze?token=");
+        assert!(subject.ends_with("123456"));
+        assert!(body.contains(&request.new_owner));
+        assert!(body.contains(&format!(
+            "https://localhost/freeze?token={}",
+            "ab".repeat(32)
+        )));
+
+        let result = started(&request, &config, true);
+        assert_eq!(result.email, "t***@test.com");
+        assert_eq!(
+            result.expires_at,
+            request.created_at + Duration::seconds(config.delay_secs + CODE_EXPIRY_SECS)
+        );
+    }
+
+    /// Mails the queued codes of `account`, returning the code of the request for `new_owner`.
+    async fn mail_codes(context: &Context, account: Address, new_owner: Address) -> String {
+        sqlx::query("update bind_code set status = $1 where status = $2 and purpose = $3")
+            .bind(CODE_SENT)
+            .bind(CODE_QUEUED)
+            .bind(CODE_PURPOSE_RECOVERY)
+            .execute(&context.db)
+            .await
+            .unwrap();
+        sqlx::query_scalar(
+            "select c.code from recovery_request r join bind_code c on c.id = r.bind_code_id where r.account = $1 and r.new_owner = $2 order by r.id desc limit 1",
+        )
+        .bind(format!("{:?}", account))
+        .bind(format!("{:?}", new_owner))
+        .fetch_one(&context.db)
+        .await
+        .unwrap()
+    }
+
+    /// Moves the pending requests past their delay.
+    async fn wait_delay(context: &Context) {
+        sqlx::query("update recovery_request set created_at = created_at - make_interval(secs => $1) where status = $2")
+            .bind((context.recovery.delay_secs + 1) as f64)
+            .bind(RECOVERY_PENDING)
+            .execute(&context.db)
+            .await
+            .unwrap();
+    }
+
+    async fn statuses(context: &Context) -> Vec<i16> {
+        sqlx::query_scalar("select status from recovery_request order by id")
+            .fetch_all(&context.db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn approves_one_request_after_its_delay() {
+        let Some(db) = TestDb::new().await else {
+            return;
+        };
+        let hash = [7u8; 32];
+        let mock = MockProvider::new();
+        mock.push::<Bytes, _>(Bytes::from(hash.to_vec())).unwrap();
+        let mut chain = testing::chain();
+        chain.provider = Provider::new(
+            FailoverClient::new(
+                vec![("mock".to_string(), Box::new(mock))],
+                ProviderConfig::default(),
+            )
+            .unwrap(),
+        );
+        let context = testing::context(db.pool.clone(), chain.clone());
+
+        let account = Address::repeat_byte(1);
+        let owner = Address::repeat_byte(2);
+        let attacker = Address::repeat_byte(3);
+        testing::bind_email(&db.pool, &chain, &format!("{:?}", account), "test@test.com").await;
+        let start = |new_owner: Address| {
+            start_recovery(
+                &context,
+                format!("{:?}", account),
+                format!("{:?}", new_owner),
+                StartRecoveryOptions::default(),
+            )
+        };
+        let approve = |code: String| {
+            approve_recovery(
+                &context,
+                format!("{:?}", account),
+                code,
+                ApproveRecoveryOptions::default(),
+            )
+        };
+
+        let first = start(owner).await.unwrap();
+        assert!(first.issued);
+        assert_eq!(first.email, "t***@test.com");
+        // restarting neither replaces the owner's request nor is stopped by another one
+        assert!(!start(owner).await.unwrap().issued);
+        assert!(start(attacker).await.unwrap().issued);
+        assert_eq!(
+            statuses(&context).await,
+            vec![RECOVERY_PENDING, RECOVERY_PENDING]
+        );
+
+        let code = mail_codes(&context, account, owner).await;
+        let early = approve(code.clone()).await.unwrap_err();
+        assert!(format!("{:?}", early).contains("can be approved after"));
+
+        wait_delay(&context).await;
+        let approval = approve(code.clone()).await.unwrap();
+        assert_eq!(approval.new_owner, format!("{:?}", owner));
+        let signer = chain.signer.parse::<LocalWallet>().unwrap().address();
+        let signature: Signature = approval.signature.parse().unwrap();
+        signature.verify(&hash[..], signer).unwrap();
+        assert_eq!(
+            statuses(&context).await,
+            vec![RECOVERY_APPROVED, RECOVERY_CANCELLED]
+        );
+        assert!(approve(code).await.is_err());
+
+        let cooling = start(owner).await.unwrap_err();
+        assert!(format!("{:?}", cooling).contains("recovered recently"));
+
+        sqlx::query("update recovery_request set approved_at = now() - interval '2 days'")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(start(owner).await.unwrap().issued);
+        let code = mail_codes(&context, account, owner).await;
+        wait_delay(&context).await;
+        let wrong = if code == "100000" { "100001" } else { "100000" };
+        for _ in 0..context.recovery.max_attempts {
+            assert!(approve(wrong.to_string()).await.is_err());
+        }
+        assert_eq!(statuses(&context).await[2], RECOVERY_CANCELLED);
+        assert!(approve(code).await.is_err());
+
+        db.drop().await;
+    }
+}
@@ -0,0 +1,766 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use ethers::{
+    providers::{Middleware, ProviderError, RpcError},
+    signers::{LocalWallet, Signer},
+    types::{
+        transaction::eip2718::TypedTransaction, Address, BlockNumber, Bytes, TransactionReceipt,
+        TransactionRequest, H256, U256,
+    },
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{
+    contracts::{
+        account::execute_call_data,
+        entry_point::{get_nonce, UserOperation, UserOperationGas, UserOperationReceipt},
+        guardian::bind_email_for_call_data,
+        provider::{ChainProvider, FailoverClient, ProviderConfig},
+    },
+    service::{
+        chain::{Chain, ChainRegistry},
+        code::BindCode,
+        error::Result,
+        Context,
+    },
+};
+
+pub const RELAY_QUEUED: i16 = 0;
+pub const RELAY_SUBMITTED: i16 = 1;
+pub const RELAY_CONFIRMED: i16 = 2;
+pub const RELAY_FAILED: i16 = 3;
+
+/// Seconds a worker holds the relays it claimed before another worker can take them over.
+pub const RELAY_CLAIM_LEASE_SECS: i64 = 300;
+
+const RELAY_COLUMNS: &str = "id, chain_id, account, email_hash, signature, status, nonce, gas_price, tx_hash, replaced_tx_hashes, user_op_hash, submitted_at";
+
+/// Signature bundlers simulate gas estimations with, before the real one exists.
+const DUMMY_SIGNATURE: &str = "0xfffffffffffffffffffffffffffffff0000000000000000000000000000000007aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa1c";
+
+/// How a chain relays bindings, from the `relay` entry of its configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum RelayConfig {
+    /// Sends the guardian's `bindEmailFor` from the relayer key.
+    Transaction,
+    /// Sends UserOperations from a smart account owned by the relayer key to a bundler.
+    UserOperation {
+        bundler_url: String,
+        account: Address,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Relay {
+    Transaction,
+    UserOperation {
+        bundler: ChainProvider,
+        account: Address,
+    },
+}
+
+impl Relay {
+    pub fn new(config: RelayConfig, provider: ProviderConfig) -> eyre::Result<Self> {
+        Ok(match config {
+            RelayConfig::Transaction => Relay::Transaction,
+            RelayConfig::UserOperation {
+                bundler_url,
+                account,
+            } => Relay::UserOperation {
+                bundler: ChainProvider::new(FailoverClient::connect(&[bundler_url], provider)?),
+                account,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayState {
+    Queued,
+    Submitted,
+    Confirmed,
+    Failed,
+}
+
+/// Progress of a relayed binding, reported by `get_status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayStatus {
+    pub state: RelayState,
+    pub tx_hash: Option<String>,
+    pub user_operation_hash: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RelayTransaction {
+    id: i32,
+    chain_id: i64,
+    account: String,
+    email_hash: String,
+    signature: String,
+    status: i16,
+    nonce: Option<i64>,
+    gas_price: Option<i64>,
+    tx_hash: Option<String>,
+    replaced_tx_hashes: Vec<String>,
+    user_op_hash: Option<String>,
+    submitted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayerConfig {
+    /// Blocks on top of the one including a relay before it is confirmed.
+    pub confirmations: u64,
+    /// Seconds before an unmined transaction is replaced with a higher gas price.
+    pub replace_after_secs: i64,
+    /// Minimum gas price increase of a replacement, in percent.
+    pub gas_price_bump_percent: u64,
+}
+
+impl Default for RelayerConfig {
+    fn default() -> Self {
+        RelayerConfig {
+            confirmations: 3,
+            replace_after_secs: 120,
+            gas_price_bump_percent: 15,
+        }
+    }
+}
+
+/// A transaction sent by the relayer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentTransaction {
+    pub hash: H256,
+    pub nonce: U256,
+    pub gas_price: U256,
+}
+
+enum RelayError {
+    /// The chain refused the relay, e.g. because the call reverts.
+    Rejected(String),
+    /// The chain could not be reached, the relay is retried later.
+    Unavailable(String),
+}
+
+/// The chain already has a transaction with this nonce, mined or pending.
+fn nonce_too_low(err: &ProviderError) -> bool {
+    err.to_string().to_lowercase().contains("nonce too low")
+}
+
+/// The node already has this very transaction.
+fn already_known(err: &ProviderError) -> bool {
+    err.to_string().to_lowercase().contains("already known")
+}
+
+impl From<ProviderError> for RelayError {
+    fn from(value: ProviderError) -> Self {
+        if value.is_error_response() {
+            RelayError::Rejected(value.to_string())
+        } else {
+            RelayError::Unavailable(value.to_string())
+        }
+    }
+}
+
+/// Submits bindings from its own key, so accounts without gas can be bound.
+#[derive(Debug, Clone)]
+pub struct Relayer {
+    wallet: LocalWallet,
+    config: RelayerConfig,
+    /// Next nonce per chain, loaded from the chain on first use and after failed sends.
+    nonces: Arc<Mutex<HashMap<u64, U256>>>,
+}
+
+impl Relayer {
+    pub fn new(wallet: LocalWallet, config: RelayerConfig) -> Self {
+        Relayer {
+            wallet,
+            config,
+            nonces: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.wallet.address()
+    }
+
+    /// Signs and sends a legacy transaction calling `to` with `data`.
+    ///
+    /// Without `replace`, the transaction takes the next relayer nonce. With it, it
+    /// replaces the given transaction at a gas price bumped above the previous one.
+    pub async fn send_transaction(
+        &self,
+        chain: &Chain,
+        to: Address,
+        data: Bytes,
+        replace: Option<&SentTransaction>,
+    ) -> std::result::Result<SentTransaction, ProviderError> {
+        let (sent, raw) = self.sign_transaction(chain, to, data, replace).await?;
+        let hash = self.broadcast(chain, raw).await?;
+        Ok(SentTransaction { hash, ..sent })
+    }
+
+    /// Signs a transaction as [`Relayer::send_transaction`] would send it, reserving its
+    /// nonce, and returns it with its raw bytes without sending it.
+    pub async fn sign_transaction(
+        &self,
+        chain: &Chain,
+        to: Address,
+        data: Bytes,
+        replace: Option<&SentTransaction>,
+    ) -> std::result::Result<(SentTransaction, Bytes), ProviderError> {
+        let provider = &chain.provider;
+        let mut nonces = self.nonces.lock().await;
+        let nonce = match (replace, nonces.get(&chain.chain_id)) {
+            (Some(sent), _) => sent.nonce,
+            (None, Some(nonce)) => *nonce,
+            (None, None) => {
+                provider
+                    .get_transaction_count(self.address(), Some(BlockNumber::Pending.into()))
+                    .await?
+            }
+        };
+
+        let mut gas_price = provider.get_gas_price().await?;
+        if let Some(sent) = replace {
+            let bumped = sent.gas_price * (100 + self.config.gas_price_bump_percent) / 100;
+            gas_price = gas_price.max(bumped);
+        }
+        let mut tx: TypedTransaction = TransactionRequest::new()
+            .from(self.address())
+            .to(to)
+            .data(data)
+            .nonce(nonce)
+            .gas_price(gas_price)
+            .chain_id(chain.chain_id)
+            .into();
+        let gas = provider.estimate_gas(&tx, None).await?;
+        tx.set_gas(gas * 120 / 100);
+
+        let signature = self
+            .wallet
+            .sign_transaction(&tx)
+            .await
+            .map_err(|err| ProviderError::CustomError(err.to_string()))?;
+        let raw = tx.rlp_signed(&signature);
+        if replace.is_none() {
+            nonces.insert(chain.chain_id, nonce + 1);
+        }
+        Ok((
+            SentTransaction {
+                hash: H256(keccak256(&raw)),
+                nonce,
+                gas_price,
+            },
+            raw,
+        ))
+    }
+
+    /// Sends a transaction signed by [`Relayer::sign_transaction`]. On failure the cached
+    /// nonce is dropped, so the next transaction reads it from the chain again.
+    pub async fn broadcast(
+        &self,
+        chain: &Chain,
+        raw: Bytes,
+    ) -> std::result::Result<H256, ProviderError> {
+        match chain.provider.send_raw_transaction(raw).await {
+            Ok(pending) => Ok(pending.tx_hash()),
+            Err(err) => {
+                self.release_nonce(chain).await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Forgets the cached nonce of `chain`, e.g. when a signed transaction won't be sent.
+    pub async fn release_nonce(&self, chain: &Chain) {
+        self.nonces.lock().await.remove(&chain.chain_id);
+    }
+
+    /// Signs a UserOperation executing `call_data` from the relayer's `account`, returning
+    /// it with its hash.
+    pub async fn sign_user_operation(
+        &self,
+        chain: &Chain,
+        bundler: &ChainProvider,
+        account: Address,
+        call_data: Bytes,
+    ) -> std::result::Result<(UserOperation, H256), ProviderError> {
+        let nonce = get_nonce(chain.provider.clone(), chain.entry_point, account)
+            .await
+            .map_err(|err| ProviderError::CustomError(err.to_string()))?;
+        let gas_price = chain.provider.get_gas_price().await?;
+        let mut operation = UserOperation {
+            sender: account,
+            nonce,
+            call_data,
+            max_fee_per_gas: gas_price,
+            max_priority_fee_per_gas: gas_price,
+            signature: DUMMY_SIGNATURE.parse().unwrap(),
+            ..Default::default()
+        };
+
+        let gas: UserOperationGas = bundler
+            .request(
+                "eth_estimateUserOperationGas",
+                (&operation, chain.entry_point),
+            )
+            .await?;
+        operation.pre_verification_gas = gas.pre_verification_gas;
+        operation.verification_gas_limit = gas.verification_gas_limit;
+        operation.call_gas_limit = gas.call_gas_limit;
+
+        let hash = operation.hash(chain.entry_point, chain.chain_id);
+        operation.signature = self
+            .wallet
+            .sign_message(hash)
+            .await
+            .map_err(|err| ProviderError::CustomError(err.to_string()))?
+            .to_vec()
+            .into();
+        Ok((operation, H256(hash)))
+    }
+
+    /// Sends a UserOperation signed by [`Relayer::sign_user_operation`] to the bundler.
+    pub async fn send_user_operation(
+        &self,
+        chain: &Chain,
+        bundler: &ChainProvider,
+        operation: &UserOperation,
+    ) -> std::result::Result<H256, ProviderError> {
+        bundler
+            .request("eth_sendUserOperation", (operation, chain.entry_point))
+            .await
+    }
+}
+
+fn parse_hash(hash: &str) -> std::result::Result<H256, RelayError> {
+    hash.parse()
+        .map_err(|_| RelayError::Rejected(format!("invalid hash {}", hash)))
+}
+
+/// Queues the relay of a binding signature issued by `verify_code`.
+pub async fn enqueue(
+    context: &Context,
+    chain: &Chain,
+    code: &BindCode,
+    email_hash: [u8; 32],
+    signature: &str,
+) -> Result<()> {
+    let _ = sqlx::query(
+        r#"INSERT INTO relay_transaction(bind_code_id, chain_id, account, email_hash, signature, status) VALUES ($1, $2, $3, $4, $5, $6)"#,
+    )
+    .bind(code.id)
+    .bind(chain.chain_id as i64)
+    .bind(&code.account)
+    .bind(format!("0x{}", hex::encode(email_hash)))
+    .bind(signature)
+    .bind(RELAY_QUEUED)
+    .execute(&context.db)
+    .await?;
+    Ok(())
+}
+
+pub async fn relay_status(db: &PgPool, bind_code_id: i32) -> Result<Option<RelayStatus>> {
+    let record: Option<(i16, Option<String>, Option<String>)> = sqlx::query_as(
+        "select status, tx_hash, user_op_hash from relay_transaction where bind_code_id = $1",
+    )
+    .bind(bind_code_id)
+    .fetch_optional(db)
+    .await?;
+    Ok(
+        record.map(|(status, tx_hash, user_operation_hash)| RelayStatus {
+            state: match status {
+                RELAY_QUEUED => RelayState::Queued,
+                RELAY_SUBMITTED => RelayState::Submitted,
+                RELAY_CONFIRMED => RelayState::Confirmed,
+                _ => RelayState::Failed,
+            },
+            tx_hash: tx_hash.map(|hash| hash.trim().to_string()),
+            user_operation_hash: user_operation_hash.map(|hash| hash.trim().to_string()),
+        }),
+    )
+}
+
+/// Puts a relay whose transaction was never sent back in the queue.
+async fn requeue(db: &PgPool, id: i32) -> std::result::Result<(), RelayError> {
+    let _ = sqlx::query(
+        r#"Update relay_transaction set status = $1, tx_hash = null, nonce = null, gas_price = null, user_op_hash = null, submitted_at = null, updated_at = now() where id = $2"#,
+    )
+    .bind(RELAY_QUEUED)
+    .bind(id)
+    .execute(db)
+    .await
+    .map_err(|err| RelayError::Unavailable(err.to_string()))?;
+    Ok(())
+}
+
+/// Signs the relay, records it as submitted and only then sends it, so a send is never
+/// lost to a failed update. Relays the chain refused before seeing them are queued again.
+async fn submit(
+    db: &PgPool,
+    relayer: &Relayer,
+    chain: &Chain,
+    relay: &Relay,
+    record: &RelayTransaction,
+) -> std::result::Result<(), RelayError> {
+    let account: Address = record
+        .account
+        .trim()
+        .parse()
+        .map_err(|_| RelayError::Rejected("invalid account".to_string()))?;
+    let email_hash = parse_hash(record.email_hash.trim())?;
+    let signature: Bytes = record
+        .signature
+        .parse()
+        .map_err(|_| RelayError::Rejected("invalid signature".to_string()))?;
+    let data = bind_email_for_call_data(account, email_hash.0, signature);
+
+    match relay {
+        Relay::Transaction => {
+            let (sent, raw) = relayer
+                .sign_transaction(chain, chain.guardian, data, None)
+                .await?;
+            let recorded = sqlx::query(
+                r#"Update relay_transaction set status = $1, tx_hash = $2, nonce = $3, gas_price = $4, submitted_at = now(), updated_at = now() where id = $5 and status = $6"#,
+            )
+            .bind(RELAY_SUBMITTED)
+            .bind(format!("{:?}", sent.hash))
+            .bind(sent.nonce.as_u64() as i64)
+            .bind(sent.gas_price.as_u64() as i64)
+            .bind(record.id)
+            .bind(RELAY_QUEUED)
+            .execute(db)
+            .await;
+            if !recorded.is_ok_and(|recorded| recorded.rows_affected() == 1) {
+                relayer.release_nonce(chain).await;
+                return Err(RelayError::Unavailable(
+                    "could not record relay transaction".to_string(),
+                ));
+            }
+            match relayer.broadcast(chain, raw).await {
+                Ok(_) => {}
+                Err(err) if already_known(&err) => {}
+                Err(err) if nonce_too_low(&err) => {
+                    warn!(target: "relayer", id = record.id, %err, "relay nonce taken, queueing again");
+                    return requeue(db, record.id).await;
+                }
+                // the node may still have it, replacing a stuck transaction sends it again
+                Err(err) if !err.is_error_response() => {
+                    return Err(RelayError::Unavailable(err.to_string()))
+                }
+                Err(err) => return Err(RelayError::Rejected(err.to_string())),
+            }
+            info!(target: "relayer", id = record.id, tx_hash = ?sent.hash, "relay transaction sent");
+        }
+        Relay::UserOperation { bundler, account } => {
+            let (operation, hash) = relayer
+                .sign_user_operation(
+                    chain,
+                    bundler,
+                    *account,
+                    execute_call_data(chain.guardian, U256::zero(), data),
+                )
+                .await?;
+            let recorded = sqlx::query(
+                r#"Update relay_transaction set status = $1, user_op_hash = $2, submitted_at = now(), updated_at = now() where id = $3 and status = $4"#,
+            )
+            .bind(RELAY_SUBMITTED)
+            .bind(format!("{:?}", hash))
+            .bind(record.id)
+            .bind(RELAY_QUEUED)
+            .execute(db)
+            .await;
+            if !recorded.is_ok_and(|recorded| recorded.rows_affected() == 1) {
+                return Err(RelayError::Unavailable(
+                    "could not record relay user operation".to_string(),
+                ));
+            }
+            match relayer
+                .send_user_operation(chain, bundler, &operation)
+                .await
+            {
+                Ok(_) => {}
+                // an unanswered operation is sent again once it is overdue
+                Err(err) if !err.is_error_response() => {
+                    return Err(RelayError::Unavailable(err.to_string()))
+                }
+                Err(err) => return Err(RelayError::Rejected(err.to_string())),
+            }
+            info!(target: "relayer", id = record.id, user_op_hash = ?hash, "relay user operation sent");
+        }
+    }
+    Ok(())
+}
+
+/// Receipt of the relay, once mined.
+async fn find_receipt(
+    chain: &Chain,
+    relay: &Relay,
+    record: &RelayTransaction,
+) -> std::result::Result<Option<TransactionReceipt>, RelayError> {
+    if let (Some(hash), Relay::UserOperation { bundler, .. }) = (&record.user_op_hash, relay) {
+        let receipt: Option<UserOperationReceipt> = bundler
+            .request("eth_getUserOperationReceipt", [parse_hash(hash.trim())?])
+            .await?;
+        return Ok(receipt.map(|receipt| {
+            let mut tx = receipt.receipt;
+            // a reverted operation still mines a successful bundle transaction
+            if !receipt.success {
+                tx.status = Some(0u64.into());
+            }
+            tx
+        }));
+    }
+
+    for hash in record
+        .tx_hash
+        .iter()
+        .chain(record.replaced_tx_hashes.iter())
+    {
+        if let Some(receipt) = chain
+            .provider
+            .get_transaction_receipt(parse_hash(hash.trim())?)
+            .await?
+        {
+            return Ok(Some(receipt));
+        }
+    }
+    Ok(None)
+}
+
+async fn track(
+    db: &PgPool,
+    relayer: &Relayer,
+    chain: &Chain,
+    relay: &Relay,
+    record: &RelayTransaction,
+) -> std::result::Result<(), RelayError> {
+    let receipt = match find_receipt(chain, relay, record).await? {
+        Some(receipt) => receipt,
+        None => return replace_stuck(db, relayer, chain, record).await,
+    };
+    let mined_at = receipt.block_number.unwrap_or_default().as_u64();
+    let current = chain.provider.get_block_number().await?.as_u64();
+    if mined_at + relayer.config.confirmations > current {
+        return Ok(());
+    }
+
+    let status = if receipt.status == Some(1u64.into()) {
+        RELAY_CONFIRMED
+    } else {
+        RELAY_FAILED
+    };
+    let _ = sqlx::query(
+        r#"Update relay_transaction set status = $1, tx_hash = $2, error = $3, updated_at = now() where id = $4"#,
+    )
+    .bind(status)
+    .bind(format!("{:?}", receipt.transaction_hash))
+    .bind((status == RELAY_FAILED).then_some("reverted"))
+    .bind(record.id)
+    .execute(db)
+    .await
+    .map_err(|err| RelayError::Unavailable(err.to_string()))?;
+    info!(target: "relayer", id = record.id, tx_hash = ?receipt.transaction_hash, status, "relay finished");
+    Ok(())
+}
+
+/// Re-sends a transaction that was not mined in time with a higher gas price.
+async fn replace_stuck(
+    db: &PgPool,
+    relayer: &Relayer,
+    chain: &Chain,
+    record: &RelayTransaction,
+) -> std::result::Result<(), RelayError> {
+    let overdue = record.submitted_at.is_some_and(|submitted_at| {
+        submitted_at + Duration::seconds(relayer.config.replace_after_secs) <= Utc::now()
+    });
+    if !overdue {
+        return Ok(());
+    }
+    let (tx_hash, nonce, gas_price) = match (&record.tx_hash, record.nonce, record.gas_price) {
+        (Some(tx_hash), Some(nonce), Some(gas_price)) => (tx_hash, nonce, gas_price),
+        // a UserOperation the bundler never included is sent again
+        _ if record.user_op_hash.is_some() => {
+            warn!(target: "relayer", id = record.id, "relay user operation not included, queueing again");
+            return requeue(db, record.id).await;
+        }
+        _ => return Ok(()),
+    };
+
+    let account: Address = record
+        .account
+        .trim()
+        .parse()
+        .map_err(|_| RelayError::Rejected("invalid account".to_string()))?;
+    let signature: Bytes = record
+        .signature
+        .parse()
+        .map_err(|_| RelayError::Rejected("invalid signature".to_string()))?;
+    let previous = SentTransaction {
+        hash: parse_hash(tx_hash.trim())?,
+        nonce: nonce.into(),
+        gas_price: gas_price.into(),
+    };
+    let sent = match relayer
+        .send_transaction(
+            chain,
+            chain.guardian,
+            bind_email_for_call_data(account, parse_hash(record.email_hash.trim())?.0, signature),
+            Some(&previous),
+        )
+        .await
+    {
+        Ok(sent) => sent,
+        // the nonce was mined meanwhile, by one of ours or by another transaction
+        Err(err) if nonce_too_low(&err) || already_known(&err) => {
+            return match find_receipt(chain, &Relay::Transaction, record).await? {
+                Some(_) => Ok(()),
+                None if nonce_too_low(&err) => {
+                    warn!(target: "relayer", id = record.id, "relay nonce taken by another transaction, queueing again");
+                    requeue(db, record.id).await
+                }
+                None => Ok(()),
+            };
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let _ = sqlx::query(
+        r#"Update relay_transaction set tx_hash = $1, replaced_tx_hashes = array_append(replaced_tx_hashes, $2), gas_price = $3, submitted_at = now(), updated_at = now() where id = $4"#,
+    )
+    .bind(format!("{:?}", sent.hash))
+    .bind(tx_hash.trim())
+    .bind(sent.gas_price.as_u64() as i64)
+    .bind(record.id)
+    .execute(db)
+    .await
+    .map_err(|err| RelayError::Unavailable(err.to_string()))?;
+    warn!(target: "relayer", id = record.id, tx_hash = ?sent.hash, "replaced stuck relay transaction");
+    Ok(())
+}
+
+/// Submits queued relays and tracks submitted ones until they are confirmed.
+pub async fn process_relays(db: &PgPool, chains: &ChainRegistry, relayer: &Relayer) {
+    let records = match sqlx::query_as::<_, RelayTransaction>(&format!(
+        r#"Update relay_transaction set claimed_at = now() where id in (select id from relay_transaction where status in ($1, $2) and (claimed_at is null or claimed_at < now() - make_interval(secs => $3)) order by id limit 50 for update skip locked) RETURNING {}"#,
+        RELAY_COLUMNS
+    ))
+    .bind(RELAY_QUEUED)
+    .bind(RELAY_SUBMITTED)
+    .bind(RELAY_CLAIM_LEASE_SECS as f64)
+    .fetch_all(db)
+    .await
+    {
+        Ok(mut records) => {
+            records.sort_by_key(|record| record.id);
+            records
+        }
+        Err(err) => {
+            error!(target: "relayer", ?err, "claim relay transactions error");
+            return;
+        }
+    };
+
+    for record in records {
+        let chain = match chains.get(Some(record.chain_id as u64)) {
+            Ok(chain) => chain,
+            Err(_) => continue,
+        };
+        let relay = match &chain.relay {
+            Some(relay) => relay,
+            None => continue,
+        };
+        let result = if record.status == RELAY_QUEUED {
+            submit(db, relayer, chain, relay, &record).await
+        } else {
+            track(db, relayer, chain, relay, &record).await
+        };
+        match result {
+            Ok(()) => {}
+            Err(RelayError::Rejected(err)) => {
+                warn!(target: "relayer", id = record.id, %err, "relay rejected");
+                let _ = sqlx::query(
+                    r#"Update relay_transaction set status = $1, error = $2, updated_at = now() where id = $3"#,
+                )
+                .bind(RELAY_FAILED)
+                .bind(&err)
+                .bind(record.id)
+                .execute(db)
+                .await;
+            }
+            Err(RelayError::Unavailable(err)) => {
+                warn!(target: "relayer", id = record.id, %err, "relay postponed");
+            }
+        }
+        let _ = sqlx::query(r#"Update relay_transaction set claimed_at = null where id = $1"#)
+            .bind(record.id)
+            .execute(db)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::chain::ChainConfig;
+    use ethers::{
+        providers::{MockProvider, Provider},
+        types::U64,
+    };
+
+    #[tokio::test]
+    async fn manages_relayer_nonces() {
+        let registry = ChainRegistry::new(
+            vec![ChainConfig {
+                chain_id: 4690,
+                rpc_urls: vec!["http://localhost:8545".to_string()],
+                guardian_address: "0xBf081D23317966eEBD59Bc8EDB593A830F373178".to_string(),
+                ..Default::default()
+            }],
+            None,
+            Some("key".to_string()),
+        )
+        .unwrap();
+        let mock = MockProvider::new();
+        // responses are popped from the back
+        mock.push(H256::repeat_byte(2)).unwrap();
+        mock.push(U256::from(21_000)).unwrap();
+        mock.push(U256::from(1_000_000_000_000u64)).unwrap();
+        mock.push(H256::repeat_byte(1)).unwrap();
+        mock.push(U256::from(21_000)).unwrap();
+        mock.push(U256::from(1_000_000_000_000u64)).unwrap();
+        mock.push(U64::from(7)).unwrap();
+        let mut chain = registry.get(None).unwrap().clone();
+        chain.provider = Provider::new(
+            FailoverClient::new(
+                vec![("mock".to_string(), Box::new(mock))],
+                ProviderConfig::default(),
+            )
+            .unwrap(),
+        );
+        let relayer = Relayer::new(
+            LocalWallet::new(&mut rand::thread_rng()),
+            RelayerConfig::default(),
+        );
+
+        let first = relayer
+            .send_transaction(&chain, chain.guardian, Bytes::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(first.hash, H256::repeat_byte(1));
+        assert_eq!(first.nonce, U256::from(7));
+
+        let second = relayer
+            .send_transaction(&chain, chain.guardian, Bytes::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(second.hash, H256::repeat_byte(2));
+        assert_eq!(second.nonce, U256::from(8));
+    }
+}
@@ -0,0 +1,289 @@
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use tracing::{error, info};
+
+use crate::service::{
+    alert::PURGE_ALERTS,
+    challenge::{CHALLENGE_TTL_SECS, PURGE_USED_CHALLENGES},
+    email_change::PURGE_EMAIL_CHANGES,
+    error::Result,
+    idempotency::{IDEMPOTENCY_KEY_TTL_HOURS, PURGE_IDEMPOTENCY_KEYS},
+    quota::{PURGE_USAGE, USAGE_RETENTION_SECS},
+    recovery::PURGE_RECOVERY_REQUESTS,
+    store::CodeStore,
+};
+
+/// Key of the advisory lock held by the instance purging a batch.
+const PURGE_LOCK: i64 = 0x7075_7267_655f_636f;
+
+/// How long codes and the personal data around them are kept.
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    /// Seconds after creation before a code, recovery request, email change or alert is purged.
+    pub retention_secs: i64,
+    /// Rows deleted or anonymized per transaction.
+    pub batch_size: i64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            retention_secs: 30 * 86400,
+            batch_size: 1000,
+        }
+    }
+}
+
+/// Rows purged by one pass of `purge_codes`, per table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    /// Codes deleted outright.
+    pub deleted: u64,
+    /// Codes a signature or relay still refers to, whose email and code were erased.
+    pub anonymized: u64,
+    /// Codes deleted from a store kept outside Postgres, see [`CodeStore::purge_before`].
+    pub store_codes: u64,
+    /// API client usage counters of past quota periods.
+    pub usage: u64,
+    /// Replay records of expired proof-of-work challenges.
+    pub challenges: u64,
+    /// Idempotency keys whose responses can no longer be replayed.
+    pub idempotency_keys: u64,
+    pub recovery_requests: u64,
+    pub email_changes: u64,
+    pub alerts: u64,
+}
+
+/// Runs `statement` binding `window_secs` and the batch size until a batch comes back
+/// short, each batch in a transaction holding the purge lock. Stops early when another
+/// instance holds the lock.
+async fn in_batches(
+    db: &PgPool,
+    config: &RetentionConfig,
+    window_secs: i64,
+    statement: &str,
+) -> Result<u64> {
+    let mut total = 0;
+    loop {
+        let mut tx = db.begin().await?;
+        let (locked,): (bool,) = sqlx::query_as("select pg_try_advisory_xact_lock($1)")
+            .bind(PURGE_LOCK)
+            .fetch_one(&mut *tx)
+            .await?;
+        if !locked {
+            return Ok(total);
+        }
+        let affected = sqlx::query(statement)
+            .bind(window_secs as f64)
+            .bind(config.batch_size)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        total += affected;
+        if affected < config.batch_size as u64 {
+            return Ok(total);
+        }
+    }
+}
+
+/// Deletes the codes past the retention window, and anonymizes those still referenced.
+/// Recovery requests, email changes and alerts go first, so their codes are free to delete.
+/// Also drops the bookkeeping rows other modules no longer need.
+pub async fn purge_codes(
+    db: &PgPool,
+    codes: &dyn CodeStore,
+    config: &RetentionConfig,
+) -> Result<PurgeReport> {
+    let recovery_requests =
+        in_batches(db, config, config.retention_secs, PURGE_RECOVERY_REQUESTS).await?;
+    let email_changes = in_batches(db, config, config.retention_secs, PURGE_EMAIL_CHANGES).await?;
+    let alerts = in_batches(db, config, config.retention_secs, PURGE_ALERTS).await?;
+    let deleted = in_batches(
+        db,
+        config,
+        config.retention_secs,
+        r#"DELETE FROM bind_code WHERE id IN (
+            SELECT c.id FROM bind_code c WHERE c.created_at < now() - make_interval(secs => $1)
+            AND NOT EXISTS (SELECT 1 FROM binding_signature s WHERE s.bind_code_id = c.id)
+            AND NOT EXISTS (SELECT 1 FROM relay_transaction r WHERE r.bind_code_id = c.id)
+            AND NOT EXISTS (SELECT 1 FROM recovery_request r WHERE r.bind_code_id = c.id)
+            AND NOT EXISTS (SELECT 1 FROM email_change e WHERE c.id IN (e.old_code_id, e.new_code_id))
+            LIMIT $2 FOR UPDATE SKIP LOCKED)"#,
+    )
+    .await?;
+    let anonymized = in_batches(
+        db,
+        config,
+        config.retention_secs,
+        r#"Update bind_code set email = '', code = '', email_ciphertext = null, email_key_version = null, email_index = null, updated_at = now() where id in (
+            select id from bind_code where created_at < now() - make_interval(secs => $1) and (email <> '' or email_ciphertext is not null)
+            limit $2 for update skip locked)"#,
+    )
+    .await?;
+    let store_codes = codes
+        .purge_before(Utc::now() - Duration::seconds(config.retention_secs))
+        .await?;
+    let usage = in_batches(db, config, USAGE_RETENTION_SECS, PURGE_USAGE).await?;
+    let challenges = in_batches(db, config, CHALLENGE_TTL_SECS, PURGE_USED_CHALLENGES).await?;
+    let idempotency_keys = in_batches(
+        db,
+        config,
+        IDEMPOTENCY_KEY_TTL_HOURS as i64 * 3600,
+        PURGE_IDEMPOTENCY_KEYS,
+    )
+    .await?;
+    Ok(PurgeReport {
+        deleted,
+        anonymized,
+        store_codes,
+        usage,
+        challenges,
+        idempotency_keys,
+        recovery_requests,
+        email_changes,
+        alerts,
+    })
+}
+
+/// One maintenance pass, logging what was purged.
+pub async fn run_retention(db: &PgPool, codes: &dyn CodeStore, config: &RetentionConfig) {
+    match purge_codes(db, codes, config).await {
+        Ok(report) => {
+            info!(target: "retention", deleted = report.deleted, anonymized = report.anonymized, store_codes = report.store_codes, usage = report.usage, challenges = report.challenges, idempotency_keys = report.idempotency_keys, recovery_requests = report.recovery_requests, email_changes = report.email_changes, alerts = report.alerts, "purged expired codes")
+        }
+        Err(err) => error!(target: "retention", ?err, "purge codes error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::Address;
+
+    use super::*;
+    use crate::service::{
+        binding::bindings_by_account,
+        code::{CODE_PURPOSE_RECOVERY, CODE_SENT},
+        store::PgCodeStore,
+        testing::{self, TestDb},
+    };
+
+    const ACCOUNT: &str = "0x00000000000000000000000000000000000000aa";
+
+    async fn insert_code(db: &PgPool, email: &str, purpose: i16) -> i32 {
+        sqlx::query_scalar(
+            "insert into bind_code(account, email, code, status, chain_id, purpose) values ($1, $2, '123456', $3, 4690, $4) returning id",
+        )
+        .bind(ACCOUNT)
+        .bind(email)
+        .bind(CODE_SENT)
+        .bind(purpose)
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn age(db: &PgPool, table: &str, column: &str, days: i32) {
+        sqlx::query(&format!(
+            "update {} set {} = {} - make_interval(days => $1)",
+            table, column, column
+        ))
+        .bind(days)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn purges_expired_rows_and_keeps_bindings() {
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        let db = &test_db.pool;
+        let chain = testing::chain();
+
+        testing::bind_email(db, &chain, ACCOUNT, "bound@test.com").await;
+        insert_code(db, "lonely@test.com", 0).await;
+        let recovery_code = insert_code(db, "bound@test.com", CODE_PURPOSE_RECOVERY).await;
+        sqlx::query(
+            "insert into recovery_request(chain_id, account, new_owner, bind_code_id, status, freeze_token) values (4690, $1, $1, $2, 0, 'token')",
+        )
+        .bind(ACCOUNT)
+        .bind(recovery_code)
+        .execute(db)
+        .await
+        .unwrap();
+        let (client_id,): (i32,) = sqlx::query_as(
+            "insert into api_client(name, key_hash, quota_per_minute, quota_per_day) values ('test', 'hash', 10, 100) returning id",
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        sqlx::query("insert into api_client_usage(client_id, period, period_start, count) values ($1, 'day', now(), 1)")
+            .bind(client_id)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query("insert into used_challenge(challenge) values ('challenge')")
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query(
+            "insert into idempotency_key(scope, key, fingerprint) values (1, 'key', 'fingerprint')",
+        )
+        .execute(db)
+        .await
+        .unwrap();
+        for (table, column) in [
+            ("bind_code", "created_at"),
+            ("recovery_request", "created_at"),
+            ("api_client_usage", "period_start"),
+            ("used_challenge", "created_at"),
+            ("idempotency_key", "created_at"),
+        ] {
+            age(db, table, column, 31).await;
+        }
+        let recent = insert_code(db, "recent@test.com", 0).await;
+
+        // batches of one row still purge everything
+        let config = RetentionConfig {
+            batch_size: 1,
+            ..RetentionConfig::default()
+        };
+        let store = PgCodeStore::new(db.clone());
+        let report = purge_codes(db, &store, &config).await.unwrap();
+        assert_eq!(
+            report,
+            PurgeReport {
+                deleted: 2,
+                anonymized: 1,
+                usage: 1,
+                challenges: 1,
+                idempotency_keys: 1,
+                recovery_requests: 1,
+                ..PurgeReport::default()
+            }
+        );
+
+        // the signed code keeps its row without its email, the binding keeps the email
+        let codes: Vec<(i32, String, String)> =
+            sqlx::query_as("select id, email, trim(code) from bind_code order by id")
+                .fetch_all(db)
+                .await
+                .unwrap();
+        assert_eq!(codes.len(), 2);
+        assert_eq!((codes[0].1.as_str(), codes[0].2.as_str()), ("", ""));
+        assert_eq!(codes[1].0, recent);
+        let bindings =
+            bindings_by_account(db, None, 4690, ACCOUNT.parse::<Address>().unwrap(), false)
+                .await
+                .unwrap();
+        assert_eq!(bindings[0].email, "bound@test.com");
+
+        assert_eq!(
+            purge_codes(db, &store, &config).await.unwrap(),
+            PurgeReport::default()
+        );
+        test_db.drop().await;
+    }
+}
@@ -0,0 +1,90 @@
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    PgPool,
+};
+
+use crate::service::error::{Result, ServiceError};
+
+/// The migrations under `migrations/`, embedded at build time.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// The newest schema version this binary knows.
+pub fn latest_version() -> i64 {
+    MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Applies the pending migrations, returning the schema version afterwards.
+pub async fn migrate(db: &PgPool) -> Result<i64> {
+    MIGRATOR
+        .run(db)
+        .await
+        .map_err(|err| ServiceError::DatabaseError(err.to_string()))?;
+    Ok(schema_version(db).await?.unwrap_or(0))
+}
+
+/// Records the migrations up to `version` as applied without running them, for databases
+/// whose tables were created by hand before migrations were embedded.
+pub async fn baseline(db: &PgPool, version: i64) -> Result<usize> {
+    let mut conn = db.acquire().await?;
+    conn.ensure_migrations_table()
+        .await
+        .map_err(|err| ServiceError::DatabaseError(err.to_string()))?;
+
+    let mut recorded = 0;
+    for migration in MIGRATOR.iter().filter(|m| m.version <= version) {
+        let result = sqlx::query(
+            r#"INSERT INTO _sqlx_migrations(version, description, success, checksum, execution_time) VALUES ($1, $2, true, $3, 0) ON CONFLICT (version) DO NOTHING"#,
+        )
+        .bind(migration.version)
+        .bind(migration.description.as_ref())
+        .bind(migration.checksum.as_ref())
+        .execute(&mut *conn)
+        .await?;
+        recorded += result.rows_affected() as usize;
+    }
+    Ok(recorded)
+}
+
+/// The newest successfully applied migration, `None` if migrations never ran.
+pub async fn schema_version(db: &PgPool) -> Result<Option<i64>> {
+    let (exists,): (bool,) = sqlx::query_as("select to_regclass('_sqlx_migrations') is not null")
+        .fetch_one(db)
+        .await?;
+    if !exists {
+        return Ok(None);
+    }
+    let (version,): (Option<i64>,) =
+        sqlx::query_as("select max(version) from _sqlx_migrations where success")
+            .fetch_one(db)
+            .await?;
+    Ok(version)
+}
+
+/// Fails when the database was migrated by a newer binary, returning the current version.
+pub async fn check_schema(db: &PgPool) -> Result<Option<i64>> {
+    let version = schema_version(db).await?;
+    match version {
+        Some(version) if version > latest_version() => Err(ServiceError::DatabaseError(format!(
+            "schema version {} is newer than {} supported by this binary",
+            version,
+            latest_version()
+        ))),
+        _ => Ok(version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeds_contiguous_migrations() {
+        let versions: Vec<i64> = MIGRATOR.iter().map(|m| m.version).collect();
+
+        assert_eq!(versions, (1..=latest_version()).collect::<Vec<_>>());
+    }
+}
@@ -33,7 +33,14 @@ pub mod sequence {
 
 /// A module that deserializes `[]` optionally
 pub mod empty_params {
-    use serde::{Deserialize, Deserializer};
+    use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(_: &(), s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_seq(Some(0))?.end()
+    }
 
     pub fn deserialize<'de, D>(d: D) -> Result<(), D::Error>
     where
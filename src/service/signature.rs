@@ -0,0 +1,244 @@
+use chrono::{DateTime, Utc};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::service::{
+    account::CounterfactualAccount,
+    alert::ensure_not_frozen,
+    binding::{bindings_by_account, record_binding},
+    chain::Chain,
+    code::BindCode,
+    encryption::{reveal_email, EmailCipher},
+    error::{Result, ServiceError},
+    ownership::{parse_address, verify_ownership, OwnershipProof},
+    Context,
+};
+
+/// A guardian binding signature issued by `verify_code`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct BindingSignature {
+    pub account: String,
+    pub chain_id: Option<i64>,
+    pub guardian_address: Option<String>,
+    pub email_hash: String,
+    pub signer: String,
+    pub signature: String,
+    pub nonce: Option<i64>,
+    pub deadline: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Seconds an issued signature is handed back by `get_signature`.
+pub const SIGNATURE_TTL_SECS: i64 = 7 * 86400;
+
+/// A signature of the binding of a code, as stored by `save_signature`.
+#[derive(Debug, Clone, Copy)]
+pub struct SignedBinding<'a> {
+    pub email_hash: [u8; 32],
+    pub signer: &'a str,
+    pub signature: &'a str,
+    /// The guardian nonce the signed hash was derived with, when derived locally.
+    pub nonce: Option<u64>,
+}
+
+/// Stores the signature of a verified code, whose row lives in the Postgres `bind_code` table,
+/// and records its binding, sealed when `cipher` is set. With `supersede` the binding revokes
+/// the other bindings of the account right away, see `record_binding`.
+pub async fn save_signature(
+    db: &PgPool,
+    cipher: Option<&EmailCipher>,
+    chain: &Chain,
+    code: &BindCode,
+    signed: SignedBinding<'_>,
+    supersede: bool,
+) -> Result<()> {
+    let account = format!("{:?}", parse_address(&code.account)?);
+    let email_hash = format!("0x{}", hex::encode(signed.email_hash));
+    let mut tx = db.begin().await?;
+    let (id,): (i32,) = sqlx::query_as(
+        r#"INSERT INTO binding_signature(bind_code_id, account, chain_id, guardian_address, email_hash, signer, signature, nonce, deadline) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, now() + make_interval(secs => $9)) RETURNING id"#,
+    )
+    .bind(code.id)
+    .bind(&account)
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .bind(&email_hash)
+    .bind(signed.signer)
+    .bind(signed.signature)
+    .bind(signed.nonce.map(|nonce| nonce as i64))
+    .bind(SIGNATURE_TTL_SECS as f64)
+    .fetch_one(&mut *tx)
+    .await?;
+    record_binding(
+        &mut tx,
+        cipher,
+        chain.chain_id as i64,
+        &account,
+        &code.email,
+        &email_hash,
+        signed.signer,
+        id,
+        supersede,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The email of the latest active binding of `account`, and its hash.
+pub(crate) async fn signed_email(
+    context: &Context,
+    chain: &Chain,
+    account: Address,
+) -> Result<Option<(String, String)>> {
+    Ok(bindings_by_account(
+        &context.db,
+        context.email_cipher.as_ref(),
+        chain.chain_id as i64,
+        account,
+        false,
+    )
+    .await?
+    .into_iter()
+    .next()
+    .map(|binding| (binding.email, binding.email_hash)))
+}
+
+/// The email bound to `account`, from the latest active binding the service signed for it.
+///
+/// On chains with an indexer, it is the latest active binding the guardian holds, so a binding
+/// replacing it only counts once it is submitted.
+pub(crate) async fn bound_email(
+    context: &Context,
+    chain: &Chain,
+    account: Address,
+) -> Result<String> {
+    let (email, _) = signed_email(context, chain, account)
+        .await?
+        .ok_or_else(|| ServiceError::InvalidRequest("no email bound".to_string()))?;
+    if chain.indexer.is_none() {
+        return Ok(email);
+    }
+
+    let onchain: Option<(String, Option<String>, Option<i16>)> = sqlx::query_as(
+        "select b.email, b.email_ciphertext, b.email_key_version from email_binding b join onchain_binding o on o.chain_id = b.chain_id and o.account = b.account and o.email_hash = b.email_hash where b.chain_id = $1 and o.guardian_address = $2 and b.account = $3 and b.revoked_at is null and o.bound order by b.id desc limit 1",
+    )
+    .bind(chain.chain_id as i64)
+    .bind(&chain.guardian_address)
+    .bind(format!("{:?}", account))
+    .fetch_optional(&context.db)
+    .await?;
+    match onchain {
+        Some((email, ciphertext, key_version)) => reveal_email(
+            context.email_cipher.as_ref(),
+            &email,
+            ciphertext.as_deref(),
+            key_version,
+        ),
+        None => Err(ServiceError::InvalidRequest(
+            "email is not bound on-chain".to_string(),
+        )),
+    }
+}
+
+/// Returns the latest signature issued for `account` on a chain once the caller proved
+/// ownership of it, until its deadline.
+pub async fn get_signature(
+    context: &Context,
+    account: String,
+    nonce: String,
+    signature: String,
+    chain_id: Option<u64>,
+    counterfactual: Option<CounterfactualAccount>,
+) -> Result<BindingSignature> {
+    let chain = context.chains.get(chain_id)?;
+    verify_ownership(
+        context,
+        chain,
+        &account,
+        &OwnershipProof { nonce, signature },
+        counterfactual.as_ref(),
+    )
+    .await?;
+    ensure_not_frozen(&context.db, chain, parse_address(&account)?).await?;
+
+    sqlx::query_as::<_, BindingSignature>(
+        "select account, chain_id, guardian_address, email_hash, signer, signature, nonce, deadline, created_at from binding_signature where account = $1 and chain_id = $2 and (deadline is null or deadline > now()) order by id desc limit 1",
+    )
+    .bind(format!("{:?}", parse_address(&account)?))
+    .bind(chain.chain_id as i64)
+    .fetch_optional(&context.db)
+    .await?
+    .ok_or_else(|| ServiceError::InvalidRequest("signature not found".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        contracts::provider::{FailoverClient, ProviderConfig},
+        service::{
+            code::{CODE_SENT, CODE_VERIFIED},
+            testing::{self, TestDb},
+            verify::sign_binding,
+        },
+    };
+    use ethers::{
+        providers::{MockProvider, Provider},
+        types::Bytes,
+    };
+
+    #[tokio::test]
+    async fn signs_a_code_once() {
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        let db = &test_db.pool;
+        let mock = MockProvider::new();
+        // binding hashes of both attempts
+        for _ in 0..2 {
+            mock.push::<Bytes, _>(Bytes::from([8u8; 32].to_vec()))
+                .unwrap();
+        }
+        let mut chain = testing::chain();
+        chain.provider = Provider::new(
+            FailoverClient::new(
+                vec![("mock".to_string(), Box::new(mock))],
+                ProviderConfig::default(),
+            )
+            .unwrap(),
+        );
+        let context = testing::context(db.clone(), chain.clone());
+        let account = "0x00000000000000000000000000000000000000AA";
+        let code = sqlx::query_as::<_, BindCode>(
+            "insert into bind_code(email, account, code, status) values ('a@b.c', $1, '123456', $2) returning *",
+        )
+        .bind(account)
+        .bind(CODE_SENT)
+        .fetch_one(db)
+        .await
+        .unwrap();
+        let address = parse_address(account).unwrap();
+
+        sign_binding(&context, &chain, address, &code, &Default::default(), false)
+            .await
+            .unwrap();
+        // a concurrent verify_code with the same code loses the claim
+        assert!(
+            sign_binding(&context, &chain, address, &code, &Default::default(), false)
+                .await
+                .is_err()
+        );
+
+        let (stored, status): (String, i16) = sqlx::query_as(
+            "select s.account, c.status from binding_signature s join bind_code c on c.id = s.bind_code_id",
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        assert_eq!(stored, account.to_lowercase());
+        assert_eq!(status, CODE_VERIFIED);
+        test_db.drop().await;
+    }
+}
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::service::{
+    binding::bindings_by_account,
+    chain::Chain,
+    code::{
+        BindCode, CODE_EXPIRY_SECS, CODE_PURPOSE_BIND, CODE_SENT, CODE_VERIFIED,
+        RESEND_INTERVAL_SECS,
+    },
+    commitment::normalize_email,
+    error::{Result, ServiceError},
+    indexer::{chain_binding, ChainBinding},
+    ownership::{verify_ownership, OwnershipProof},
+    relayer::{relay_status, RelayStatus},
+    Context,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeState {
+    Pending,
+    Sent,
+    Verified,
+    Expired,
+}
+
+/// Optional trailing parameter of `get_status`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusOptions {
+    /// The request token returned by `send_code`, reporting the delivery of that code.
+    pub request_id: Option<String>,
+    /// Proof of owning the account, reporting the latest code of the pair and whether the
+    /// email was ever bound to it.
+    pub ownership: Option<OwnershipProof>,
+    pub chain_id: Option<u64>,
+}
+
+/// What the service knows about an account/email pair. Never carries the code itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindingStatus {
+    pub state: Option<CodeState>,
+    pub email_sent: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub resend_available_at: Option<DateTime<Utc>>,
+    pub bound: bool,
+    /// Progress of the binding transaction, when the relayer submits it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<RelayStatus>,
+    /// What the guardian indexer saw on-chain for the verified binding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onchain: Option<ChainBinding>,
+}
+
+fn code_state(code: &BindCode, now: DateTime<Utc>) -> CodeState {
+    match code.status {
+        CODE_VERIFIED => CodeState::Verified,
+        _ if code.created_at + Duration::seconds(CODE_EXPIRY_SECS) < now => CodeState::Expired,
+        CODE_SENT => CodeState::Sent,
+        _ => CodeState::Pending,
+    }
+}
+
+fn binding_status(code: &BindCode, now: DateTime<Utc>) -> BindingStatus {
+    BindingStatus {
+        state: Some(code_state(code, now)),
+        email_sent: code.status >= CODE_SENT,
+        expires_at: Some(code.created_at + Duration::seconds(CODE_EXPIRY_SECS)),
+        resend_available_at: Some(if code.status < CODE_VERIFIED {
+            code.created_at + Duration::seconds(RESEND_INTERVAL_SECS)
+        } else {
+            now
+        }),
+        bound: code.status == CODE_VERIFIED,
+        relay: None,
+        onchain: None,
+    }
+}
+
+/// The status of `code` with the progress of its relay and of its binding on-chain.
+async fn code_progress(context: &Context, chain: &Chain, code: &BindCode) -> Result<BindingStatus> {
+    let onchain = if code.status == CODE_VERIFIED && chain.indexer.is_some() {
+        Some(chain_binding(&context.db, code.id).await?)
+    } else {
+        None
+    };
+    // only verified codes have a row in Postgres to relay
+    let relay = if code.status == CODE_VERIFIED {
+        relay_status(&context.db, code.id).await?
+    } else {
+        None
+    };
+    Ok(BindingStatus {
+        relay,
+        onchain,
+        ..binding_status(code, Utc::now())
+    })
+}
+
+pub async fn get_status(
+    context: &Context,
+    account: String,
+    email: String,
+    options: StatusOptions,
+) -> Result<BindingStatus> {
+    let chain = context.chains.get(options.chain_id)?;
+    let chain_id = chain.chain_id as i64;
+
+    // anyone can name an account and an email, only the requester of a code or the owner of
+    // the account learns about them
+    let request_id = match (&options.ownership, options.request_id) {
+        (Some(proof), _) => {
+            verify_ownership(context, chain, &account, proof, None).await?;
+            let code = context
+                .codes
+                .latest_for_pair(chain_id, &account, &email, CODE_PURPOSE_BIND)
+                .await?;
+            // codes past retention are anonymized, their bindings are kept
+            let bound = context
+                .codes
+                .is_verified(chain_id, &account, &email, CODE_PURPOSE_BIND)
+                .await?
+                || match account.parse() {
+                    Ok(address) => bindings_by_account(
+                        &context.db,
+                        context.email_cipher.as_ref(),
+                        chain_id,
+                        address,
+                        true,
+                    )
+                    .await?
+                    .iter()
+                    .any(|binding| binding.email == normalize_email(&email)),
+                    Err(_) => false,
+                };
+            let status = match code {
+                Some(code) => code_progress(context, chain, &code).await?,
+                None => BindingStatus {
+                    state: None,
+                    email_sent: false,
+                    expires_at: None,
+                    resend_available_at: None,
+                    bound: false,
+                    relay: None,
+                    onchain: None,
+                },
+            };
+            return Ok(BindingStatus { bound, ..status });
+        }
+        (None, Some(request_id)) => request_id,
+        (None, None) => {
+            return Err(ServiceError::InvalidRequest(
+                "request_id or ownership proof required".to_string(),
+            ))
+        }
+    };
+    let code = context
+        .codes
+        .find_by_token(&request_id, CODE_PURPOSE_BIND)
+        .await?
+        .filter(|code| code.account == account && code.chain_id == Some(chain_id))
+        .ok_or_else(|| ServiceError::InvalidRequest("request not found".to_string()))?;
+    if context.codes.reveal_email(&code)? != email {
+        return Err(ServiceError::InvalidRequest(
+            "request not found".to_string(),
+        ));
+    }
+
+    code_progress(context, chain, &code).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(status: i16, age_secs: i64, now: DateTime<Utc>) -> BindCode {
+        BindCode {
+            id: 1,
+            account: "0x01".to_string(),
+            email: "test@test.com".to_string(),
+            code: "123456".to_string(),
+            status,
+            created_at: now - Duration::seconds(age_secs),
+            updated_at: None,
+            request_token: Some("token".to_string()),
+            chain_id: Some(4690),
+            email_ciphertext: None,
+            email_key_version: None,
+        }
+    }
+
+    #[test]
+    fn reports_code_lifecycle() {
+        let now = Utc::now();
+
+        let pending = binding_status(&code(0, 10, now), now);
+        assert_eq!(pending.state, Some(CodeState::Pending));
+        assert!(!pending.email_sent && !pending.bound);
+
+        let sent = binding_status(&code(CODE_SENT, 10, now), now);
+        assert_eq!(sent.state, Some(CodeState::Sent));
+        assert!(sent.email_sent);
+        assert_eq!(
+            sent.resend_available_at,
+            Some(now - Duration::seconds(10) + Duration::seconds(RESEND_INTERVAL_SECS))
+        );
+
+        let expired = binding_status(&code(CODE_SENT, CODE_EXPIRY_SECS + 1, now), now);
+        assert_eq!(expired.state, Some(CodeState::Expired));
+
+        let verified = binding_status(&code(CODE_VERIFIED, CODE_EXPIRY_SECS + 1, now), now);
+        assert_eq!(verified.state, Some(CodeState::Verified));
+        assert!(verified.bound);
+        assert_eq!(verified.resend_available_at, Some(now));
+    }
+}
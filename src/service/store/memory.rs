@@ -0,0 +1,210 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+
+use super::{CodeStore, CLAIM_LEASE_SECS};
+use crate::service::{
+    code::{BindCode, CODE_PURPOSE_RECOVERY, CODE_QUEUED, CODE_SENT, CODE_VERIFIED},
+    error::Result,
+};
+
+/// A store kept in memory, for tests.
+#[derive(Debug, Default)]
+pub struct MemoryCodeStore {
+    codes: Mutex<Vec<MemoryCode>>,
+}
+
+#[derive(Debug)]
+struct MemoryCode {
+    code: BindCode,
+    purpose: i16,
+    claimed_at: Option<DateTime<Utc>>,
+}
+
+impl MemoryCodeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn find<P>(&self, predicate: P) -> Option<BindCode>
+    where
+        P: Fn(&MemoryCode) -> bool,
+    {
+        let codes = self.codes.lock().unwrap();
+        codes
+            .iter()
+            .rev()
+            .find(|stored| predicate(stored))
+            .map(|stored| stored.code.clone())
+    }
+
+    fn update<F>(&self, id: i32, f: F)
+    where
+        F: FnOnce(&mut MemoryCode),
+    {
+        let mut codes = self.codes.lock().unwrap();
+        if let Some(stored) = codes.iter_mut().find(|stored| stored.code.id == id) {
+            f(stored);
+        }
+    }
+}
+
+#[async_trait]
+impl CodeStore for MemoryCodeStore {
+    async fn insert_code(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        code: &str,
+        request_token: &str,
+        purpose: i16,
+    ) -> Result<BindCode> {
+        let mut codes = self.codes.lock().unwrap();
+        let inserted = BindCode {
+            id: codes.len() as i32 + 1,
+            account: account.to_string(),
+            email: email.to_string(),
+            code: code.to_string(),
+            status: CODE_QUEUED,
+            created_at: Utc::now(),
+            updated_at: None,
+            request_token: Some(request_token.to_string()),
+            chain_id: Some(chain_id),
+            email_ciphertext: None,
+            email_key_version: None,
+        };
+        codes.push(MemoryCode {
+            code: inserted.clone(),
+            purpose,
+            claimed_at: None,
+        });
+        Ok(inserted)
+    }
+
+    async fn latest_for_pair(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>> {
+        Ok(self.find(|stored| {
+            stored.purpose == purpose
+                && stored.code.chain_id == Some(chain_id)
+                && stored.code.account == account
+                && stored.code.email == email
+        }))
+    }
+
+    async fn find_sent(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        given: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>> {
+        Ok(self.find(|stored| {
+            stored.purpose == purpose
+                && stored.code.chain_id == Some(chain_id)
+                && stored.code.account == account
+                && stored.code.email == email
+                && stored.code.code == given
+                && stored.code.status == CODE_SENT
+        }))
+    }
+
+    async fn find_by_token(&self, request_token: &str, purpose: i16) -> Result<Option<BindCode>> {
+        Ok(self.find(|stored| {
+            stored.purpose == purpose && stored.code.request_token.as_deref() == Some(request_token)
+        }))
+    }
+
+    async fn set_request_token(&self, id: i32, request_token: &str) -> Result<()> {
+        self.update(id, |stored| {
+            stored.code.request_token = Some(request_token.to_string())
+        });
+        Ok(())
+    }
+
+    async fn is_verified(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<bool> {
+        Ok(self
+            .find(|stored| {
+                stored.purpose == purpose
+                    && stored.code.chain_id == Some(chain_id)
+                    && stored.code.account == account
+                    && stored.code.email == email
+                    && stored.code.status == CODE_VERIFIED
+            })
+            .is_some())
+    }
+
+    async fn count_since(&self, since: DateTime<Utc>) -> Result<i64> {
+        let codes = self.codes.lock().unwrap();
+        Ok(codes
+            .iter()
+            .filter(|stored| stored.code.created_at > since)
+            .count() as i64)
+    }
+
+    async fn claim_pending(&self, limit: i64) -> Result<Vec<BindCode>> {
+        let now = Utc::now();
+        let mut codes = self.codes.lock().unwrap();
+        Ok(codes
+            .iter_mut()
+            .rev()
+            .filter(|stored| {
+                stored.code.status == CODE_QUEUED
+                    && stored.purpose != CODE_PURPOSE_RECOVERY
+                    && stored
+                        .claimed_at
+                        .is_none_or(|at| at < now - Duration::seconds(CLAIM_LEASE_SECS))
+            })
+            .take(limit as usize)
+            .map(|stored| {
+                stored.claimed_at = Some(now);
+                stored.code.clone()
+            })
+            .collect())
+    }
+
+    async fn mark_sent(&self, id: i32) -> Result<()> {
+        self.update(id, |stored| {
+            stored.code.status = CODE_SENT;
+            stored.code.updated_at = Some(Utc::now());
+        });
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: i32) -> Result<()> {
+        self.update(id, |stored| stored.claimed_at = None);
+        Ok(())
+    }
+
+    async fn verify(&self, code: &BindCode) -> Result<Option<BindCode>> {
+        let mut verified = None;
+        self.update(code.id, |stored| {
+            if stored.code.status == CODE_SENT {
+                stored.code.status = CODE_VERIFIED;
+                stored.code.updated_at = Some(Utc::now());
+                verified = Some(stored.code.clone());
+            }
+        });
+        Ok(verified)
+    }
+
+    async fn purge_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut codes = self.codes.lock().unwrap();
+        let count = codes.len();
+        codes.retain(|stored| stored.code.created_at >= before);
+        Ok((count - codes.len()) as u64)
+    }
+}
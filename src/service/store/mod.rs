@@ -0,0 +1,231 @@
+mod memory;
+mod postgres;
+mod sqlite;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+pub use self::{memory::MemoryCodeStore, postgres::PgCodeStore, sqlite::SqliteCodeStore};
+use crate::service::{code::BindCode, error::Result};
+
+/// Seconds a claimed code is reserved for the mail worker that claimed it.
+pub const CLAIM_LEASE_SECS: i64 = 300;
+
+const CODE_COLUMNS: &str = "id, account, email, code, status, created_at, updated_at, request_token, chain_id, email_ciphertext, email_key_version";
+
+/// Storage of the codes mailed by `send_code`, selected at startup by `CODE_STORE`.
+///
+/// Only codes are pluggable: signatures, relays and the other tables stay in Postgres and
+/// reference verified codes in its `bind_code` table, which is why [`CodeStore::verify`] hands
+/// back the Postgres row.
+#[async_trait]
+pub trait CodeStore: Send + Sync {
+    /// Stores a queued code of `purpose`.
+    async fn insert_code(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        code: &str,
+        request_token: &str,
+        purpose: i16,
+    ) -> Result<BindCode>;
+
+    /// The latest code of `purpose` issued for `account` and `email`.
+    async fn latest_for_pair(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>>;
+
+    /// The latest sent code of `purpose` for `account` and `email` matching `code`.
+    async fn find_sent(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        code: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>>;
+
+    /// The code of `purpose` issued with `request_token`.
+    async fn find_by_token(&self, request_token: &str, purpose: i16) -> Result<Option<BindCode>>;
+
+    async fn set_request_token(&self, id: i32, request_token: &str) -> Result<()>;
+
+    /// Whether a code of `purpose` for `account` and `email` was ever verified.
+    async fn is_verified(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<bool>;
+
+    /// How many codes were issued after `since`.
+    async fn count_since(&self, since: DateTime<Utc>) -> Result<i64>;
+
+    /// Reserves up to `limit` queued codes, newest first, for `CLAIM_LEASE_SECS`. Recovery
+    /// codes are left to the recovery mailer.
+    ///
+    /// Codes claimed by another worker are skipped until they are marked or their lease ends.
+    async fn claim_pending(&self, limit: i64) -> Result<Vec<BindCode>>;
+
+    async fn mark_sent(&self, id: i32) -> Result<()>;
+
+    /// Releases the claim of a code whose mail failed, so the next pass retries it.
+    async fn mark_failed(&self, id: i32) -> Result<()>;
+
+    /// Marks a sent code verified and returns it as signatures reference it. `None` when
+    /// another call used it first.
+    async fn verify(&self, code: &BindCode) -> Result<Option<BindCode>>;
+
+    /// The email of a code returned without it, decrypting it when stored encrypted. Codes
+    /// looked up by email carry it already.
+    fn reveal_email(&self, code: &BindCode) -> Result<String> {
+        Ok(code.email.clone())
+    }
+
+    /// Deletes the codes issued before `before` that the store keeps outside Postgres,
+    /// returning how many. The `bind_code` table is left to `service::retention`, which keeps
+    /// the codes signatures refer to.
+    async fn purge_before(&self, _before: DateTime<Utc>) -> Result<u64> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{
+        code::{CODE_PURPOSE_BIND, CODE_PURPOSE_RECOVERY, CODE_VERIFIED},
+        testing::TestDb,
+    };
+
+    async fn claims_each_code_once(store: &dyn CodeStore) {
+        let first = store
+            .insert_code(1, "0x01", "a@test.com", "111111", "a", CODE_PURPOSE_BIND)
+            .await
+            .unwrap();
+        let second = store
+            .insert_code(1, "0x01", "a@test.com", "222222", "b", CODE_PURPOSE_BIND)
+            .await
+            .unwrap();
+
+        let claimed = store.claim_pending(10).await.unwrap();
+        assert_eq!(
+            claimed.iter().map(|code| code.id).collect::<Vec<_>>(),
+            vec![second.id, first.id]
+        );
+        assert!(store.claim_pending(10).await.unwrap().is_empty());
+
+        store.mark_failed(first.id).await.unwrap();
+        store.mark_sent(second.id).await.unwrap();
+        assert_eq!(store.claim_pending(10).await.unwrap()[0].id, first.id);
+
+        let latest = store
+            .latest_for_pair(1, "0x01", "a@test.com", CODE_PURPOSE_BIND)
+            .await
+            .unwrap();
+        assert_eq!(latest.map(|code| code.code), Some("222222".to_string()));
+        let sent = store
+            .find_sent(1, "0x01", "a@test.com", "222222", CODE_PURPOSE_BIND)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent.id, second.id);
+
+        assert!(!store
+            .is_verified(1, "0x01", "a@test.com", CODE_PURPOSE_BIND)
+            .await
+            .unwrap());
+        let verified = store.verify(&sent).await.unwrap().unwrap();
+        assert_eq!(verified.status, CODE_VERIFIED);
+        assert!(store.verify(&sent).await.unwrap().is_none());
+        assert!(store
+            .is_verified(1, "0x01", "a@test.com", CODE_PURPOSE_BIND)
+            .await
+            .unwrap());
+        assert_eq!(
+            store
+                .find_by_token("b", CODE_PURPOSE_BIND)
+                .await
+                .unwrap()
+                .map(|code| code.id),
+            Some(verified.id)
+        );
+        assert_eq!(
+            store
+                .count_since(Utc::now() - chrono::Duration::minutes(1))
+                .await
+                .unwrap(),
+            2
+        );
+
+        // recovery codes are mailed with their request
+        store
+            .insert_code(
+                1,
+                "0x01",
+                "a@test.com",
+                "333333",
+                "c",
+                CODE_PURPOSE_RECOVERY,
+            )
+            .await
+            .unwrap();
+        assert!(store.claim_pending(10).await.unwrap().is_empty());
+        assert!(store
+            .find_by_token("c", CODE_PURPOSE_BIND)
+            .await
+            .unwrap()
+            .is_none());
+
+        // codes issued since are kept
+        store
+            .purge_before(Utc::now() - chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+        assert!(store
+            .find_by_token("c", CODE_PURPOSE_RECOVERY)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn stores_claim_each_code_once() {
+        let store = MemoryCodeStore::new();
+        claims_each_code_once(&store).await;
+        assert_eq!(store.purge_before(Utc::now()).await.unwrap(), 3);
+
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        claims_each_code_once(&PgCodeStore::new(test_db.pool.clone())).await;
+        test_db.drop().await;
+
+        let Some(test_db) = TestDb::new().await else {
+            return;
+        };
+        let store = SqliteCodeStore::connect("sqlite::memory:", test_db.pool.clone())
+            .await
+            .unwrap();
+        claims_each_code_once(&store).await;
+        assert_eq!(store.purge_before(Utc::now()).await.unwrap(), 2);
+        assert!(store
+            .find_by_token("a", CODE_PURPOSE_BIND)
+            .await
+            .unwrap()
+            .is_none());
+        let (moved,): (i64,) =
+            sqlx::query_as("select count(*) from bind_code where request_token = 'b'")
+                .fetch_one(&test_db.pool)
+                .await
+                .unwrap();
+        assert_eq!(moved, 1);
+        test_db.drop().await;
+    }
+}
@@ -0,0 +1,259 @@
+use std::cmp::Reverse;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use super::{CodeStore, CLAIM_LEASE_SECS, CODE_COLUMNS};
+use crate::service::{
+    code::{BindCode, CODE_PURPOSE_RECOVERY, CODE_QUEUED, CODE_SENT, CODE_VERIFIED},
+    encryption::{reveal_email, EmailCipher, StoredEmail},
+    error::Result,
+};
+
+/// Matches `email` by blind index, or in plain text on rows stored before encryption.
+const EMAIL_MATCHES: &str = "(email_index = $2 or (email_index is null and email = $3))";
+
+/// The production store, the `bind_code` table.
+#[derive(Debug, Clone)]
+pub struct PgCodeStore {
+    db: PgPool,
+    cipher: Option<EmailCipher>,
+}
+
+impl PgCodeStore {
+    pub fn new(db: PgPool) -> Self {
+        PgCodeStore { db, cipher: None }
+    }
+
+    /// Stores new emails encrypted, see `EmailCipher`.
+    pub fn with_cipher(mut self, cipher: Option<EmailCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    fn blind_index(&self, email: &str) -> Option<String> {
+        self.cipher.as_ref().map(|cipher| cipher.blind_index(email))
+    }
+
+    /// Stores `code` as verified, keeping its request token and creation time. Used by stores
+    /// moving their verified codes here.
+    pub(crate) async fn insert_verified(&self, code: &BindCode, purpose: i16) -> Result<BindCode> {
+        let stored = StoredEmail::seal(self.cipher.as_ref(), &code.email)?;
+        let verified = sqlx::query_as::<_, BindCode>(&format!(
+            r#"INSERT INTO bind_code(account, email, code, status, request_token, chain_id, purpose, created_at, updated_at, email_ciphertext, email_key_version, email_index) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, now(), $9, $10, $11) RETURNING {}"#,
+            CODE_COLUMNS
+        ))
+        .bind(&code.account)
+        .bind(&stored.email)
+        .bind(&code.code)
+        .bind(CODE_VERIFIED)
+        .bind(&code.request_token)
+        .bind(code.chain_id)
+        .bind(purpose)
+        .bind(code.created_at)
+        .bind(&stored.ciphertext)
+        .bind(stored.key_version)
+        .bind(&stored.index)
+        .fetch_one(&self.db)
+        .await?;
+        Ok(BindCode {
+            email: code.email.clone(),
+            ..verified
+        })
+    }
+}
+
+/// Fills in the email a code was looked up by.
+pub(super) fn with_email(code: Option<BindCode>, email: &str) -> Option<BindCode> {
+    code.map(|code| BindCode {
+        email: email.to_string(),
+        ..code
+    })
+}
+
+#[async_trait]
+impl CodeStore for PgCodeStore {
+    async fn insert_code(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        code: &str,
+        request_token: &str,
+        purpose: i16,
+    ) -> Result<BindCode> {
+        let stored = StoredEmail::seal(self.cipher.as_ref(), email)?;
+        let inserted = sqlx::query_as::<_, BindCode>(&format!(
+            r#"INSERT INTO bind_code(account, email, code, status, request_token, chain_id, purpose, email_ciphertext, email_key_version, email_index) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING {}"#,
+            CODE_COLUMNS
+        ))
+        .bind(account)
+        .bind(&stored.email)
+        .bind(code)
+        .bind(CODE_QUEUED)
+        .bind(request_token)
+        .bind(chain_id)
+        .bind(purpose)
+        .bind(&stored.ciphertext)
+        .bind(stored.key_version)
+        .bind(&stored.index)
+        .fetch_one(&self.db)
+        .await?;
+        Ok(BindCode {
+            email: email.to_string(),
+            ..inserted
+        })
+    }
+
+    async fn latest_for_pair(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>> {
+        let code = sqlx::query_as::<_, BindCode>(&format!(
+            "select {} from bind_code where account = $1 and {} and chain_id = $4 and purpose = $5 order by id desc limit 1",
+            CODE_COLUMNS, EMAIL_MATCHES
+        ))
+        .bind(account)
+        .bind(self.blind_index(email))
+        .bind(email)
+        .bind(chain_id)
+        .bind(purpose)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(with_email(code, email))
+    }
+
+    async fn find_sent(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        code: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>> {
+        let code = sqlx::query_as::<_, BindCode>(&format!(
+            "select {} from bind_code where account = $1 and {} and code = $4 and status = $5 and chain_id = $6 and purpose = $7 order by id desc limit 1",
+            CODE_COLUMNS, EMAIL_MATCHES
+        ))
+        .bind(account)
+        .bind(self.blind_index(email))
+        .bind(email)
+        .bind(code)
+        .bind(CODE_SENT)
+        .bind(chain_id)
+        .bind(purpose)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(with_email(code, email))
+    }
+
+    async fn find_by_token(&self, request_token: &str, purpose: i16) -> Result<Option<BindCode>> {
+        Ok(sqlx::query_as::<_, BindCode>(&format!(
+            "select {} from bind_code where request_token = $1 and purpose = $2",
+            CODE_COLUMNS
+        ))
+        .bind(request_token)
+        .bind(purpose)
+        .fetch_optional(&self.db)
+        .await?)
+    }
+
+    async fn set_request_token(&self, id: i32, request_token: &str) -> Result<()> {
+        let _ = sqlx::query(r#"Update bind_code set request_token = $1 where id = $2"#)
+            .bind(request_token)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn is_verified(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<bool> {
+        let (verified,): (bool,) = sqlx::query_as(&format!(
+            "select exists(select 1 from bind_code where account = $1 and {} and status = $4 and chain_id = $5 and purpose = $6)",
+            EMAIL_MATCHES
+        ))
+        .bind(account)
+        .bind(self.blind_index(email))
+        .bind(email)
+        .bind(CODE_VERIFIED)
+        .bind(chain_id)
+        .bind(purpose)
+        .fetch_one(&self.db)
+        .await?;
+        Ok(verified)
+    }
+
+    async fn count_since(&self, since: DateTime<Utc>) -> Result<i64> {
+        let (count,): (i64,) =
+            sqlx::query_as("select count(*) from bind_code where created_at > $1")
+                .bind(since)
+                .fetch_one(&self.db)
+                .await?;
+        Ok(count)
+    }
+
+    async fn claim_pending(&self, limit: i64) -> Result<Vec<BindCode>> {
+        let mut codes = sqlx::query_as::<_, BindCode>(&format!(
+            r#"Update bind_code set claimed_at = now() where id in (select id from bind_code where status = $1 and purpose <> $2 and (claimed_at is null or claimed_at < now() - make_interval(secs => $3)) order by id desc limit $4 for update skip locked) RETURNING {}"#,
+            CODE_COLUMNS
+        ))
+        .bind(CODE_QUEUED)
+        .bind(CODE_PURPOSE_RECOVERY)
+        .bind(CLAIM_LEASE_SECS as f64)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        codes.sort_by_key(|code| Reverse(code.id));
+        Ok(codes)
+    }
+
+    async fn mark_sent(&self, id: i32) -> Result<()> {
+        let _ =
+            sqlx::query(r#"Update bind_code set status = $1, updated_at = now() where id = $2"#)
+                .bind(CODE_SENT)
+                .bind(id)
+                .execute(&self.db)
+                .await?;
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: i32) -> Result<()> {
+        let _ = sqlx::query(r#"Update bind_code set claimed_at = null where id = $1"#)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn verify(&self, code: &BindCode) -> Result<Option<BindCode>> {
+        let verified = sqlx::query_as::<_, BindCode>(&format!(
+            r#"Update bind_code set status = $1, updated_at = now() where id = $2 and status = $3 RETURNING {}"#,
+            CODE_COLUMNS
+        ))
+        .bind(CODE_VERIFIED)
+        .bind(code.id)
+        .bind(CODE_SENT)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(with_email(verified, &code.email))
+    }
+
+    fn reveal_email(&self, code: &BindCode) -> Result<String> {
+        reveal_email(
+            self.cipher.as_ref(),
+            &code.email,
+            code.email_ciphertext.as_deref(),
+            code.email_key_version,
+        )
+    }
+}
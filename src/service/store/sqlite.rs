@@ -0,0 +1,314 @@
+use std::cmp::Reverse;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{sqlite::SqlitePoolOptions, PgPool, SqlitePool};
+
+use super::{postgres::with_email, CodeStore, PgCodeStore, CLAIM_LEASE_SECS, CODE_COLUMNS};
+use crate::service::{
+    code::{BindCode, CODE_PURPOSE_RECOVERY, CODE_QUEUED, CODE_SENT, CODE_VERIFIED},
+    encryption::{reveal_email, EmailCipher, StoredEmail},
+    error::Result,
+};
+
+/// Matches `email` by blind index, or in plain text when no cipher is configured.
+const EMAIL_MATCHES: &str = "(email_index = ?2 or (email_index is null and email = ?3))";
+
+/// A store keeping codes in a single SQLite file until they are verified, creating its table
+/// on connect. Verified codes move to the Postgres `bind_code` table, where signatures and
+/// relays reference them.
+#[derive(Debug, Clone)]
+pub struct SqliteCodeStore {
+    db: SqlitePool,
+    verified: PgCodeStore,
+    cipher: Option<EmailCipher>,
+}
+
+impl SqliteCodeStore {
+    /// Connects to `url`, e.g. `sqlite://codes.db?mode=rwc`, moving verified codes to `pg`.
+    pub async fn connect(url: &str, pg: PgPool) -> Result<Self> {
+        // a single connection, so `sqlite::memory:` is one database
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(url)
+            .await?;
+        sqlx::query(
+            r#"create table if not exists bind_code (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NOT NULL,
+                account TEXT NOT NULL,
+                code TEXT NOT NULL,
+                status INTEGER NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT,
+                request_token TEXT UNIQUE,
+                chain_id INTEGER,
+                purpose INTEGER NOT NULL,
+                claimed_at TEXT,
+                email_ciphertext TEXT,
+                email_key_version INTEGER,
+                email_index TEXT
+            )"#,
+        )
+        .execute(&db)
+        .await?;
+        // files created before emails were encrypted lack their columns
+        let columns: Vec<String> =
+            sqlx::query_scalar("select name from pragma_table_info('bind_code')")
+                .fetch_all(&db)
+                .await?;
+        for (column, kind) in [
+            ("email_ciphertext", "TEXT"),
+            ("email_key_version", "INTEGER"),
+            ("email_index", "TEXT"),
+        ] {
+            if !columns.iter().any(|name| name == column) {
+                sqlx::query(&format!(
+                    "alter table bind_code add column {} {}",
+                    column, kind
+                ))
+                .execute(&db)
+                .await?;
+            }
+        }
+        sqlx::query(
+            "create index if not exists bind_code_account_email_index_idx on bind_code (account, email_index)",
+        )
+        .execute(&db)
+        .await?;
+        Ok(SqliteCodeStore {
+            db,
+            verified: PgCodeStore::new(pg),
+            cipher: None,
+        })
+    }
+
+    /// Stores emails encrypted, here and once moved to Postgres, see `EmailCipher`.
+    pub fn with_cipher(mut self, cipher: Option<EmailCipher>) -> Self {
+        self.verified = self.verified.with_cipher(cipher.clone());
+        self.cipher = cipher;
+        self
+    }
+
+    fn blind_index(&self, email: &str) -> Option<String> {
+        self.cipher.as_ref().map(|cipher| cipher.blind_index(email))
+    }
+
+    async fn set_status(&self, id: i32, status: i16) -> Result<()> {
+        let _ = sqlx::query(r#"Update bind_code set status = ?1, updated_at = ?2 where id = ?3"#)
+            .bind(status)
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CodeStore for SqliteCodeStore {
+    async fn insert_code(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        code: &str,
+        request_token: &str,
+        purpose: i16,
+    ) -> Result<BindCode> {
+        let stored = StoredEmail::seal(self.cipher.as_ref(), email)?;
+        let inserted = sqlx::query_as::<_, BindCode>(&format!(
+            r#"INSERT INTO bind_code(account, email, code, status, request_token, chain_id, purpose, created_at, email_ciphertext, email_key_version, email_index) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11) RETURNING {}"#,
+            CODE_COLUMNS
+        ))
+        .bind(account)
+        .bind(&stored.email)
+        .bind(code)
+        .bind(CODE_QUEUED)
+        .bind(request_token)
+        .bind(chain_id)
+        .bind(purpose)
+        .bind(Utc::now())
+        .bind(&stored.ciphertext)
+        .bind(stored.key_version)
+        .bind(&stored.index)
+        .fetch_one(&self.db)
+        .await?;
+        Ok(BindCode {
+            email: email.to_string(),
+            ..inserted
+        })
+    }
+
+    async fn latest_for_pair(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>> {
+        let queued = sqlx::query_as::<_, BindCode>(&format!(
+            "select {} from bind_code where account = ?1 and {} and chain_id = ?4 and purpose = ?5 order by id desc limit 1",
+            CODE_COLUMNS, EMAIL_MATCHES
+        ))
+        .bind(account)
+        .bind(self.blind_index(email))
+        .bind(email)
+        .bind(chain_id)
+        .bind(purpose)
+        .fetch_optional(&self.db)
+        .await?;
+        let queued = with_email(queued, email);
+        let verified = self
+            .verified
+            .latest_for_pair(chain_id, account, email, purpose)
+            .await?;
+        Ok(queued
+            .into_iter()
+            .chain(verified)
+            .max_by_key(|code| code.created_at))
+    }
+
+    async fn find_sent(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        code: &str,
+        purpose: i16,
+    ) -> Result<Option<BindCode>> {
+        let code = sqlx::query_as::<_, BindCode>(&format!(
+            "select {} from bind_code where account = ?1 and {} and code = ?4 and status = ?5 and chain_id = ?6 and purpose = ?7 order by id desc limit 1",
+            CODE_COLUMNS, EMAIL_MATCHES
+        ))
+        .bind(account)
+        .bind(self.blind_index(email))
+        .bind(email)
+        .bind(code)
+        .bind(CODE_SENT)
+        .bind(chain_id)
+        .bind(purpose)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(with_email(code, email))
+    }
+
+    async fn find_by_token(&self, request_token: &str, purpose: i16) -> Result<Option<BindCode>> {
+        let queued = sqlx::query_as::<_, BindCode>(&format!(
+            "select {} from bind_code where request_token = ?1 and purpose = ?2",
+            CODE_COLUMNS
+        ))
+        .bind(request_token)
+        .bind(purpose)
+        .fetch_optional(&self.db)
+        .await?;
+        match queued {
+            Some(code) => Ok(Some(code)),
+            None => self.verified.find_by_token(request_token, purpose).await,
+        }
+    }
+
+    async fn set_request_token(&self, id: i32, request_token: &str) -> Result<()> {
+        let _ = sqlx::query(r#"Update bind_code set request_token = ?1 where id = ?2"#)
+            .bind(request_token)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn is_verified(
+        &self,
+        chain_id: i64,
+        account: &str,
+        email: &str,
+        purpose: i16,
+    ) -> Result<bool> {
+        self.verified
+            .is_verified(chain_id, account, email, purpose)
+            .await
+    }
+
+    async fn count_since(&self, since: DateTime<Utc>) -> Result<i64> {
+        let (count,): (i64,) =
+            sqlx::query_as("select count(*) from bind_code where created_at > ?1")
+                .bind(since)
+                .fetch_one(&self.db)
+                .await?;
+        Ok(count + self.verified.count_since(since).await?)
+    }
+
+    async fn claim_pending(&self, limit: i64) -> Result<Vec<BindCode>> {
+        let now = Utc::now();
+        let mut codes = sqlx::query_as::<_, BindCode>(&format!(
+            r#"Update bind_code set claimed_at = ?1 where id in (select id from bind_code where status = ?2 and purpose <> ?3 and (claimed_at is null or claimed_at < ?4) order by id desc limit ?5) RETURNING {}"#,
+            CODE_COLUMNS
+        ))
+        .bind(now)
+        .bind(CODE_QUEUED)
+        .bind(CODE_PURPOSE_RECOVERY)
+        .bind(now - Duration::seconds(CLAIM_LEASE_SECS))
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        codes.sort_by_key(|code| Reverse(code.id));
+        Ok(codes)
+    }
+
+    async fn mark_sent(&self, id: i32) -> Result<()> {
+        self.set_status(id, CODE_SENT).await
+    }
+
+    async fn mark_failed(&self, id: i32) -> Result<()> {
+        let _ = sqlx::query(r#"Update bind_code set claimed_at = null where id = ?1"#)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn verify(&self, code: &BindCode) -> Result<Option<BindCode>> {
+        let purpose: Option<i16> = sqlx::query_scalar(
+            r#"Update bind_code set status = ?1, updated_at = ?2 where id = ?3 and status = ?4 RETURNING purpose"#,
+        )
+        .bind(CODE_VERIFIED)
+        .bind(Utc::now())
+        .bind(code.id)
+        .bind(CODE_SENT)
+        .fetch_optional(&self.db)
+        .await?;
+        let Some(purpose) = purpose else {
+            return Ok(None);
+        };
+        let verified = match self.verified.insert_verified(code, purpose).await {
+            Ok(verified) => verified,
+            Err(err) => {
+                // hand the code back, so it can be entered again
+                self.set_status(code.id, CODE_SENT).await?;
+                return Err(err);
+            }
+        };
+        let _ = sqlx::query("delete from bind_code where id = ?1")
+            .bind(code.id)
+            .execute(&self.db)
+            .await?;
+        Ok(Some(verified))
+    }
+
+    async fn purge_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        Ok(sqlx::query("delete from bind_code where created_at < ?1")
+            .bind(before)
+            .execute(&self.db)
+            .await?
+            .rows_affected())
+    }
+
+    fn reveal_email(&self, code: &BindCode) -> Result<String> {
+        reveal_email(
+            self.cipher.as_ref(),
+            &code.email,
+            code.email_ciphertext.as_deref(),
+            code.email_key_version,
+        )
+    }
+}
@@ -0,0 +1,147 @@
+//! Fixtures for tests. Database tests need `TEST_DATABASE_URL` pointing at a Postgres server
+//! where they may create databases, and skip themselves when it is not set.
+
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::utils::keccak256;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Connection, Executor, PgConnection, PgPool,
+};
+
+use super::{
+    chain::{Chain, ChainConfig, ChainRegistry},
+    code::{BindCode, CODE_VERIFIED},
+    email_change::EmailChangeConfig,
+    encryption::EmailCipher,
+    events::EventHub,
+    guardian::HashConfig,
+    ownership::OwnershipConfig,
+    recovery::RecoveryConfig,
+    signature::{save_signature, SignedBinding},
+    store::PgCodeStore,
+    Context,
+};
+
+/// A chain whose provider is never reached.
+pub fn chain() -> Chain {
+    let config: ChainConfig = serde_json::from_value(serde_json::json!({
+        "chain_id": 4690,
+        "rpc_urls": ["http://127.0.0.1:1"],
+        "guardian_address": "0xBf081D23317966eEBD59Bc8EDB593A830F373178",
+        "signer": "0123456789012345678901234567890123456789012345678901234567890123",
+    }))
+    .unwrap();
+    ChainRegistry::new(vec![config], None, None)
+        .unwrap()
+        .get(None)
+        .unwrap()
+        .clone()
+}
+
+/// An email cipher with fixed keys.
+pub fn cipher() -> EmailCipher {
+    EmailCipher::new(HashMap::from([(1, [1u8; 32])]), 1, vec![9]).unwrap()
+}
+
+/// A context serving `chain` alone from `db`, without challenges or a relayer.
+pub fn context(db: PgPool, chain: Chain) -> Context {
+    Context {
+        codes: Arc::new(PgCodeStore::new(db.clone())),
+        db,
+        chains: ChainRegistry::single(chain),
+        events: EventHub::new(),
+        challenge: None,
+        ownership: OwnershipConfig {
+            domain: "localhost".to_string(),
+            uri: "http://localhost".to_string(),
+            required: false,
+        },
+        guardian_hash: HashConfig {
+            local: false,
+            sample_rate: 0.0,
+        },
+        relayer: None,
+        recovery: RecoveryConfig::default(),
+        email_change: EmailChangeConfig::default(),
+        email_cipher: None,
+    }
+}
+
+/// Binds `email` to `account` as if `verify_code` signed its `keccak256` hash.
+pub async fn bind_email(db: &PgPool, chain: &Chain, account: &str, email: &str) {
+    let code = sqlx::query_as::<_, BindCode>(
+        "insert into bind_code(account, email, code, status, chain_id) values ($1, $2, '123456', $3, $4) returning *",
+    )
+    .bind(account)
+    .bind(email)
+    .bind(CODE_VERIFIED)
+    .bind(chain.chain_id as i64)
+    .fetch_one(db)
+    .await
+    .unwrap();
+    save_signature(
+        db,
+        None,
+        chain,
+        &code,
+        SignedBinding {
+            email_hash: keccak256(email),
+            signer: "0x0000000000000000000000000000000000000001",
+            signature: "0x",
+            nonce: None,
+        },
+        true,
+    )
+    .await
+    .unwrap();
+}
+
+/// A freshly migrated database of its own on the `TEST_DATABASE_URL` server.
+pub struct TestDb {
+    pub pool: PgPool,
+    url: String,
+    name: String,
+}
+
+impl TestDb {
+    pub async fn new() -> Option<Self> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let name = format!("binder_test_{:016x}", rand::random::<u64>());
+        let mut admin = PgConnection::connect(&url)
+            .await
+            .expect("could not connect to TEST_DATABASE_URL");
+        admin
+            .execute(format!(r#"create database "{}""#, name).as_str())
+            .await
+            .expect("could not create test database");
+
+        let options = url
+            .parse::<PgConnectOptions>()
+            .expect("invalid TEST_DATABASE_URL")
+            .database(&name);
+        let pool = PgPoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await
+            .expect("could not connect to test database");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("could not migrate test database");
+        Some(TestDb { pool, url, name })
+    }
+
+    /// Drops the database. Databases of failed tests are left behind for inspection.
+    pub async fn drop(self) {
+        self.pool.close().await;
+        let mut admin = PgConnection::connect(&self.url)
+            .await
+            .expect("could not connect to TEST_DATABASE_URL");
+        admin
+            // closed connections may still be shutting down on the server
+            .execute(format!(r#"drop database "{}" with (force)"#, self.name).as_str())
+            .await
+            .expect("could not drop test database");
+    }
+}
@@ -0,0 +1,134 @@
+use ethers::types::{Address, Bytes, U256};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    contracts::{
+        account::execute_call_data, entry_point::get_nonce, guardian::bind_email_call_data,
+    },
+    service::{
+        account::{check_counterfactual, is_deployed, CounterfactualAccount},
+        chain::Chain,
+        error::{Result, ServiceError},
+    },
+};
+
+/// A UserOperation binding the email, left for the wallet to fill gas fields, sign and submit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationDraft {
+    pub sender: Address,
+    pub nonce: U256,
+    /// Deploys the account when it has no code yet, empty otherwise.
+    pub init_code: Bytes,
+    /// The account's `execute` call to the guardian's `bindEmail`.
+    pub call_data: Bytes,
+    pub entry_point: Address,
+}
+
+/// Builds the UserOperation through which `account` submits its binding `signature`.
+pub async fn build_user_operation(
+    chain: &Chain,
+    account: Address,
+    email_hash: [u8; 32],
+    signature: Bytes,
+    counterfactual: Option<&CounterfactualAccount>,
+) -> Result<UserOperationDraft> {
+    let init_code = match counterfactual {
+        Some(counterfactual) => {
+            check_counterfactual(chain, account, counterfactual)?;
+            if is_deployed(chain, account).await? {
+                Bytes::default()
+            } else {
+                // checked by `check_counterfactual`
+                let factory = chain.account_factory(counterfactual.factory).unwrap();
+                factory.user_operation_init_code(counterfactual.owner, counterfactual.salt)
+            }
+        }
+        None => Bytes::default(),
+    };
+    let nonce = get_nonce(chain.provider.clone(), chain.entry_point, account)
+        .await
+        .map_err(|err| {
+            error!(target: "user_operation", ?err, chain_id = chain.chain_id, "query entry point nonce error");
+            ServiceError::ChainError(err.to_string())
+        })?;
+
+    Ok(UserOperationDraft {
+        sender: account,
+        nonce,
+        init_code,
+        call_data: execute_call_data(
+            chain.guardian,
+            U256::zero(),
+            bind_email_call_data(email_hash, signature),
+        ),
+        entry_point: chain.entry_point,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        contracts::provider::{FailoverClient, ProviderConfig},
+        service::chain::{ChainConfig, ChainRegistry},
+    };
+    use ethers::{
+        abi::{AbiDecode, AbiEncode},
+        providers::{MockProvider, Provider},
+        utils::{id, keccak256},
+    };
+
+    #[tokio::test]
+    async fn builds_guardian_binding_operation() {
+        let registry = ChainRegistry::new(
+            vec![ChainConfig {
+                chain_id: 4690,
+                rpc_urls: vec!["http://localhost:8545".to_string()],
+                guardian_address: "0xBf081D23317966eEBD59Bc8EDB593A830F373178".to_string(),
+                ..Default::default()
+            }],
+            None,
+            Some("key".to_string()),
+        )
+        .unwrap();
+        let mock = MockProvider::new();
+        mock.push::<Bytes, _>(Bytes::from(U256::from(3).encode()))
+            .unwrap();
+        let mut chain = registry.get(None).unwrap().clone();
+        chain.provider = Provider::new(
+            FailoverClient::new(
+                vec![("mock".to_string(), Box::new(mock))],
+                ProviderConfig::default(),
+            )
+            .unwrap(),
+        );
+        let account: Address = "0x8803DAF0AB9Bad65a56F4D9AEcA56085491C299A"
+            .parse()
+            .unwrap();
+
+        let operation = build_user_operation(
+            &chain,
+            account,
+            keccak256("test@test.com"),
+            Bytes::from(vec![1u8; 65]),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(operation.sender, account);
+        assert_eq!(operation.nonce, U256::from(3));
+        assert!(operation.init_code.is_empty());
+        assert_eq!(
+            &operation.call_data[..4],
+            &id("execute(address,uint256,bytes)")
+        );
+        let (dest, value, func) =
+            <(Address, U256, Bytes)>::decode(&operation.call_data[4..]).unwrap();
+        assert_eq!(dest, chain.guardian);
+        assert!(value.is_zero());
+        assert_eq!(&func[..4], &id("bindEmail(bytes32,bytes)"));
+    }
+}
@@ -1,55 +1,218 @@
-use ethers::signers::{LocalWallet, Signer};
-
-use crate::{
-    contracts::guardian::get_hash,
-    service::{
-        code::BindCode,
-        error::{Result, ServiceError},
-        Context,
-    },
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::{Address, H256},
 };
+use serde::{Deserialize, Serialize};
+
+use crate::service::{
+    account::CounterfactualAccount,
+    alert::ensure_not_frozen,
+    chain::Chain,
+    code::{ensure_unbound, find_sent_code, BindCode, CODE_EXPIRY_SECS, CODE_PURPOSE_BIND},
+    commitment::email_commitment,
+    error::{Result, ServiceError},
+    events::DeliveryState,
+    guardian::binding_hash,
+    ownership::{parse_address, verify_ownership, OwnershipProof},
+    relayer,
+    signature::{save_signature, SignedBinding},
+    user_operation::{build_user_operation, UserOperationDraft},
+    Context,
+};
+
+/// Optional trailing parameter of `verify_code`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerifyCodeOptions {
+    pub idempotency_key: Option<String>,
+    pub chain_id: Option<u64>,
+    /// Return a [`VerifyCodeResult`] instead of the bare signature.
+    pub detailed: bool,
+    /// Also return the UserOperation submitting the signature to the guardian.
+    pub user_operation: bool,
+    /// Factory, owner and salt of an account that may not be deployed yet.
+    pub counterfactual: Option<CounterfactualAccount>,
+    /// Have the relayer submit the binding, see `get_status` for its progress. Requires
+    /// `ownership`, so only the account can have a binding submitted for it.
+    pub relay: bool,
+    /// Proof of owning the account, required to relay and, on chains with salted email
+    /// commitments, to bind an account whose salt mode is not recorded yet.
+    pub ownership: Option<OwnershipProof>,
+    /// The user-held salt of the account on chains with salted email commitments.
+    pub email_salt: Option<H256>,
+}
+
+/// Result of `verify_code`: the signature and the guardian it is valid for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyCodeResult {
+    pub signature: String,
+    pub chain_id: u64,
+    pub guardian: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_operation: Option<UserOperationDraft>,
+    /// The email hash the signature binds, salted on chains with salted commitments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_hash: Option<String>,
+}
+
+/// What `verify_code` answers: the bare signature, unless a detailed result or a
+/// UserOperation was asked for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum VerifyCodeResponse {
+    Signature(String),
+    Detailed(VerifyCodeResult),
+}
 
 pub async fn verify_code(
     context: &Context,
     account: String,
     email: String,
     code: String,
-) -> Result<String> {
-    let codes = sqlx::query_as::<_, BindCode>(
-        "select id, account, email, code, status, created_at, updated_at from bind_code where account = $1 and email = $2 and code = $3 and status = $4 order by id desc limit 1",
-    ).bind(&account).bind(&email).bind(&code).bind(1i16).fetch_all(&context.db).await?;
-
-    if codes.is_empty() || codes[0].created_at.timestamp() + 360 < chrono::Local::now().timestamp()
-    {
-        return Err(ServiceError::InvalidRequest("error code".to_string()));
-    }
+    options: VerifyCodeOptions,
+) -> Result<VerifyCodeResponse> {
+    let chain = context.chains.get(options.chain_id)?;
+    let address = parse_address(&account)?;
+    ensure_not_frozen(&context.db, chain, address).await?;
 
-    let wallet = match context.signer.parse::<LocalWallet>() {
-        Ok(w) => w,
-        Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
-    };
-    let hash = match get_hash(
-        context.provider.clone(),
-        &context.guardian_address,
+    let found = find_sent_code(
+        context,
+        chain.chain_id as i64,
         &account,
         &email,
+        &code,
+        CODE_PURPOSE_BIND,
     )
-    .await
-    {
-        Ok(h) => h,
-        Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
+    .await?;
+    let found = match found {
+        Some(found)
+            if found.created_at.timestamp() + CODE_EXPIRY_SECS
+                >= chrono::Local::now().timestamp() =>
+        {
+            found
+        }
+        _ => return Err(ServiceError::InvalidRequest("error code".to_string())),
     };
-    match wallet.sign_message(hash).await {
-        Ok(s) => {
-            let _ = sqlx::query(
-                r#"Update bind_code set status = $1, updated_at = now() where id = $2"#,
+    // the code may predate a binding of another email
+    ensure_unbound(context, chain, address, &found.email).await?;
+
+    let result = sign_binding(context, chain, address, &found, &options, false).await?;
+    Ok(if options.detailed || options.user_operation {
+        VerifyCodeResponse::Detailed(result)
+    } else {
+        VerifyCodeResponse::Signature(result.signature)
+    })
+}
+
+/// Signs the binding of the code's email to `address` and stores the signature, handing it to
+/// the relayer when asked. Unless the caller `claimed` the code already, it is marked verified
+/// in the code store, which hands back the row the signature references.
+///
+/// Relaying needs an ownership proof, which is checked whenever given.
+pub(crate) async fn sign_binding(
+    context: &Context,
+    chain: &Chain,
+    address: Address,
+    code: &BindCode,
+    options: &VerifyCodeOptions,
+    claimed: bool,
+) -> Result<VerifyCodeResult> {
+    if options.relay && (context.relayer.is_none() || chain.relay.is_none()) {
+        return Err(ServiceError::InvalidRequest(format!(
+            "relaying is not available on chain {}",
+            chain.chain_id
+        )));
+    }
+    let owner_verified = match &options.ownership {
+        Some(proof) => {
+            verify_ownership(
+                context,
+                chain,
+                &code.account,
+                proof,
+                options.counterfactual.as_ref(),
             )
-            .bind(2i16)
-            .bind(codes[0].id)
-            .execute(&context.db)
             .await?;
-            Ok(format!("0x{}", s))
+            true
         }
-        Err(err) => Err(ServiceError::InvalidRequest(err.to_string())),
+        None => false,
+    };
+    if options.relay && !owner_verified {
+        return Err(ServiceError::InvalidRequest(
+            "relaying requires an ownership proof".to_string(),
+        ));
+    }
+    let wallet = match chain.signer.parse::<LocalWallet>() {
+        Ok(w) => w,
+        Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
+    };
+    let email_hash = email_commitment(
+        &context.db,
+        chain,
+        address,
+        &code.email,
+        options.email_salt,
+        owner_verified,
+    )
+    .await?;
+    let (hash, nonce) = binding_hash(&context.guardian_hash, chain, address, email_hash).await?;
+    let s = match wallet.sign_message(hash).await {
+        Ok(s) => s,
+        Err(err) => return Err(ServiceError::InvalidRequest(err.to_string())),
+    };
+    let signature = format!("0x{}", s);
+    let user_operation = if options.user_operation {
+        Some(
+            build_user_operation(
+                chain,
+                address,
+                email_hash,
+                s.to_vec().into(),
+                options.counterfactual.as_ref(),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+    let verified;
+    let code = if claimed {
+        code
+    } else {
+        // a concurrent verify_code with the same code loses here
+        verified = context
+            .codes
+            .verify(code)
+            .await?
+            .ok_or_else(|| ServiceError::InvalidRequest("error code".to_string()))?;
+        &verified
+    };
+    save_signature(
+        &context.db,
+        context.email_cipher.as_ref(),
+        chain,
+        code,
+        SignedBinding {
+            email_hash,
+            signer: &format!("{:?}", wallet.address()),
+            signature: &signature,
+            nonce,
+        },
+        // with an indexer, the binding supersedes the others once the guardian holds it
+        chain.indexer.is_none(),
+    )
+    .await?;
+    if options.relay {
+        relayer::enqueue(context, chain, code, email_hash, &signature).await?;
+    }
+    if let Some(token) = &code.request_token {
+        context.events.publish(token, DeliveryState::Verified);
     }
+    Ok(VerifyCodeResult {
+        signature,
+        chain_id: chain.chain_id,
+        guardian: chain.guardian_address.clone(),
+        user_operation,
+        email_hash: Some(format!("0x{}", hex::encode(email_hash))),
+    })
 }